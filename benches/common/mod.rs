@@ -0,0 +1,264 @@
+//! Helpers shared by the benchmarks, and the harness that times, reports and compares them
+//!
+//! Every benchmark takes the same options after `--`, e.g. `cargo bench --bench protocol -- --runs 10 handshake`:
+//! * `--runs <n>` how many times to time each measurement, after a warm up, 5 by default
+//! * `--size <size>` the size of the file to send, for benchmarks of a single size
+//! * `--max-size <size>` the largest file to send, for benchmarks of many sizes
+//! * `--save <name>` save the results to `target/fshare-bench/<name>.json`
+//! * `--baseline <name>` compare the results with ones saved earlier, marking any that are more than 10% slower
+//! * anything else only runs the measurements whose names contain it
+//!
+//! Files are generated from fixed seeds and every measurement reports the median of its runs, so results from the
+//! same machine can be compared from one commit to the next.
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fshare::{format_size, parse_size, ServerBuilder};
+use serde::{Deserialize, Serialize};
+
+/// How much slower than the baseline a measurement can be before it is marked as a regression
+const TOLERANCE: f64 = 0.10;
+
+/// Measurements longer than this aren't warmed up first, one run is enough to fill the page cache
+const WARM_UP_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// The timings of one measurement, as saved for later runs to compare with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timing {
+    pub median_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+    /// Bytes sent in each run, if the measurement is of throughput
+    pub bytes: Option<u64>,
+}
+
+/// Times measurements, prints them as they finish, and saves or compares them when done
+pub struct Suite {
+    name: &'static str,
+    pub runs: usize,
+    pub size: Option<u64>,
+    pub max_size: Option<u64>,
+    save: Option<String>,
+    baseline: Option<(String, BTreeMap<String, Timing>)>,
+    filters: Vec<String>,
+    results: BTreeMap<String, Timing>,
+    regressions: usize,
+}
+
+impl Suite {
+    /// A suite configured from the command line
+    pub fn from_args(name: &'static str) -> Suite {
+        let mut suite = Suite {
+            name,
+            runs: 5,
+            size: None,
+            max_size: None,
+            save: None,
+            baseline: None,
+            filters: Vec::new(),
+            results: BTreeMap::new(),
+            regressions: 0,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = |option: &str| {
+                args.next()
+                    .unwrap_or_else(|| panic!("{} needs a value", option))
+            };
+            match arg.as_str() {
+                "--runs" => suite.runs = value("--runs").parse().expect("--runs <n>"),
+                "--size" => suite.size = Some(parse_size(&value("--size")).expect("--size <size>")),
+                "--max-size" => {
+                    suite.max_size =
+                        Some(parse_size(&value("--max-size")).expect("--max-size <size>"))
+                }
+                "--save" => suite.save = Some(value("--save")),
+                "--baseline" => {
+                    let baseline = value("--baseline");
+                    let results = fs::read(results_path(&baseline))
+                        .map(|saved| serde_json::from_slice(&saved).expect("saved results"))
+                        .unwrap_or_else(|e| panic!("no results saved as {}: {}", baseline, e));
+                    suite.baseline = Some((baseline, results));
+                }
+                // cargo bench passes --bench to every benchmark
+                "--bench" => {}
+                _ => suite.filters.push(arg),
+            }
+        }
+        suite.runs = suite.runs.max(1);
+        println!("{}, median of {} runs", name, suite.runs);
+        println!(
+            "{:<32} {:>10} {:>10} {:>10} {:>14}",
+            "", "median", "min", "max", "throughput"
+        );
+        suite
+    }
+
+    /// Whether a measurement should run, according to the filters given
+    pub fn wants(&self, measurement: &str) -> bool {
+        self.filters.is_empty()
+            || self
+                .filters
+                .iter()
+                .any(|filter| measurement.contains(filter.as_str()))
+    }
+
+    /// Time `run` for a measurement, which returns how long the part worth timing took, e.g. without its setup
+    ///
+    /// `bytes` is what each run sends, to report the throughput.
+    pub fn measure(
+        &mut self,
+        measurement: &str,
+        bytes: Option<u64>,
+        mut run: impl FnMut() -> Duration,
+    ) {
+        if !self.wants(measurement) {
+            return;
+        }
+        if bytes.unwrap_or(0) <= WARM_UP_LIMIT {
+            run();
+        }
+        let mut samples: Vec<Duration> = (0..self.runs).map(|_| run()).collect();
+        samples.sort();
+        let timing = Timing {
+            median_ns: samples[samples.len() / 2].as_nanos() as u64,
+            min_ns: samples[0].as_nanos() as u64,
+            max_ns: samples[samples.len() - 1].as_nanos() as u64,
+            bytes,
+        };
+        self.report(measurement, &timing);
+        // results from every benchmark can be saved under the same name
+        self.results
+            .insert(format!("{}/{}", self.name, measurement), timing);
+    }
+
+    fn report(&mut self, measurement: &str, timing: &Timing) {
+        let throughput = timing
+            .bytes
+            .map(|bytes| {
+                format!(
+                    "{}/s",
+                    format_size(per_second(bytes, Duration::from_nanos(timing.median_ns)))
+                )
+            })
+            .unwrap_or_default();
+        let comparison = match self
+            .baseline
+            .as_ref()
+            .and_then(|(_, baseline)| baseline.get(&format!("{}/{}", self.name, measurement)))
+        {
+            Some(before) => {
+                let change = timing.median_ns as f64 / before.median_ns as f64 - 1.0;
+                let verdict = if change > TOLERANCE {
+                    self.regressions += 1;
+                    "  regressed"
+                } else if change < -TOLERANCE {
+                    "  improved"
+                } else {
+                    ""
+                };
+                format!("{:+6.1}%{}", change * 100.0, verdict)
+            }
+            None => String::new(),
+        };
+        println!(
+            "{:<32} {:>10.1?} {:>10.1?} {:>10.1?} {:>14} {}",
+            measurement,
+            Duration::from_nanos(timing.median_ns),
+            Duration::from_nanos(timing.min_ns),
+            Duration::from_nanos(timing.max_ns),
+            throughput,
+            comparison
+        );
+    }
+
+    /// Save the results if asked to, and sum up the comparison with the baseline if there is one
+    pub fn finish(self) {
+        if let Some(save) = &self.save {
+            let path = results_path(save);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            // keep what the other benchmarks saved under the same name
+            let mut saved: BTreeMap<String, Timing> = fs::read(&path)
+                .ok()
+                .and_then(|saved| serde_json::from_slice(&saved).ok())
+                .unwrap_or_default();
+            saved.extend(self.results.clone());
+            fs::write(&path, serde_json::to_vec_pretty(&saved).unwrap()).unwrap();
+            println!("saved {} results to {}", self.name, path.display());
+        }
+        if let Some((baseline, _)) = &self.baseline {
+            println!(
+                "{} of {} measurements regressed by more than {}% since {}",
+                self.regressions,
+                self.results.len(),
+                TOLERANCE * 100.0,
+                baseline
+            );
+        }
+    }
+}
+
+/// Where results saved under `name` are kept
+fn results_path(name: &str) -> PathBuf {
+    let target = std::env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("target"));
+    target.join("fshare-bench").join(format!("{}.json", name))
+}
+
+/// A fresh directory for a benchmark to work in, with a `receive` directory inside
+pub fn bench_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fshare-bench-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("receive")).unwrap();
+    dir
+}
+
+/// A file of `size` bytes that doesn't compress or deduplicate, different for each `seed`
+pub fn write_file(path: &Path, size: u64, seed: u32) {
+    let mut state = seed;
+    let block: Vec<u8> = (0..1024 * 1024)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        })
+        .collect();
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    let mut written = 0;
+    while written < size {
+        let length = (size - written).min(block.len() as u64) as usize;
+        file.write_all(&block[..length]).unwrap();
+        written += length as u64;
+    }
+    file.flush().unwrap();
+}
+
+/// Start a server receiving into `directory` on a port chosen by the OS, returning its address
+pub fn start_server(directory: &Path, configure: impl FnOnce(&mut ServerBuilder)) -> String {
+    let mut server = ServerBuilder::new();
+    server.directory(directory).unwrap();
+    configure(&mut server);
+    let mut server = server.build().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || server.serve(listener));
+    address
+}
+
+/// How long `f` takes
+pub fn time(f: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+pub fn per_second(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64()) as u64
+}
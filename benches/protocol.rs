@@ -0,0 +1,155 @@
+//! The throughput and latency of sending files to a server over localhost
+//!
+//! * `throughput` sends files from 1 KiB up to `--max-size`, 256 MiB by default, e.g. `-- --max-size 4G` to include
+//!   files of several GiB
+//! * `small files` is the cost of each of many 1 KiB files, sent over a connection of their own or all over one, and
+//!   of each of a directory of 1000 of them synced to a server that has none of them
+//! * `handshake` is how long each phase of sending a 1 KiB file takes, from loading the file to saying Goodbye
+//!
+//! Run with `cargo bench --bench protocol`, see `benches/common/mod.rs` for the options. To watch for regressions,
+//! save a baseline with `-- --save main` and compare a later build with `-- --baseline main`.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use fshare::{format_size, Client, Disconnected};
+
+use common::{bench_dir, start_server, time, write_file, Suite};
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+const SIZES: [u64; 7] = [KIB, 64 * KIB, MIB, 16 * MIB, 256 * MIB, GIB, 4 * GIB];
+
+/// How many files the small files measurements send each run
+const SMALL_FILES: usize = 50;
+
+/// How many files the sync measurement sends each run
+const SYNC_FILES: usize = 1000;
+
+fn main() {
+    let mut suite = Suite::from_args("protocol");
+    let dir = bench_dir("protocol");
+    let address = start_server(&dir.join("receive"), |_| {});
+
+    throughput(&mut suite, &dir, &address);
+    small_files(&mut suite, &dir, &address);
+    handshake(&mut suite, &dir, &address);
+
+    fs::remove_dir_all(dir).unwrap();
+    suite.finish();
+}
+
+fn throughput(suite: &mut Suite, dir: &Path, address: &str) {
+    let max_size = suite.max_size.unwrap_or(256 * MIB);
+    for size in SIZES.iter().copied().filter(|&size| size <= max_size) {
+        let measurement = format!("throughput/{}", format_size(size));
+        if !suite.wants(&measurement) {
+            continue;
+        }
+        let path = dir.join(format!("{}.bin", size));
+        write_file(&path, size, 1);
+        suite.measure(&measurement, Some(size), || {
+            time(|| {
+                Client::<Disconnected>::new()
+                    .send(address.to_string(), path.to_str().unwrap().to_string())
+                    .unwrap()
+            })
+        });
+        fs::remove_file(path).unwrap();
+        let _ = fs::remove_file(dir.join("receive").join(format!("{}.bin", size)));
+    }
+}
+
+fn small_files(suite: &mut Suite, dir: &Path, address: &str) {
+    let small = dir.join("small");
+    fs::create_dir_all(&small).unwrap();
+    let paths: Vec<String> = (0..SMALL_FILES)
+        .map(|i| {
+            let path = small.join(format!("{}.bin", i));
+            write_file(&path, KIB, i as u32);
+            path.to_str().unwrap().to_string()
+        })
+        .collect();
+
+    suite.measure("small files/connection each", None, || {
+        time(|| {
+            for path in &paths {
+                Client::<Disconnected>::new()
+                    .send(address.to_string(), path.clone())
+                    .unwrap();
+            }
+        }) / SMALL_FILES as u32
+    });
+    suite.measure("small files/one connection", None, || {
+        time(|| {
+            let mut client = Client::<Disconnected>::new().connect(address).unwrap();
+            for path in &paths {
+                client = client
+                    .file(path.as_str())
+                    .unwrap()
+                    .negotiate()
+                    .unwrap()
+                    .send()
+                    .unwrap();
+            }
+            client.goodbye();
+        }) / SMALL_FILES as u32
+    });
+
+    let synced = dir.join("synced");
+    fs::create_dir_all(&synced).unwrap();
+    for i in 0..SYNC_FILES {
+        write_file(&synced.join(format!("synced-{}.bin", i)), KIB, i as u32);
+    }
+    suite.measure("small files/sync", None, || {
+        // the server must not have any of them yet, or they aren't sent
+        for i in 0..SYNC_FILES {
+            let _ = fs::remove_file(dir.join("receive").join(format!("synced-{}.bin", i)));
+        }
+        time(|| {
+            let summary = Client::<Disconnected>::new()
+                .sync(&synced, address, false)
+                .unwrap();
+            assert_eq!(summary.sent.len(), SYNC_FILES);
+        }) / SYNC_FILES as u32
+    });
+}
+
+/// The phases of sending a file, in order
+const PHASES: [&str; 5] = ["load", "connect", "negotiate", "send", "goodbye"];
+
+fn handshake(suite: &mut Suite, dir: &Path, address: &str) {
+    let path = dir.join("handshake.bin");
+    write_file(&path, KIB, 1);
+    let path = path.to_str().unwrap();
+    for (phase, name) in PHASES.iter().enumerate() {
+        suite.measure(&format!("handshake/{}", name), None, || {
+            send_in_phases(path, address)[phase]
+        });
+    }
+}
+
+/// Send a file, timing each phase
+fn send_in_phases(path: &str, address: &str) -> [Duration; 5] {
+    let mut phases = [Duration::ZERO; 5];
+    let mut start = Instant::now();
+    let mut lap = |phase: usize| {
+        phases[phase] = start.elapsed();
+        start = Instant::now();
+    };
+    let client = Client::<Disconnected>::new().file(path).unwrap();
+    lap(0);
+    let client = client.connect(address).unwrap();
+    lap(1);
+    let client = client.negotiate().unwrap();
+    lap(2);
+    let client = client.send().unwrap();
+    lap(3);
+    client.goodbye();
+    lap(4);
+    phases
+}
@@ -0,0 +1,103 @@
+//! Measures how fast the server receives a large file over localhost along each of its receive paths
+//!
+//! Run with `cargo bench --bench receive`, optionally followed by `-- --size <size>` to choose how large a file to
+//! send, e.g. `-- --size 2G`. See `benches/common/mod.rs` for the other options.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use fshare::{format_size, Client, Disconnected};
+
+use common::{bench_dir, start_server, time, write_file, Suite};
+
+/// How the file is sent, and what the server has of it beforehand
+struct Route {
+    name: &'static str,
+    zero_copy: bool,
+    streams: usize,
+    delta: bool,
+    /// The seed of the server's copy of the file before each run, if it has one
+    base: Option<u32>,
+}
+
+fn main() {
+    let mut suite = Suite::from_args("receive");
+    let size = suite.size.unwrap_or(256 * 1024 * 1024);
+    let dir = bench_dir("receive");
+    let path = dir.join("large.bin");
+    write_file(&path, size, 1);
+
+    let routes = [
+        Route {
+            name: "spliced",
+            zero_copy: true,
+            streams: 1,
+            delta: false,
+            base: None,
+        },
+        Route {
+            name: "buffered",
+            zero_copy: false,
+            streams: 1,
+            delta: false,
+            base: None,
+        },
+        Route {
+            name: "4 ranges",
+            zero_copy: true,
+            streams: 4,
+            delta: false,
+            base: None,
+        },
+        Route {
+            name: "literals",
+            zero_copy: true,
+            streams: 1,
+            delta: true,
+            base: Some(2),
+        },
+        Route {
+            name: "copies",
+            zero_copy: true,
+            streams: 1,
+            delta: true,
+            base: Some(1),
+        },
+    ];
+
+    for route in &routes {
+        let receive = dir.join("receive");
+        let address = start_server(&receive, |server| {
+            server.zero_copy(route.zero_copy);
+        });
+        suite.measure(
+            &format!("{} {}", route.name, format_size(size)),
+            Some(size),
+            || run(route, &path, &receive, &address, size),
+        );
+    }
+    fs::remove_dir_all(dir).unwrap();
+    suite.finish();
+}
+
+/// Send the file once, returning how long it took
+fn run(route: &Route, file: &Path, receive: &Path, address: &str, size: u64) -> Duration {
+    let received = receive.join("large.bin");
+    match route.base {
+        Some(seed) => write_file(&received, size, seed),
+        None => {
+            let _ = fs::remove_file(&received);
+        }
+    }
+    time(|| {
+        Client::<Disconnected>::new()
+            .zero_copy(route.zero_copy)
+            .streams(route.streams)
+            .delta(route.delta)
+            .send(address.to_string(), file.to_str().unwrap().to_string())
+            .unwrap()
+    })
+}
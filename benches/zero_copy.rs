@@ -0,0 +1,45 @@
+//! Compares sending large files over localhost through buffers with sending them with sendfile and splice
+//!
+//! Run with `cargo bench --bench zero_copy`, optionally followed by `-- --size <size>` to choose how large a file to
+//! send, e.g. `-- --size 2G`. See `benches/common/mod.rs` for the other options.
+//!
+//! Neither is zero copy. Either way every chunk is still read into a buffer and checksummed on both sides, and the
+//! server syncs the file to disk before renaming it into place, so on a slow CPU or disk those rather than the copies
+//! may set the pace. `sendfile` and `splice` only save the copy from that buffer to the socket or the file.
+
+mod common;
+
+use std::fs;
+
+use fshare::{format_size, Client, Disconnected};
+
+use common::{bench_dir, start_server, time, write_file, Suite};
+
+fn main() {
+    println!("sendfile and splice still read every chunk into a buffer on each side to checksum it, so aren't zero copy");
+    let mut suite = Suite::from_args("zero_copy");
+    let size = suite.size.unwrap_or(512 * 1024 * 1024);
+    let dir = bench_dir("zero-copy");
+    let path = dir.join("large.bin");
+    write_file(&path, size, 1);
+
+    for (name, zero_copy) in [("buffered", false), ("sendfile and splice", true)] {
+        let address = start_server(&dir.join("receive"), |server| {
+            server.zero_copy(zero_copy);
+        });
+        suite.measure(
+            &format!("{} {}", name, format_size(size)),
+            Some(size),
+            || {
+                time(|| {
+                    Client::<Disconnected>::new()
+                        .zero_copy(zero_copy)
+                        .send(address.clone(), path.to_str().unwrap().to_string())
+                        .unwrap()
+                })
+            },
+        );
+    }
+    fs::remove_dir_all(dir).unwrap();
+    suite.finish();
+}
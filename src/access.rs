@@ -0,0 +1,141 @@
+//! Which peers a server is willing to talk to, by IP address or network
+
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+
+/// An IP network such as `10.0.0.0/8` or `fd00::/8`, a single address is a network of exactly one address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // an IPv4 peer connecting to a dual stack socket shows up as an IPv4-mapped IPv6 address
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .with_context(|| format!("Invalid address in `{}`", value))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or(anyhow!(
+                    "Invalid prefix length in `{}`, expected 0 to {}",
+                    value,
+                    max
+                ))?,
+            None => max,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Rules deciding which peers may connect
+///
+/// A peer matching any deny rule is refused. If there are any allow rules, a peer must also match one of them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessRules {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessRules {
+    /// Returns the reason a peer is not allowed to connect, if it isn't
+    pub fn check(&self, ip: IpAddr) -> Result<(), String> {
+        if let Some(rule) = self.deny.iter().find(|rule| rule.contains(ip)) {
+            return Err(format!("{} is denied by the rule `deny {}`", ip, rule));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.contains(ip)) {
+            return Err(format!("{} is not in any allowed network", ip));
+        }
+        Ok(())
+    }
+
+    /// Add the rules in `other` to these rules
+    pub fn extend(&mut self, other: AccessRules) {
+        self.allow.extend(other.allow);
+        self.deny.extend(other.deny);
+    }
+
+    /// Load rules from a file with one rule per line, such as:
+    /// ```text
+    /// # the office
+    /// allow 10.0.3.0/24
+    /// deny 10.0.3.13
+    /// ```
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<AccessRules> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read access rules from {:?}", path))?;
+        text.parse()
+            .with_context(|| format!("Invalid access rules in {:?}", path))
+    }
+}
+
+impl FromStr for AccessRules {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut rules = AccessRules::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (action, network) = line.split_once(char::is_whitespace).ok_or(anyhow!(
+                "line {}: expected `allow` or `deny` and a network",
+                number + 1
+            ))?;
+            let network = network
+                .trim()
+                .parse()
+                .with_context(|| format!("line {}", number + 1))?;
+            match action {
+                "allow" => rules.allow.push(network),
+                "deny" => rules.deny.push(network),
+                _ => bail!(
+                    "line {}: expected `allow` or `deny`, found `{}`",
+                    number + 1,
+                    action
+                ),
+            }
+        }
+        Ok(rules)
+    }
+}
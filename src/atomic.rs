@@ -0,0 +1,103 @@
+//! Files that only appear at their final path once they have been completely written
+//!
+//! Content is written to a hidden temporary file next to the final path, which is synced to disk and renamed into
+//! place on [AtomicFile::commit]. If the AtomicFile is dropped without being committed, e.g. because the transfer
+//! failed part way through, the temporary file is removed so nothing half-written is ever left behind.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(unix)]
+use log::warn;
+
+/// Distinguishes temporary files created by this process, so concurrent transfers of the same name don't collide
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The end of the name of every temporary file
+pub const PARTIAL_SUFFIX: &str = ".fshare-partial";
+
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    temp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Create a temporary file that will become `path` once committed
+    pub fn create(path: PathBuf) -> io::Result<AtomicFile> {
+        let temp_path = temp_path(&path);
+        // readable too, so what is written can be read back to check it
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        Ok(AtomicFile {
+            file,
+            temp_path,
+            path,
+            committed: false,
+        })
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Sync the content to disk and move it into place, replacing any existing file at the final path
+    ///
+    /// Once the file is in place this succeeds, even if syncing the rename to disk doesn't.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        // make sure the rename itself survives a crash, but the file is in place whether or not this works, so failing
+        // here mustn't report it as missing
+        #[cfg(unix)]
+        if let Some(directory) = self.path.parent() {
+            if let Err(e) = File::open(directory).and_then(|directory| directory.sync_all()) {
+                warn!(path:? = self.path, error:% = e; "stored file, but could not sync its directory to disk");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// A hidden name in the same directory as `path`, so that renaming it into place never crosses filesystems
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.{}-{}{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        PARTIAL_SUFFIX
+    ))
+}
@@ -0,0 +1,153 @@
+//! A permanent record of every file a server tried to receive, or was asked to delete
+//!
+//! Records are appended to a file in [JSON Lines](https://jsonlines.org) format, one object per line, so the log can be
+//! followed with `tail -f`, processed with standard tools, and is never rewritten.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use super::limits::format_size;
+
+/// Whether a file was received
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Received,
+    /// The transfer started but didn't finish, nothing was stored
+    Failed,
+    /// The server's limits didn't allow the file, none of it was sent
+    Refused,
+    /// A client syncing a directory removed the file
+    Deleted,
+}
+
+impl FromStr for Outcome {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "received" => Ok(Outcome::Received),
+            "failed" => Ok(Outcome::Failed),
+            "refused" => Ok(Outcome::Refused),
+            "deleted" => Ok(Outcome::Deleted),
+            _ => bail!(
+                "Invalid outcome `{}`, expected `received`, `failed`, `refused` or `deleted`",
+                value
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Received => write!(f, "received"),
+            Outcome::Failed => write!(f, "failed"),
+            Outcome::Refused => write!(f, "refused"),
+            Outcome::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+/// One attempt at receiving a file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When the attempt started, as an RFC 3339 timestamp in UTC, see [crate::format_timestamp]
+    pub time: String,
+    /// The address of the client that sent the file
+    pub peer: String,
+    /// The name the client gave the file
+    pub file: String,
+    /// Where the file was, or would have been, stored
+    pub path: PathBuf,
+    /// The size the client announced, in bytes
+    pub size: u64,
+    /// The CRC-32 of the content as 8 hex digits, only known once all of it arrived
+    pub checksum: Option<String>,
+    /// How long the attempt took, in milliseconds
+    pub duration_ms: u64,
+    pub outcome: Outcome,
+    /// Why the attempt failed
+    pub error: Option<String>,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<8} {} {} ({}) -> {:?} in {}ms",
+            self.time,
+            self.outcome,
+            self.peer,
+            self.file,
+            format_size(self.size),
+            self.path,
+            self.duration_ms
+        )?;
+        if let Some(checksum) = &self.checksum {
+            write!(f, " crc32 {}", checksum)?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// An append-only audit log
+///
+/// Clones append to the same file, so every connection a server handles at once can share one log.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Arc<File>,
+}
+
+impl AuditLog {
+    /// Open the log at `path` for appending, creating it if it doesn't exist
+    pub fn open<P: Into<PathBuf>>(path: P) -> anyhow::Result<AuditLog> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Could not open the audit log {:?}", path))?;
+        Ok(AuditLog {
+            path,
+            file: Arc::new(file),
+        })
+    }
+
+    /// Append a record, it is on disk by the time this returns
+    pub fn record(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        // a single write of a whole line, so concurrent writers can't interleave records
+        let mut file = self.file.as_ref();
+        file.write_all(&line)
+            .and_then(|()| self.file.sync_data())
+            .with_context(|| format!("Could not write to the audit log {:?}", self.path))
+    }
+
+    /// Read every record in the log at `path`, oldest first
+    pub fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<AuditRecord>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read the audit log {:?}", path))?;
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Invalid record on line {} of {:?}", number + 1, path))
+            })
+            .collect()
+    }
+}
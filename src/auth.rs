@@ -0,0 +1,36 @@
+//! Proving a client knows the server's secret, without sending the secret itself
+//!
+//! The server sends a random challenge, and the client answers with the HMAC-SHA256 of it keyed with the secret. Only
+//! someone who knows the secret can answer, and an answer overheard on the network is no use for the next challenge.
+//! The secret still guards nothing but who may send: without TLS everything else crosses the network as it is.
+
+use anyhow::anyhow;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// The size in bytes of a challenge, and of its answer
+pub const CHALLENGE_SIZE: usize = 32;
+
+/// A challenge no client can have answered before
+pub fn challenge() -> anyhow::Result<[u8; CHALLENGE_SIZE]> {
+    let mut challenge = [0; CHALLENGE_SIZE];
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| anyhow!("Could not generate a random challenge"))?;
+    Ok(challenge)
+}
+
+/// The answer to `challenge` from someone who knows `secret`
+pub fn answer(secret: &str, challenge: &[u8]) -> [u8; CHALLENGE_SIZE] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let mut answer = [0; CHALLENGE_SIZE];
+    answer.copy_from_slice(hmac::sign(&key, challenge).as_ref());
+    answer
+}
+
+/// Whether `answer` is the answer to `challenge` from someone who knows `secret`, taking as long to say no however
+/// much of it is right
+pub fn check(secret: &str, challenge: &[u8], answer: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::verify(&key, challenge, answer).is_ok()
+}
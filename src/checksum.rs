@@ -0,0 +1,131 @@
+//! CRC-32 (IEEE 802.3) checksums used to verify file content as it travels over the wire
+//!
+//! This is the same checksum used by zip, gzip and png, implemented here with a lookup table
+//! so that we don't need to pull in a dependency for it.
+
+/// Lookup table for the reflected CRC-32 polynomial, computed at compile time
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// A running CRC-32 checksum, fed with data as it is read or written
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: 0xFFFF_FFFF }
+    }
+
+    /// Feed more data into the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.state;
+        for byte in data {
+            crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        self.state = crc;
+    }
+
+    /// The checksum of all data fed so far
+    pub fn finish(&self) -> u32 {
+        self.state ^ 0xFFFF_FFFF
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checksum a complete buffer in one go
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// The checksum of two pieces of data one after the other, from the checksum of each and the length of the second
+///
+/// This is zlib's `crc32_combine`, which appends `length` zero bits to `first` by squaring an operator matrix rather
+/// than feeding it the bytes, so pieces checksummed separately (e.g. on different connections) can be put together.
+pub fn combine(first: u32, second: u32, length: u64) -> u32 {
+    if length == 0 {
+        return first;
+    }
+    // the operator for one zero bit
+    let mut odd = [0u32; 32];
+    odd[0] = 0xEDB8_8320;
+    for (n, row) in odd.iter_mut().enumerate().skip(1) {
+        *row = 1 << (n - 1);
+    }
+    // then two and four zero bits
+    let mut even = square(&odd);
+    odd = square(&even);
+
+    // apply the operator for each bit of the length in bytes, starting with one byte (eight zero bits)
+    let mut crc = first;
+    let mut length = length;
+    loop {
+        even = square(&odd);
+        if length & 1 == 1 {
+            crc = times(&even, crc);
+        }
+        length >>= 1;
+        if length == 0 {
+            break;
+        }
+        odd = square(&even);
+        if length & 1 == 1 {
+            crc = times(&odd, crc);
+        }
+        length >>= 1;
+        if length == 0 {
+            break;
+        }
+    }
+    crc ^ second
+}
+
+/// Multiply a vector by a matrix over GF(2)
+fn times(matrix: &[u32; 32], vector: u32) -> u32 {
+    let mut sum = 0;
+    let mut vector = vector;
+    let mut row = 0;
+    while vector != 0 {
+        if vector & 1 == 1 {
+            sum ^= matrix[row];
+        }
+        vector >>= 1;
+        row += 1;
+    }
+    sum
+}
+
+fn square(matrix: &[u32; 32]) -> [u32; 32] {
+    let mut squared = [0; 32];
+    for (n, row) in squared.iter_mut().enumerate() {
+        *row = times(matrix, matrix[n]);
+    }
+    squared
+}
@@ -0,0 +1,367 @@
+//! Moving file content between files and connections as chunks, with fewer copies through buffers where possible
+//!
+//! On Linux, the data of each chunk is sent with `sendfile(2)` straight from the page cache to the socket, and
+//! received with `splice(2)` from the socket through a pipe into the file. This is not zero copy: every chunk carries
+//! its CRC-32, so the sender still reads each chunk into a buffer to calculate it, and the receiver reads each chunk
+//! back out of the file to check it. What it saves is the other copy on each side, writing the buffer to the socket
+//! or the file. Elsewhere, over a connection encrypted with TLS, or when a file or connection turns out not to support
+//! it, the data goes through a buffer both ways instead.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use log::debug;
+
+use super::checksum::{self, Crc32};
+use super::protocol::{ChunkHeader, CHUNK_SIZE};
+use super::source::Source;
+use super::tls::Connection;
+
+/// Sends content as chunks, straight from its file if it is in one
+pub(crate) struct ChunkSender<'a> {
+    source: &'a Source,
+    connection: &'a Connection,
+    /// A chunk's header followed by its data
+    buffer: Vec<u8>,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    zero_copy: bool,
+}
+
+impl<'a> ChunkSender<'a> {
+    pub fn new(source: &'a Source, connection: &'a Connection, zero_copy: bool) -> Self {
+        ChunkSender {
+            source,
+            connection,
+            buffer: vec![0; ChunkHeader::SIZE + CHUNK_SIZE],
+            zero_copy: zero_copy
+                && cfg!(target_os = "linux")
+                && source.file().is_some()
+                && connection.unencrypted().is_some(),
+        }
+    }
+
+    /// Send a chunk of up to `length` bytes of the content from `offset`, returning how many were sent, 0 at the end
+    /// of the content
+    pub fn send(
+        &mut self,
+        offset: u64,
+        length: u64,
+        checksum: &mut Crc32,
+    ) -> anyhow::Result<usize> {
+        let length = length.min(CHUNK_SIZE as u64) as usize;
+        let end = ChunkHeader::SIZE + length;
+        let read = self
+            .source
+            .read_at(&mut self.buffer[ChunkHeader::SIZE..end], offset)?;
+        if read == 0 {
+            return Ok(0);
+        }
+        let end = ChunkHeader::SIZE + read;
+        let data = &self.buffer[ChunkHeader::SIZE..end];
+        let chunk_checksum = checksum::crc32(data);
+        checksum.update(data);
+        self.buffer[..4].copy_from_slice(&(read as u32).to_be_bytes());
+        self.buffer[4..ChunkHeader::SIZE].copy_from_slice(&chunk_checksum.to_be_bytes());
+
+        let mut connection = self.connection;
+        #[cfg(target_os = "linux")]
+        if let (true, Some(file), Some(socket)) =
+            (self.zero_copy, self.source.file(), connection.unencrypted())
+        {
+            linux::send_more(socket, &self.buffer[..ChunkHeader::SIZE])?;
+            // if the file changes in the meantime the checksum won't match, and the receiver will say so
+            let sent = linux::sendfile(socket, file, offset, read)?;
+            if sent == read {
+                return Ok(read);
+            }
+            debug!("this file can't be sent with sendfile, sending through a buffer instead");
+            self.zero_copy = false;
+            connection.write_all(&self.buffer[ChunkHeader::SIZE + sent..end])?;
+            return Ok(read);
+        }
+        connection.write_all(&self.buffer[..end])?;
+        Ok(read)
+    }
+}
+
+/// Receives chunks of content, straight into a file where there is one
+pub(crate) struct ChunkReceiver<'a> {
+    connection: &'a Connection,
+    buffer: Vec<u8>,
+    #[cfg(target_os = "linux")]
+    pipe: Option<linux::Pipe>,
+}
+
+impl<'a> ChunkReceiver<'a> {
+    pub fn new(connection: &'a Connection, zero_copy: bool) -> Self {
+        ChunkReceiver {
+            connection,
+            buffer: vec![0; CHUNK_SIZE],
+            #[cfg(target_os = "linux")]
+            pipe: if zero_copy && connection.unencrypted().is_some() {
+                linux::Pipe::new()
+                    .map_err(|e| debug!(error:% = e; "could not create a pipe to splice through"))
+                    .ok()
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Read the header of the next chunk
+    pub fn header(&mut self) -> anyhow::Result<ChunkHeader> {
+        ChunkHeader::read_from(&mut self.connection)
+    }
+
+    /// Receive the `length` bytes of data that follow a header, into `file` at `offset` if given, returning the data
+    /// so it can be checked
+    ///
+    /// Nothing guards against the data being wrong, so the file should be discarded if it doesn't match its checksum.
+    pub fn receive(
+        &mut self,
+        length: usize,
+        offset: u64,
+        file: Option<&File>,
+    ) -> anyhow::Result<&[u8]> {
+        let file = match file {
+            Some(file) => file,
+            None => {
+                self.connection.read_exact(&mut self.buffer[..length])?;
+                return Ok(&self.buffer[..length]);
+            }
+        };
+        let mut received = 0;
+        #[cfg(target_os = "linux")]
+        if let (Some(pipe), Some(socket)) = (&self.pipe, self.connection.unencrypted()) {
+            received = match pipe.splice(socket, file, offset, length)? {
+                None => length,
+                Some(moved) => {
+                    debug!("this file can't be spliced into, receiving through a buffer instead");
+                    self.pipe = None;
+                    moved
+                }
+            };
+            // read back what went straight into the file, from the page cache, to check it
+            use std::os::unix::fs::FileExt;
+            file.read_exact_at(&mut self.buffer[..received], offset)?;
+        }
+        if received < length {
+            let data = &mut self.buffer[received..length];
+            self.connection.read_exact(data)?;
+            write_all_at(file, data, offset + received as u64)?;
+        }
+        Ok(&self.buffer[..length])
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buffer, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buffer, offset)
+}
+
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    let mut written = 0;
+    while written < data.len() {
+        written += file.seek_write(&data[written..], offset + written as u64)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::net::TcpStream;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    /// Whether an error means the kernel can't do this for these particular descriptors, rather than that it failed
+    fn unsupported(error: &io::Error) -> bool {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+        )
+    }
+
+    /// Write `data` to the connection, telling the kernel more is coming so it goes out in the same packet
+    pub fn send_more(connection: &TcpStream, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            // SAFETY: the pointer and length describe `data`, which outlives the call
+            let sent = unsafe {
+                libc::send(
+                    connection.as_raw_fd(),
+                    data.as_ptr().cast(),
+                    data.len(),
+                    libc::MSG_MORE | libc::MSG_NOSIGNAL,
+                )
+            };
+            if sent < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            data = &data[sent as usize..];
+        }
+        Ok(())
+    }
+
+    /// Send `length` bytes of `file` from `offset` to the connection, returning how many were sent before finding
+    /// that sendfile doesn't work for this file, which is all of them if it does
+    pub fn sendfile(
+        connection: &TcpStream,
+        file: &File,
+        offset: u64,
+        length: usize,
+    ) -> io::Result<usize> {
+        let mut offset = offset as libc::off_t;
+        let mut sent = 0;
+        while sent < length {
+            // SAFETY: both descriptors stay open for the duration of the call, and `offset` is a valid off_t
+            let result = unsafe {
+                libc::sendfile(
+                    connection.as_raw_fd(),
+                    file.as_raw_fd(),
+                    &mut offset,
+                    length - sent,
+                )
+            };
+            match result {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the file ended while it was being sent",
+                    ))
+                }
+                sent_now if sent_now > 0 => sent += sent_now as usize,
+                _ => {
+                    let error = io::Error::last_os_error();
+                    match error.kind() {
+                        io::ErrorKind::Interrupted => {}
+                        _ if unsupported(&error) => return Ok(sent),
+                        _ => return Err(error),
+                    }
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// A pipe to splice data from a connection into a file through
+    #[derive(Debug)]
+    pub struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub fn new() -> io::Result<Pipe> {
+            let mut fds = [0; 2];
+            // SAFETY: `fds` has room for the two descriptors pipe2 returns
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: pipe2 succeeded, so both descriptors are open and nothing else owns them
+            Ok(unsafe {
+                Pipe {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                }
+            })
+        }
+
+        /// Move `length` bytes from the connection into `file` at `offset`
+        ///
+        /// Returns how many bytes made it into the file if it turns out the file can't be spliced into, any more have
+        /// to be read from the connection some other way.
+        pub fn splice(
+            &self,
+            connection: &TcpStream,
+            file: &File,
+            offset: u64,
+            length: usize,
+        ) -> io::Result<Option<usize>> {
+            let mut moved = 0;
+            while moved < length {
+                let in_pipe = match splice(
+                    connection.as_raw_fd(),
+                    self.write.as_raw_fd(),
+                    None,
+                    length - moved,
+                ) {
+                    Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    Ok(in_pipe) => in_pipe,
+                    Err(e) if unsupported(&e) => return Ok(Some(moved)),
+                    Err(e) => return Err(e),
+                };
+                let mut left = in_pipe;
+                while left > 0 {
+                    let mut position = (offset + (moved + in_pipe - left) as u64) as libc::loff_t;
+                    match splice(
+                        self.read.as_raw_fd(),
+                        file.as_raw_fd(),
+                        Some(&mut position),
+                        left,
+                    ) {
+                        Ok(written) => left -= written,
+                        Err(e) if unsupported(&e) => {
+                            // what's already in the pipe has left the connection, so write it out the long way
+                            let mut buffer = vec![0; left];
+                            File::from(self.read.try_clone()?).read_exact(&mut buffer)?;
+                            super::write_all_at(file, &buffer, position as u64)?;
+                            return Ok(Some(moved + in_pipe));
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                moved += in_pipe;
+            }
+            Ok(None)
+        }
+    }
+
+    /// Splice up to `length` bytes from one descriptor to another, one of which is a pipe, retrying if interrupted
+    fn splice(
+        from: RawFd,
+        to: RawFd,
+        to_offset: Option<&mut libc::loff_t>,
+        length: usize,
+    ) -> io::Result<usize> {
+        let to_offset = to_offset.map_or(std::ptr::null_mut(), |offset| offset as *mut _);
+        loop {
+            // SAFETY: the descriptors are open for the duration of the call and `to_offset` is null or a valid loff_t
+            let result = unsafe {
+                libc::splice(
+                    from,
+                    std::ptr::null_mut(),
+                    to,
+                    to_offset,
+                    length,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if result >= 0 {
+                return Ok(result as usize);
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+}
@@ -1,37 +1,63 @@
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufReader, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpStream;
 use std::path::PathBuf;
-
+use std::thread;
+
+use super::auth;
+use super::checksum::Crc32;
+use super::chunks::ChunkSender;
+use super::connect;
+use super::delta::{self, Signatures};
+use super::manifest::Manifest;
+use super::parallel;
 use super::protocol::{self, ProtocolConnection};
+use super::source::Source;
+use super::throttle::RateLimiter;
+use super::timeouts::{Reconnect, RetryPolicy, Timeouts};
+use super::tls::{ClientTls, Connection};
 
 use anyhow::{anyhow, bail, Context};
+use log::{debug, info, warn};
 
 trait LoadFile {
-    fn file_state(&mut self) -> &mut Option<File>;
+    fn file_state(&mut self) -> &mut Option<Source>;
     fn filename_state(&mut self) -> &mut Option<String>;
 
     fn load_file<T: Into<String>>(&mut self, filepath: T) -> anyhow::Result<()> {
         // grab the file_name part of filepath
         // first parse into a PathBuf
-        let filepath = &filepath.into();
+        let filepath = filepath.into();
 
         let path_buf = &filepath.parse::<PathBuf>().with_context(|| format!("Could not load file: `{}`, is it a directory?\nYou can only send one file at a time", &filepath))?;
         // then convert to a utf8 string, which is lossy due to differences in how windows and linux store strings, but infallible
         // the ok_or is because ".." is a valid PathBuf but its file_name() is None
         let name = path_buf.file_name().ok_or(anyhow!("Could not load file: `{}`, is it a directory?\nYou can only send one file at a time", &filepath))?.to_string_lossy().to_string();
+        self.load_file_named(filepath, name)
+    }
+
+    /// Load the file at `filepath` to send it as `name`
+    fn load_file_named(&mut self, filepath: String, name: String) -> anyhow::Result<()> {
         // we store the name in state to send to the server later
         *(self.filename_state()) = Some(name);
         // finally we can actually open the file
-        let file = File::open(path_buf).with_context(|| format!("Failed to read file: `{}`, is it a directory?\nYou can only send one file at a time", &filepath))?;
-        *(self.file_state()) = Some(file);
-        dbg!(self.filename_state());
+        let file = File::open(&filepath).with_context(|| format!("Failed to read file: `{}`, is it a directory?\nYou can only send one file at a time", &filepath))?;
+        *(self.file_state()) = Some(Source::File(file));
+        debug!(path = filepath.as_str(); "loaded file to send");
         Ok(())
     }
+
+    /// Load content from somewhere other than a file, to send it as `name`
+    fn load_source(&mut self, source: Source, name: String) {
+        debug!(file = name.as_str(), source:? = source; "loaded content to send");
+        *(self.filename_state()) = Some(name);
+        *(self.file_state()) = Some(source);
+    }
 }
 
 impl LoadFile for Disconnected {
-    fn file_state(&mut self) -> &mut Option<File> {
+    fn file_state(&mut self) -> &mut Option<Source> {
         &mut self.file
     }
 
@@ -41,7 +67,7 @@ impl LoadFile for Disconnected {
 }
 
 impl LoadFile for Connected {
-    fn file_state(&mut self) -> &mut Option<File> {
+    fn file_state(&mut self) -> &mut Option<Source> {
         &mut self.file
     }
 
@@ -54,7 +80,7 @@ impl<S> LoadFile for Client<S>
 where
     S: LoadFile,
 {
-    fn file_state(&mut self) -> &mut Option<File> {
+    fn file_state(&mut self) -> &mut Option<Source> {
         self.state.file_state()
     }
 
@@ -64,19 +90,19 @@ where
 }
 
 impl ProtocolConnection for Connected {
-    fn connection(&mut self) -> &mut TcpStream {
+    fn connection(&mut self) -> &mut Connection {
         &mut self.connection
     }
 }
 
 impl ProtocolConnection for Negotiating {
-    fn connection(&mut self) -> &mut TcpStream {
+    fn connection(&mut self) -> &mut Connection {
         &mut self.connection
     }
 }
 
 impl ProtocolConnection for Sending {
-    fn connection(&mut self) -> &mut TcpStream {
+    fn connection(&mut self) -> &mut Connection {
         &mut self.connection
     }
 }
@@ -85,23 +111,101 @@ impl<S> ProtocolConnection for Client<S>
 where
     S: ProtocolConnection,
 {
-    fn connection(&mut self) -> &mut TcpStream {
+    fn connection(&mut self) -> &mut Connection {
         self.state.connection()
     }
 }
 /// The client is used to send files to the server
+///
+/// Each stage of the protocol is a different type of Client, and each transition between them hands back the client in
+/// its previous state on failure, with the cause in `error`, so nothing (such as the file) is lost.
+/// The Client is also an Error, so transitions can be chained with `?`:
+/// ```no_run
+/// # use fshare::{Client, Disconnected};
+/// # fn main() -> anyhow::Result<()> {
+/// let client = Client::<Disconnected>::new()
+///     .file("notes.txt")?
+///     .connect("buildbox:8080")?
+///     .negotiate()?
+///     .send()?
+///     .goodbye();
+/// # Ok(())
+/// # }
+/// ```
 #[derive(Debug)]
 pub struct Client<S> {
     state: S,
     pub error: Option<anyhow::Error>,
+    settings: Settings,
+}
+
+/// Configuration that stays with the client through every state
+#[derive(Debug)]
+pub(crate) struct Settings {
+    pub timeouts: Timeouts,
+    pub rate_limit: Option<RateLimiter>,
+    pub delta: bool,
+    pub streams: usize,
+    pub zero_copy: bool,
+    pub secret: Option<String>,
+    pub tls: Option<ClientTls>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            timeouts: Timeouts::default(),
+            rate_limit: None,
+            delta: false,
+            streams: 1,
+            zero_copy: true,
+            secret: None,
+            tls: None,
+        }
+    }
+}
+
+impl<S> Client<S> {
+    fn with_error(mut self, error: anyhow::Error) -> Self {
+        self.error = Some(error);
+        self
+    }
+}
+
+impl<S> fmt::Display for Client<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = std::any::type_name::<S>().rsplit("::").next().unwrap();
+        match &self.error {
+            // the error itself is the source, so it is part of the chain and can be downcast to
+            Some(_) => write!(f, "fshare client stopped while {}", state),
+            None => write!(f, "fshare client is {}", state),
+        }
+    }
+}
+
+impl<S> std::error::Error for Client<S>
+where
+    S: fmt::Debug,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error
+            .as_ref()
+            .map(|error| error.as_ref() as &(dyn std::error::Error + 'static))
+    }
 }
 
 #[derive(Debug)]
 pub struct Disconnected {
-    file: Option<File>,
+    file: Option<Source>,
     filename: Option<String>,
 }
 
+impl Default for Client<Disconnected> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Client<Disconnected> {
     pub fn new() -> Client<Disconnected> {
         Client {
@@ -110,109 +214,366 @@ impl Client<Disconnected> {
                 filename: None,
             },
             error: None,
+            settings: Settings::default(),
         }
     }
 
+    /// Configures the file to send
+    #[allow(clippy::result_large_err)]
+    pub fn file<T: Into<String>>(mut self, filepath: T) -> Result<Self, Self> {
+        match self.load_file(filepath) {
+            Ok(()) => Ok(self),
+            Err(e) => Err(self.with_error(e)),
+        }
+    }
+
+    /// Configures bytes in memory to send as a file called `name`
+    ///
+    /// The name may contain `/` to put the file in a subdirectory on the server.
+    pub fn bytes<N: Into<String>, B: Into<Vec<u8>>>(mut self, name: N, bytes: B) -> Self {
+        self.load_source(Source::Bytes(bytes.into()), name.into());
+        self
+    }
+
+    /// Configures a reader of `size` bytes to send as a file called `name`
+    ///
+    /// The reader is only read once, as it is sent, so it is always sent in full over a single connection, and can't
+    /// be sent again if that fails part way. The server will refuse the file if the reader ends before `size` bytes.
+    pub fn reader<N: Into<String>, R: Read + Send + 'static>(
+        mut self,
+        name: N,
+        reader: R,
+        size: u64,
+    ) -> Self {
+        self.load_source(Source::reader(reader, size), name.into());
+        self
+    }
+
+    /// Configures how long to wait on the server, and how hard to try saying Goodbye
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.settings.timeouts = timeouts;
+        self
+    }
+
+    /// Configures the most bytes per second to send file content at
+    pub fn limit(mut self, bytes_per_second: u64) -> Self {
+        self.settings.rate_limit = Some(RateLimiter::new(bytes_per_second));
+        self
+    }
+
+    /// Configures whether to only send the parts of each file that differ from the server's copy, if it has one
+    ///
+    /// This saves sending most of a large file that has changed a little, at the cost of the server reading its copy
+    /// and both ends reading the file to compare them. A file the server doesn't have is sent in full as usual.
+    pub fn delta(mut self, delta: bool) -> Self {
+        self.settings.delta = delta;
+        self
+    }
+
+    /// Configures how many connections to send each large file over at once, in ranges
+    ///
+    /// One TCP stream may not be able to fill a fast link on its own. Files smaller than a few megabytes are still
+    /// sent over a single connection, as are deltas.
+    pub fn streams(mut self, streams: usize) -> Self {
+        self.settings.streams = streams;
+        self
+    }
+
+    /// Configures whether to send files from the page cache to the connection with `sendfile(2)` where the platform
+    /// allows, which is the default on Linux
+    ///
+    /// Despite the name this isn't zero copy, each chunk is still read into a buffer to calculate its checksum, but it
+    /// isn't then written from there to the connection. Turning this off always sends the content through a buffer,
+    /// which is mostly useful to compare the two. With TLS the content always goes through a buffer to be encrypted.
+    pub fn zero_copy(mut self, zero_copy: bool) -> Self {
+        self.settings.zero_copy = zero_copy;
+        self
+    }
+
+    /// Configures the secret to prove to the server that we know, see [crate::ServerBuilder::secret]
+    pub fn secret<S: Into<String>>(mut self, secret: S) -> Self {
+        self.settings.secret = Some(secret.into());
+        self
+    }
+
+    /// Configures the client to connect with TLS, trusting `tls` to vouch for the server, see [crate::ClientTls]
+    pub fn tls(mut self, tls: ClientTls) -> Self {
+        self.settings.tls = Some(tls);
+        self
+    }
+
     pub fn try_connection<S: Into<String>>(
         &self,
         connection_string: S,
-    ) -> anyhow::Result<TcpStream> {
-        let remote_addr = connection_string.into().parse::<SocketAddr>()?;
-        Ok(TcpStream::connect(remote_addr)?)
+    ) -> anyhow::Result<Connection> {
+        let host = connection_string.into();
+        let socket = connect::connect(&host, self.settings.timeouts.connect)?;
+        open(socket, &host, &self.settings)
     }
 
+    // the client is handed back on failure so the caller can recover its file, so the error is as large as the client
+    #[allow(clippy::result_large_err)]
     pub fn connect<S: Into<String>>(
         self,
         connection_string: S,
     ) -> Result<Client<Connected>, Client<Disconnected>> {
-        match self.try_connection(connection_string) {
+        let address = connection_string.into();
+        match self.try_connection(&address) {
             Ok(connection) => {
-                connection
-                    .set_read_timeout(Some(std::time::Duration::new(5, 0)))
-                    .unwrap();
-                Ok(Client {
+                let mut client = Client {
                     state: Connected {
                         connection,
                         file: self.state.file,
                         filename: self.state.filename,
                     },
                     error: None,
-                })
+                    settings: self.settings,
+                };
+                debug!(address = address.as_str(), server = client.peer(); "connected");
+                Ok(client)
             }
-            Err(error) => Err(Client {
-                state: Disconnected {
-                    file: self.state.file,
-                    filename: self.state.filename,
-                },
-                error: Some(error),
-            }),
+            Err(error) => Err(self.with_error(error)),
         }
     }
 
     /// Convenience method for end user to send a file using the configured client
-    pub fn send(mut self, address: String, file: String) -> anyhow::Result<()> {
-        self.load_file(&file)?;
-        /* Convenient API to aim for...
-        client
-            .file(file)?
-            .connect(address)?
-            .negotiate()?
-            .send()?
-        Or something to that effect - chained method calls :)
-        */
-
-        match self.connect(address) {
-            Ok(connected_client) => {
-                let mut negotiating_client = connected_client.request()?;
-                if let Ok(protocol::Message::Ack) = negotiating_client.receive_message() {
-                    let mut sending_client = negotiating_client.accept();
-                    sending_client.send_file()?;
-                    if let Ok(protocol::Message::Ack) = sending_client.receive_message() {
-                        println!("Server acknowledged receipt of file");
-                    }
-                    println!("Closing connection");
-                    if let Ok(connected_client) = sending_client.finish() {
-                        let _disconnected_client = connected_client.goodbye();
-                    }
-                } else {
-                    let connected_client = negotiating_client.deny();
-                    let _disconnected_client = connected_client.goodbye();
-                    println!("Disconnected, the server did not accept our request");
+    pub fn send(self, address: String, file: String) -> anyhow::Result<()> {
+        self.file(file)?
+            .send_with_retry(address, RetryPolicy::default())?;
+        Ok(())
+    }
+
+    /// Send the configured file, reconnecting and starting over when the attempt fails, as allowed by the policy
+    ///
+    /// On failure the client is handed back with the error of the last attempt, and still holds the file
+    #[allow(clippy::result_large_err)]
+    pub fn send_with_retry<S: AsRef<str>>(
+        self,
+        address: S,
+        policy: RetryPolicy,
+    ) -> Result<Client<Disconnected>, Client<Disconnected>> {
+        let address = address.as_ref();
+        let mut client = self;
+        let mut attempt = 0;
+        loop {
+            thread::sleep(policy.retry.delay(attempt));
+            attempt += 1;
+            let retryable = match client.send_once(address, policy.reconnect) {
+                Ok(client) => break Ok(client),
+                Err((failed, retryable)) => {
+                    client = failed;
+                    retryable
                 }
+            };
+            if !retryable || attempt >= policy.retry.attempts {
+                break Err(client);
             }
-            Err(e) => {
-                eprintln!("Unable to connect: {}", e.error.unwrap());
+            warn!(
+                address = address,
+                file = client.state.filename.as_deref(),
+                attempt = attempt,
+                attempts = policy.retry.attempts,
+                error = format!("{:#}", client.error.as_ref().unwrap());
+                "attempt to send failed, trying again"
+            );
+        }
+    }
+
+    /// A single attempt at sending the configured file
+    /// On failure the client is handed back ready to try again, along with whether the failure is worth retrying
+    #[allow(clippy::result_large_err)]
+    fn send_once(
+        self,
+        address: &str,
+        reconnect: Reconnect,
+    ) -> Result<Client<Disconnected>, (Client<Disconnected>, bool)> {
+        let connected = self.connect(address).map_err(|client| {
+            // a server that won't take our secret won't take it next time either
+            let retryable = !client.error.as_ref().unwrap().is::<Denied>();
+            (client, retryable)
+        })?;
+        let sending = connected.negotiate().map_err(|mut client| {
+            let error = client.error.take().unwrap();
+            if error.is::<Denied>() {
+                // the server is still there and has made up its mind, asking again won't change it
+                (client.goodbye().with_error(error), false)
+            } else {
+                (client.with_error(error).abort(), true)
             }
+        })?;
+        let connected = sending.send().map_err(|client| {
+            // a reader that has been partly sent can't start over, so neither can we
+            let rewound = client.state.file.rewind().is_ok();
+            (client.abort(), rewound && reconnect == Reconnect::Always)
+        })?;
+        let client = connected.goodbye();
+        if let Some(e) = &client.error {
+            warn!(
+                address = address,
+                error = format!("{:#}", e);
+                "the file was sent, but the connection did not close cleanly"
+            );
         }
-        Ok(())
+        Ok(client)
     }
 }
 
+/// The server did not accept the file we asked to send
+#[derive(Debug)]
+pub struct Denied {
+    pub filename: String,
+    pub reason: String,
+}
+
+impl Denied {
+    /// Read the reason the server gave after sending RequestDenied
+    fn read_from(connection: &mut Connection, filename: String) -> Denied {
+        let reason = protocol::read_string(connection)
+            .unwrap_or_else(|e| format!("no reason was given ({})", e));
+        Denied { filename, reason }
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The server did not accept {}: {}",
+            self.filename, self.reason
+        )
+    }
+}
+
+impl std::error::Error for Denied {}
+
 #[derive(Debug)]
 pub struct Connected {
-    connection: TcpStream,
-    file: Option<File>,
+    connection: Connection,
+    file: Option<Source>,
     filename: Option<String>,
 }
 
 impl Client<Connected> {
-    pub fn request(mut self) -> anyhow::Result<Client<Negotiating>> {
-        if self.state.file.is_some() {
+    /// Configures another file to send over this connection
+    #[allow(clippy::result_large_err)]
+    pub fn file<T: Into<String>>(mut self, filepath: T) -> Result<Self, Self> {
+        match self.load_file(filepath) {
+            Ok(()) => Ok(self),
+            Err(e) => Err(self.with_error(e)),
+        }
+    }
+
+    /// Configures another file to send over this connection, under a name of our choosing
+    ///
+    /// The name may contain `/` to put the file in a subdirectory on the server.
+    #[allow(clippy::result_large_err)]
+    pub fn file_named<T: Into<String>, N: Into<String>>(
+        mut self,
+        filepath: T,
+        name: N,
+    ) -> Result<Self, Self> {
+        match self.load_file_named(filepath.into(), name.into()) {
+            Ok(()) => Ok(self),
+            Err(e) => Err(self.with_error(e)),
+        }
+    }
+
+    /// Configures bytes in memory to send over this connection as a file called `name`, see
+    /// [Client::<Disconnected>::bytes]
+    pub fn bytes<N: Into<String>, B: Into<Vec<u8>>>(mut self, name: N, bytes: B) -> Self {
+        self.load_source(Source::Bytes(bytes.into()), name.into());
+        self
+    }
+
+    /// Configures a reader of `size` bytes to send over this connection as a file called `name`, see
+    /// [Client::<Disconnected>::reader]
+    pub fn reader<N: Into<String>, R: Read + Send + 'static>(
+        mut self,
+        name: N,
+        reader: R,
+        size: u64,
+    ) -> Self {
+        self.load_source(Source::reader(reader, size), name.into());
+        self
+    }
+
+    /// Ask the server which files it already has, see [Manifest]
+    pub fn manifest(&mut self) -> anyhow::Result<Manifest> {
+        self.send_message(protocol::Message::ManifestRequest)?;
+        match self.receive_message()? {
+            protocol::Message::Ack => {
+                let manifest = Manifest::read_from(self.connection())?;
+                debug!(server = self.peer(), files = manifest.files.len(); "received manifest");
+                Ok(manifest)
+            }
+            protocol::Message::RequestDenied => Err(Denied::read_from(
+                self.connection(),
+                String::from("the request for its manifest"),
+            )
+            .into()),
+            message => bail!("Expected Ack, received: `{:?}`", message),
+        }
+    }
+
+    /// Ask the server to delete a file, by the name it has in the server's [Manifest]
+    pub fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        self.send_message(protocol::Message::DeleteRequest)?;
+        protocol::write_string(self.connection(), name)?;
+        match self.receive_message()? {
+            protocol::Message::Ack => {
+                info!(server = self.peer(), file = name; "server deleted the file");
+                Ok(())
+            }
+            protocol::Message::RequestDenied => {
+                Err(Denied::read_from(self.connection(), format!("deleting {}", name)).into())
+            }
+            message => bail!("Expected Ack, received: `{:?}`", message),
+        }
+    }
+
+    /// Request to transfer the configured file, and describe it for the server to consider
+    #[allow(clippy::result_large_err)]
+    pub fn request(mut self) -> Result<Client<Negotiating>, Client<Connected>> {
+        let (request, info) = match self.try_request() {
+            Ok(requested) => requested,
+            Err(e) => return Err(self.with_error(e)),
+        };
+        Ok(Client {
+            state: Negotiating {
+                connection: self.state.connection,
+                file: self.state.file.unwrap(),
+                info,
+                request,
+            },
+            error: None,
+            settings: self.settings,
+        })
+    }
+
+    fn try_request(&mut self) -> anyhow::Result<(protocol::Message, protocol::FileInfo)> {
+        if let Some(source) = &self.state.file {
             if self.state.filename.is_some() {
-                self.send_message(protocol::Message::FileTransferRequest)?;
-                let received = self.receive_message()?;
-                if let protocol::Message::Ack = received {
-                    self.send_filename()?;
-                    Ok(Client {
-                        state: Negotiating {
-                            connection: self.state.connection,
-                            file: self.state.file.unwrap(),
-                            filename: self.state.filename.unwrap(),
-                        },
-                        error: None,
-                    })
+                let size = source.size()?;
+                // a reader can only be read once in order, which is all sending it whole takes
+                let request = if self.settings.delta && source.seekable() {
+                    protocol::Message::DeltaTransferRequest
+                } else if source.seekable()
+                    && parallel::ranges(size, self.settings.streams).len() > 1
+                {
+                    protocol::Message::ParallelTransferRequest
                 } else {
-                    bail!("Expected Ack, received: `{:?}`", received)
+                    protocol::Message::FileTransferRequest
+                };
+                self.send_message(request)?;
+                let received = self.receive_message()?;
+                match received {
+                    protocol::Message::Ack => Ok((request, self.send_file_info()?)),
+                    // the server can refuse to talk to us at all
+                    protocol::Message::RequestDenied => {
+                        let filename = self.state.filename.clone().unwrap();
+                        Err(Denied::read_from(self.connection(), filename).into())
+                    }
+                    _ => bail!("Expected Ack, received: `{:?}`", received),
                 }
             } else {
                 bail!("Cannot request to transfer file: no filename has been configured!")
@@ -222,14 +583,69 @@ impl Client<Connected> {
         }
     }
 
-    pub fn send_filename(&mut self) -> anyhow::Result<()> {
-        let filename = self.state.filename.clone().ok_or(anyhow!(
-            "Could not send_filename because it has not been configured"
+    /// Request to transfer the configured file and wait for the server to accept it
+    #[allow(clippy::result_large_err)]
+    pub fn negotiate(self) -> Result<Client<Sending>, Client<Connected>> {
+        let mut negotiating_client = self.request()?;
+        match negotiating_client.receive_message() {
+            Ok(protocol::Message::Ack) => {
+                debug!(
+                    server = negotiating_client.peer(),
+                    file = negotiating_client.filename();
+                    "server accepted the file"
+                );
+                let content = match negotiating_client.read_content() {
+                    Ok(content) => content,
+                    Err(e) => return Err(negotiating_client.deny().with_error(e)),
+                };
+                let mut sending_client = negotiating_client.accept();
+                sending_client.state.content = content;
+                Ok(sending_client)
+            }
+            Ok(protocol::Message::RequestDenied) => {
+                let filename = negotiating_client.filename().to_string();
+                let error = Denied::read_from(negotiating_client.connection(), filename);
+                Err(negotiating_client.deny().with_error(error.into()))
+            }
+            Ok(message) => {
+                let error = Denied {
+                    filename: negotiating_client.filename().to_string(),
+                    reason: format!("expected Ack, received: `{:?}`", message),
+                };
+                Err(negotiating_client.deny().with_error(error.into()))
+            }
+            Err(e) => Err(negotiating_client.deny().with_error(e)),
+        }
+    }
+
+    /// Send the name, size and metadata of the configured file
+    pub fn send_file_info(&mut self) -> anyhow::Result<protocol::FileInfo> {
+        let name = self.state.filename.clone().ok_or(anyhow!(
+            "Could not send_file_info because no filename has been configured"
         ))?;
+        let info = self
+            .state
+            .file
+            .as_ref()
+            .ok_or(anyhow!(
+                "Could not send_file_info because no file has been configured"
+            ))?
+            .info(name)?;
+
+        info.write_to(self.connection())?;
+        debug!(
+            server = self.peer(),
+            file = info.name.as_str(),
+            bytes = info.size;
+            "sent file info"
+        );
+        Ok(info)
+    }
 
-        self.connection().write_all(filename.clone().as_bytes())?;
-        println!("sent filename: {}", filename);
-        Ok(())
+    /// Drop the connection without saying Goodbye, keeping the file and any error
+    pub fn abort(mut self) -> Client<Disconnected> {
+        let error = self.error.take();
+        self.disconnect(error)
     }
 
     fn disconnect(self, error: Option<anyhow::Error>) -> Client<Disconnected> {
@@ -239,26 +655,39 @@ impl Client<Connected> {
                 filename: self.state.filename,
             },
             error,
+            settings: self.settings,
         }
     }
 
     pub fn goodbye(mut self) -> Client<Disconnected> {
-        let max_attempts = 5;
+        let retry = self.settings.timeouts.goodbye;
         let mut attempt = 0;
         // Say Goodbye and wait for a Goodbye from server (or timeout)
         loop {
-            if let Err(e) = self.send_message(protocol::Message::Goodbye) {
-                eprintln!("Error saying Goodbye: Attempt {}", attempt);
-                attempt += 1;
-                if attempt >= max_attempts {
-                    eprintln!("Max attempts to say Goodbye reached. Disconnecting");
-                    break self.disconnect(Some(e));
-                };
-            } else {
-                if let Ok(protocol::Message::Goodbye) = self.receive_message() {
+            thread::sleep(retry.delay(attempt));
+            let error = match self.send_message(protocol::Message::Goodbye) {
+                Ok(()) => match self.receive_message() {
                     // close connection without error
-                    break self.disconnect(None);
-                }
+                    Ok(protocol::Message::Goodbye) => break self.disconnect(None),
+                    Ok(message) => anyhow!("Expected Goodbye, received: `{:?}`", message),
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+            debug!(
+                server = self.peer(),
+                attempt = attempt + 1,
+                error = format!("{:#}", error);
+                "could not say Goodbye"
+            );
+            if is_closed(&error) {
+                debug!("the server has already closed the connection, disconnecting");
+                break self.disconnect(Some(error));
+            }
+            attempt += 1;
+            if attempt >= retry.attempts {
+                debug!("out of attempts to say Goodbye, disconnecting");
+                break self.disconnect(Some(error));
             }
         }
     }
@@ -266,41 +695,157 @@ impl Client<Connected> {
 
 #[derive(Debug)]
 pub struct Negotiating {
-    connection: TcpStream,
-    file: File,
-    filename: String,
+    connection: Connection,
+    file: Source,
+    info: protocol::FileInfo,
+    /// How we asked to send the file
+    request: protocol::Message,
 }
 
 impl Client<Negotiating> {
+    /// The name of the file we are negotiating to send
+    pub fn filename(&self) -> &str {
+        &self.state.info.name
+    }
+
+    /// Once the server has accepted the file, read what it needs us to know to send it the way we asked to
+    fn read_content(&mut self) -> anyhow::Result<Content> {
+        match self.state.request {
+            // for a delta, the server goes on to describe its copy of the file, if it has one
+            protocol::Message::DeltaTransferRequest => {
+                let signatures = Signatures::read_from(self.connection())?;
+                if signatures.blocks.is_empty() {
+                    Ok(Content::Whole)
+                } else {
+                    Ok(Content::Delta(signatures))
+                }
+            }
+            protocol::Message::ParallelTransferRequest => Ok(Content::Ranges(u64::from_be_bytes(
+                protocol::read_array(self.connection())?,
+            ))),
+            _ => Ok(Content::Whole),
+        }
+    }
+
     pub fn accept(self) -> Client<Sending> {
         Client {
             state: Sending {
                 connection: self.state.connection,
                 file: self.state.file,
+                info: self.state.info,
+                content: Content::Whole,
             },
             error: None,
+            settings: self.settings,
         }
     }
 
+    /// Give up on sending the file, but keep hold of it in case we want to try again
     pub fn deny(self) -> Client<Connected> {
         Client {
             state: Connected {
                 connection: self.state.connection,
-                file: None,
-                filename: None,
+                file: Some(self.state.file),
+                filename: Some(self.state.info.name),
             },
             error: None,
+            settings: self.settings,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct Sending {
-    connection: TcpStream,
-    file: File,
+    connection: Connection,
+    file: Source,
+    info: protocol::FileInfo,
+    content: Content,
+}
+
+/// How the content of the file is sent, as agreed while negotiating
+#[derive(Debug)]
+enum Content {
+    /// Streamed in full over the connection
+    Whole,
+    /// As instructions to rebuild it from the server's copy, described by its signatures
+    Delta(Signatures),
+    /// In ranges over connections of their own, for the transfer with this id
+    Ranges(u64),
 }
 
 impl Client<Sending> {
+    /// Stream the file to the server and wait for it to acknowledge receipt
+    #[allow(clippy::result_large_err)]
+    pub fn send(mut self) -> Result<Client<Connected>, Client<Sending>> {
+        let sent = self
+            .send_file()
+            .and_then(|()| match self.receive_message()? {
+                protocol::Message::Ack => Ok(()),
+                // the server couldn't rebuild the file from the delta, and is waiting for all of it
+                protocol::Message::RequestDenied
+                    if matches!(self.state.content, Content::Delta(_)) =>
+                {
+                    let reason = protocol::read_string(self.connection())?;
+                    warn!(
+                        server = self.peer(),
+                        file = self.state.info.name.as_str(),
+                        reason = reason.as_str();
+                        "the server could not use the delta, sending all of the file"
+                    );
+                    self.state.content = Content::Whole;
+                    self.send_file()?;
+                    match self.receive_message()? {
+                        protocol::Message::Ack => Ok(()),
+                        message => bail!("Expected Ack, received: `{:?}`", message),
+                    }
+                }
+                message => bail!("Expected Ack, received: `{:?}`", message),
+            })
+            .with_context(|| format!("Failed to send {}", self.state.info.name));
+        match sent {
+            Ok(()) => {
+                info!(
+                    server = self.peer(),
+                    file = self.state.info.name.as_str(),
+                    bytes = self.state.info.size;
+                    "server acknowledged receipt of the file"
+                );
+                Ok(Client {
+                    state: Connected {
+                        connection: self.state.connection,
+                        file: None,
+                        filename: None,
+                    },
+                    error: None,
+                    settings: self.settings,
+                })
+            }
+            Err(e) => Err(self.with_error(e)),
+        }
+    }
+
+    /// Drop the connection without saying Goodbye, keeping the file (ready to send again, unless it was a reader) and any error
+    pub fn abort(self) -> Client<Disconnected> {
+        let error = match (self.state.file.rewind(), self.error) {
+            (Ok(_), error) => error,
+            (Err(e), Some(error)) => {
+                Some(error.context(format!("Could not rewind the file to send it again: {}", e)))
+            }
+            (Err(e), None) => {
+                Some(anyhow::Error::from(e).context("Could not rewind the file to send it again"))
+            }
+        };
+        Client {
+            state: Disconnected {
+                file: Some(self.state.file),
+                filename: Some(self.state.info.name),
+            },
+            error,
+            settings: self.settings,
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
     pub fn finish(mut self) -> Result<Client<Connected>, Client<Sending>> {
         match self.send_message(protocol::Message::Goodbye) {
             Ok(_) => Ok(Client {
@@ -310,22 +855,126 @@ impl Client<Sending> {
                     filename: None,
                 },
                 error: None,
+                settings: self.settings,
             }),
-            Err(e) => Err(Client {
-                state: Sending { ..self.state },
-                error: Some(e),
-            }),
+            Err(e) => Err(self.with_error(e)),
         }
     }
 
+    /// Send the content of the file, the way that was agreed while negotiating
     pub fn send_file(&mut self) -> anyhow::Result<()> {
-        let size = self.state.file.metadata()?.len();
-        // send file size so server knows how much to read
-        // TODO security - we should send the file size sooner so that it can be negotiated, but then confirm the file size is the same (perhaps it was written to in the meantime by another process?)
-        self.state.connection.write(&size.to_be_bytes())?;
+        // the server already knows how much to read from negotiating
+        let size = self.state.info.size;
+
+        match &self.state.content {
+            Content::Whole => {}
+            Content::Delta(signatures) => {
+                let mut writer = BufWriter::new(&mut self.state.connection);
+                let sent = delta::write_delta(
+                    &mut self.state.file.positioned(),
+                    size,
+                    signatures,
+                    &mut writer,
+                    self.settings.rate_limit.as_ref(),
+                )?;
+                debug!(
+                    file = self.state.info.name.as_str(),
+                    bytes = size,
+                    sent = sent.literal;
+                    "sent changes to file"
+                );
+                return Ok(());
+            }
+            Content::Ranges(id) => {
+                let checksum = parallel::send_ranges(
+                    self.state.connection.peer_addr()?,
+                    self.state.connection.host(),
+                    *id,
+                    &self.state.file,
+                    size,
+                    &self.settings,
+                )?;
+                // every range has arrived, all that's left is to check the whole file
+                protocol::write_end(&mut self.state.connection, checksum)?;
+                return Ok(());
+            }
+        }
 
-        let mut buffer = BufReader::new(&mut self.state.file);
-        io::copy(&mut buffer, &mut self.state.connection)?;
+        // stream the content in chunks, each with its own checksum, and finish with the checksum of the whole file
+        // we only send as much as we announced, the server will notice if the file has shrunk since
+        let mut sender = ChunkSender::new(
+            &self.state.file,
+            &self.state.connection,
+            self.settings.zero_copy,
+        );
+        let mut checksum = Crc32::new();
+        let mut offset = 0;
+        while offset < size {
+            let sent = sender.send(offset, size - offset, &mut checksum)?;
+            if sent == 0 {
+                break;
+            }
+            if let Some(limiter) = &self.settings.rate_limit {
+                limiter.take(sent);
+            }
+            offset += sent as u64;
+        }
+        protocol::write_end(&mut self.state.connection, checksum.finish())?;
         Ok(())
     }
 }
+
+/// Whether an error means the other end has closed the connection, so there's no point trying again on it
+fn is_closed(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(io::ErrorKind::UnexpectedEof)
+            | Some(io::ErrorKind::BrokenPipe)
+            | Some(io::ErrorKind::ConnectionReset)
+            | Some(io::ErrorKind::ConnectionAborted)
+    )
+}
+
+/// Get a newly made connection to `host` ready to speak the protocol over, encrypting it and proving we know the
+/// server's secret if the settings say to
+pub(crate) fn open(
+    socket: TcpStream,
+    host: &str,
+    settings: &Settings,
+) -> anyhow::Result<Connection> {
+    socket.set_read_timeout(Some(settings.timeouts.read))?;
+    socket.set_write_timeout(Some(settings.timeouts.write))?;
+    // messages are small and each waits on a reply, so send them straight away rather than waiting for more
+    socket.set_nodelay(true)?;
+    let mut connection = match &settings.tls {
+        Some(tls) => Connection::client(socket, tls, host)?,
+        None => Connection::plain(socket),
+    };
+    if let Some(secret) = &settings.secret {
+        authenticate(&mut connection, secret)?;
+    }
+    Ok(connection)
+}
+
+/// Prove to the server that we know its secret, see [crate::auth]
+fn authenticate(connection: &mut Connection, secret: &str) -> anyhow::Result<()> {
+    let denied = |connection: &mut Connection| {
+        anyhow::Error::from(Denied::read_from(connection, String::from("our secret")))
+    };
+    connection.send_message(protocol::Message::AuthRequest)?;
+    match connection.receive_message()? {
+        protocol::Message::Ack => {}
+        protocol::Message::RequestDenied => return Err(denied(connection)),
+        message => bail!("Expected Ack, received: `{:?}`", message),
+    }
+    let challenge: [u8; auth::CHALLENGE_SIZE] = protocol::read_array(connection)?;
+    connection.write_all(&auth::answer(secret, &challenge))?;
+    match connection.receive_message()? {
+        protocol::Message::Ack => {
+            debug!("proved we know the server's secret");
+            Ok(())
+        }
+        protocol::Message::RequestDenied => Err(denied(connection)),
+        message => bail!("Expected Ack, received: `{:?}`", message),
+    }
+}
@@ -0,0 +1,565 @@
+//! Settings read from a TOML file, so they don't have to be given on the command line every time
+//!
+//! Every setting is optional, and named after its command line flag:
+//! ```toml
+//! [server]
+//! directory = "/srv/fshare"
+//! max_file_size = "2G"
+//! allow = ["10.0.3.0/24"]
+//! audit_log = "/var/log/fshare.jsonl"
+//! secret = "correct horse battery staple"
+//! tls_cert = "/etc/fshare/buildbox.pem"
+//! tls_key = "/etc/fshare/buildbox.key"
+//!
+//! # defaults for every file the client sends
+//! [client]
+//! retries = 3
+//!
+//! # servers to send to by name, used with `fshare client -a nas`
+//! [remotes]
+//! nas = "192.168.1.20:8080"
+//!
+//! # a remote can also have settings of its own
+//! [remotes.buildbox]
+//! address = "10.0.3.17:8080"
+//! limit = "10M"
+//! secret = "correct horse battery staple"
+//! tls = "/etc/fshare/ca.pem"
+//! ```
+//! Sizes are a number of bytes or a string such as `"10M"`, durations are strings such as `"5s"` or `"500ms"`.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use log::debug;
+use serde::{de, Deserialize, Deserializer};
+use toml_edit::{DocumentMut, Item, Table};
+
+use super::access::{AccessRules, Cidr};
+use super::client::{Client, Disconnected};
+use super::limits::parse_size;
+use super::server::ServerBuilder;
+use super::timeouts::{parse_duration, Reconnect, RetryPolicy, Timeouts};
+use super::tls::{ClientTls, ServerTls};
+
+/// The address a server listens on when none is configured
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0:8080";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// Defaults for sending to any server
+    pub client: ClientConfig,
+    /// Servers to send to by name, and any settings particular to each of them
+    #[serde(deserialize_with = "remotes")]
+    pub remotes: BTreeMap<String, ClientConfig>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Could not read the config file {:?}", path))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config file {:?}", path))
+    }
+
+    /// Load the config file at `path` if one is given, otherwise the one in the default location if there is one
+    pub fn find(path: Option<&Path>) -> anyhow::Result<Config> {
+        match path {
+            Some(path) => Config::load(path),
+            None => match Config::default_path().filter(|path| path.exists()) {
+                Some(path) => {
+                    debug!(path:? = path; "loading the default config file");
+                    Config::load(path)
+                }
+                None => Ok(Config::default()),
+            },
+        }
+    }
+
+    /// `fshare/config.toml` in the user's config directory, e.g. `~/.config/fshare/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let directory = if cfg!(windows) {
+            std::env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        };
+        directory.map(|directory| directory.join("fshare").join("config.toml"))
+    }
+
+    /// The settings to send to `name` with, which may be a remote or just an address
+    ///
+    /// A remote's settings take precedence over the `[client]` defaults, and its address is used in place of its name.
+    pub fn client_for(&self, name: &str) -> ClientConfig {
+        match self.remotes.get(name) {
+            Some(remote) => {
+                debug!(remote = name, address = remote.address.as_deref(); "resolved remote");
+                ClientConfig {
+                    address: Some(remote.address.clone().unwrap_or_else(|| name.to_string())),
+                    ..remote.clone()
+                }
+                .or(self.client.clone())
+            }
+            None => ClientConfig {
+                address: Some(name.to_string()),
+                ..self.client.clone()
+            },
+        }
+    }
+
+    /// Add a remote to the config file at `path`, or change the address of an existing one, along with the secret to
+    /// send to it with and the certificate to trust for TLS if they are given
+    ///
+    /// Everything else in the file, including comments and any other settings of the remote, is left as it is. The file
+    /// is created if it doesn't exist.
+    pub fn add_remote(
+        path: &Path,
+        name: &str,
+        address: &str,
+        secret: Option<&str>,
+        tls: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+            bail!(
+                "Invalid remote name `{}`, it can't contain `:` or spaces",
+                name
+            );
+        }
+        match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => bail!(
+                "Invalid address `{}`, expected a host and port such as `10.0.3.17:8080`",
+                address
+            ),
+        }
+        let mut document = edit(path)?;
+        let remotes = document
+            .entry("remotes")
+            .or_insert_with(|| {
+                // only show the [remotes] header once there is something in it
+                let mut remotes = Table::new();
+                remotes.set_implicit(true);
+                Item::Table(remotes)
+            })
+            .as_table_like_mut()
+            .ok_or(anyhow!("`remotes` in {:?} is not a table", path))?;
+        let mut settings = vec![("address", toml_edit::value(address))];
+        if let Some(secret) = secret {
+            settings.push(("secret", toml_edit::value(secret)));
+        }
+        if let Some(tls) = tls {
+            let tls = tls.to_str().ok_or(anyhow!(
+                "Can't save {:?} in the config file, it isn't valid UTF-8",
+                tls
+            ))?;
+            settings.push(("tls", toml_edit::value(tls)));
+        }
+        match remotes.get_mut(name).and_then(Item::as_table_like_mut) {
+            // keep any settings particular to the remote
+            Some(remote) => {
+                for (key, value) in settings {
+                    remote.insert(key, value);
+                }
+            }
+            // just an address is written as one, anything more needs a table of its own
+            None if settings.len() == 1 => {
+                remotes.insert(name, toml_edit::value(address));
+            }
+            None => {
+                remotes.insert(name, Item::Table(settings.into_iter().collect()));
+            }
+        }
+        save(path, &document)
+    }
+
+    /// Remove a remote from the config file at `path`, returning whether there was one to remove
+    pub fn remove_remote(path: &Path, name: &str) -> anyhow::Result<bool> {
+        let mut document = edit(path)?;
+        let removed = document
+            .get_mut("remotes")
+            .and_then(Item::as_table_like_mut)
+            .and_then(|remotes| remotes.remove(name))
+            .is_some();
+        if removed {
+            save(path, &document)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// The config file at `path` as a document that can be changed without losing its comments and layout
+fn edit(path: &Path) -> anyhow::Result<DocumentMut> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Could not read the config file {:?}", path))
+        }
+    };
+    text.parse()
+        .with_context(|| format!("Invalid config file {:?}", path))
+}
+
+fn save(path: &Path, document: &DocumentMut) -> anyhow::Result<()> {
+    let text = document.to_string();
+    // never write a file we wouldn't be able to read back
+    toml::from_str::<Config>(&text).with_context(|| format!("Invalid config file {:?}", path))?;
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(path, text).with_context(|| format!("Could not write the config file {:?}", path))
+}
+
+/// Everything a client can be configured with
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// The server to send to
+    pub address: Option<String>,
+    #[serde(deserialize_with = "duration")]
+    pub connect_timeout: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub read_timeout: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub write_timeout: Option<Duration>,
+    pub goodbye_attempts: Option<u32>,
+    #[serde(deserialize_with = "duration")]
+    pub goodbye_backoff: Option<Duration>,
+    /// Bytes per second
+    #[serde(deserialize_with = "size")]
+    pub limit: Option<u64>,
+    pub retries: Option<u32>,
+    #[serde(deserialize_with = "duration")]
+    pub retry_backoff: Option<Duration>,
+    #[serde(deserialize_with = "parsed")]
+    pub reconnect: Option<Reconnect>,
+    /// Only send what has changed from the server's copy of a file
+    pub delta: Option<bool>,
+    /// How many connections to send each large file over at once
+    pub streams: Option<usize>,
+    /// The secret the server only talks to clients that know
+    pub secret: Option<String>,
+    /// The certificate of the authority that signed the server's, to connect with TLS
+    pub tls: Option<PathBuf>,
+}
+
+impl ClientConfig {
+    /// These settings, with any that are missing taken from `fallback`
+    pub fn or(self, fallback: ClientConfig) -> ClientConfig {
+        ClientConfig {
+            address: self.address.or(fallback.address),
+            connect_timeout: self.connect_timeout.or(fallback.connect_timeout),
+            read_timeout: self.read_timeout.or(fallback.read_timeout),
+            write_timeout: self.write_timeout.or(fallback.write_timeout),
+            goodbye_attempts: self.goodbye_attempts.or(fallback.goodbye_attempts),
+            goodbye_backoff: self.goodbye_backoff.or(fallback.goodbye_backoff),
+            limit: self.limit.or(fallback.limit),
+            retries: self.retries.or(fallback.retries),
+            retry_backoff: self.retry_backoff.or(fallback.retry_backoff),
+            reconnect: self.reconnect.or(fallback.reconnect),
+            delta: self.delta.or(fallback.delta),
+            streams: self.streams.or(fallback.streams),
+            secret: self.secret.or(fallback.secret),
+            tls: self.tls.or(fallback.tls),
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        let mut timeouts = Timeouts::default();
+        timeouts.connect = self.connect_timeout.unwrap_or(timeouts.connect);
+        timeouts.read = self.read_timeout.unwrap_or(timeouts.read);
+        timeouts.write = self.write_timeout.unwrap_or(timeouts.write);
+        timeouts.goodbye.attempts = self.goodbye_attempts.unwrap_or(timeouts.goodbye.attempts);
+        timeouts.goodbye.backoff = self.goodbye_backoff.unwrap_or(timeouts.goodbye.backoff);
+        timeouts
+    }
+
+    /// Sending is only tried once unless retries are configured, and always reconnects unless configured otherwise
+    pub fn retry_policy(&self) -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        policy.retry.attempts = self.retries.unwrap_or(0).saturating_add(1);
+        policy.retry.backoff = self.retry_backoff.unwrap_or(policy.retry.backoff);
+        policy.reconnect = self.reconnect.unwrap_or(Reconnect::Always);
+        policy
+    }
+
+    /// A client configured with these settings, ready to be given a file
+    ///
+    /// Returns an error if the certificate to trust for TLS can't be read.
+    pub fn client(&self) -> anyhow::Result<Client<Disconnected>> {
+        let mut client = Client::<Disconnected>::new()
+            .timeouts(self.timeouts())
+            .delta(self.delta.unwrap_or(false))
+            .streams(self.streams.unwrap_or(1));
+        if let Some(bytes_per_second) = self.limit {
+            client = client.limit(bytes_per_second);
+        }
+        if let Some(secret) = &self.secret {
+            client = client.secret(secret.as_str());
+        }
+        if let Some(path) = &self.tls {
+            client = client.tls(ClientTls::trust(path)?);
+        }
+        Ok(client)
+    }
+}
+
+/// Everything a server can be configured with
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The address to listen on
+    pub address: Option<String>,
+    /// Where to store received files
+    pub directory: Option<PathBuf>,
+    pub preserve_metadata: Option<bool>,
+    #[serde(deserialize_with = "size")]
+    pub max_file_size: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub max_directory_size: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub min_free_space: Option<u64>,
+    /// Bytes per second across all connections
+    #[serde(deserialize_with = "size")]
+    pub limit: Option<u64>,
+    /// Bytes per second for each connection
+    #[serde(deserialize_with = "size")]
+    pub connection_limit: Option<u64>,
+    #[serde(deserialize_with = "parsed_list")]
+    pub allow: Vec<Cidr>,
+    #[serde(deserialize_with = "parsed_list")]
+    pub deny: Vec<Cidr>,
+    pub access_file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    #[serde(deserialize_with = "duration")]
+    pub read_timeout: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub write_timeout: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub idle_timeout: Option<Duration>,
+    pub goodbye_attempts: Option<u32>,
+    #[serde(deserialize_with = "duration")]
+    pub goodbye_backoff: Option<Duration>,
+    /// The name to answer clients looking for servers on the local network with
+    pub announce: Option<String>,
+    /// Whether clients syncing a directory may delete files
+    pub allow_delete: Option<bool>,
+    /// A command for the shell to run for each file once it is stored
+    pub on_receive: Option<String>,
+    /// The most connections to handle at once
+    pub max_connections: Option<usize>,
+    /// The secret clients must prove they know
+    pub secret: Option<String>,
+    /// The certificate to prove who the server is with, to only talk TLS
+    pub tls_cert: Option<PathBuf>,
+    /// The private key of the certificate
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// These settings, with any that are missing taken from `fallback`
+    ///
+    /// Lists of allowed or denied networks are replaced as a whole, rather than combined.
+    pub fn or(self, fallback: ServerConfig) -> ServerConfig {
+        let or_list = |list: Vec<Cidr>, fallback: Vec<Cidr>| {
+            if list.is_empty() {
+                fallback
+            } else {
+                list
+            }
+        };
+        ServerConfig {
+            address: self.address.or(fallback.address),
+            directory: self.directory.or(fallback.directory),
+            preserve_metadata: self.preserve_metadata.or(fallback.preserve_metadata),
+            max_file_size: self.max_file_size.or(fallback.max_file_size),
+            max_directory_size: self.max_directory_size.or(fallback.max_directory_size),
+            min_free_space: self.min_free_space.or(fallback.min_free_space),
+            limit: self.limit.or(fallback.limit),
+            connection_limit: self.connection_limit.or(fallback.connection_limit),
+            allow: or_list(self.allow, fallback.allow),
+            deny: or_list(self.deny, fallback.deny),
+            access_file: self.access_file.or(fallback.access_file),
+            audit_log: self.audit_log.or(fallback.audit_log),
+            read_timeout: self.read_timeout.or(fallback.read_timeout),
+            write_timeout: self.write_timeout.or(fallback.write_timeout),
+            idle_timeout: self.idle_timeout.or(fallback.idle_timeout),
+            goodbye_attempts: self.goodbye_attempts.or(fallback.goodbye_attempts),
+            goodbye_backoff: self.goodbye_backoff.or(fallback.goodbye_backoff),
+            announce: self.announce.or(fallback.announce),
+            allow_delete: self.allow_delete.or(fallback.allow_delete),
+            on_receive: self.on_receive.or(fallback.on_receive),
+            max_connections: self.max_connections.or(fallback.max_connections),
+            secret: self.secret.or(fallback.secret),
+            tls_cert: self.tls_cert.or(fallback.tls_cert),
+            tls_key: self.tls_key.or(fallback.tls_key),
+        }
+    }
+
+    /// The address to listen on, `0.0.0.0:8080` unless configured otherwise
+    pub fn address(&self) -> &str {
+        self.address.as_deref().unwrap_or(DEFAULT_ADDRESS)
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        let mut timeouts = Timeouts::default();
+        timeouts.read = self.read_timeout.unwrap_or(timeouts.read);
+        timeouts.write = self.write_timeout.unwrap_or(timeouts.write);
+        timeouts.idle = self.idle_timeout.unwrap_or(timeouts.idle);
+        timeouts.goodbye.attempts = self.goodbye_attempts.unwrap_or(timeouts.goodbye.attempts);
+        timeouts.goodbye.backoff = self.goodbye_backoff.unwrap_or(timeouts.goodbye.backoff);
+        timeouts
+    }
+
+    /// A server builder configured with these settings, receiving into the current directory unless configured otherwise
+    pub fn builder(&self) -> anyhow::Result<ServerBuilder> {
+        let mut server = ServerBuilder::new();
+        server.directory(
+            self.directory
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("./")),
+        )?;
+        server
+            .timeouts(self.timeouts())
+            .preserve_metadata(self.preserve_metadata.unwrap_or(false));
+        if let Some(bytes) = self.max_file_size {
+            server.max_file_size(bytes);
+        }
+        if let Some(bytes) = self.max_directory_size {
+            server.max_directory_bytes(bytes);
+        }
+        if let Some(bytes) = self.min_free_space {
+            server.min_free_space(bytes);
+        }
+        if let Some(bytes_per_second) = self.limit {
+            server.rate_limit(bytes_per_second);
+        }
+        if let Some(bytes_per_second) = self.connection_limit {
+            server.connection_rate_limit(bytes_per_second);
+        }
+        if let Some(path) = &self.access_file {
+            server.access_rules(AccessRules::load(path)?);
+        }
+        server.access_rules(AccessRules {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+        });
+        if let Some(path) = &self.audit_log {
+            server.audit_log(path)?;
+        }
+        if let Some(name) = &self.announce {
+            server.announce(name);
+        }
+        server.allow_delete(self.allow_delete.unwrap_or(false));
+        if let Some(command) = &self.on_receive {
+            server.on_receive_command(command);
+        }
+        if let Some(connections) = self.max_connections {
+            server.max_connections(connections);
+        }
+        if let Some(secret) = &self.secret {
+            server.secret(secret);
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(certificate), Some(key)) => {
+                server.tls(ServerTls::load(certificate, key)?);
+            }
+            (None, None) => {}
+            _ => bail!("TLS needs both a certificate and its private key, set both `tls_cert` and `tls_key`"),
+        }
+        Ok(server)
+    }
+}
+
+/// A duration such as `"5s"` or `"500ms"`
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_duration(&value).map(Some).map_err(de::Error::custom)
+}
+
+/// A number of bytes, or a size such as `"10M"`
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(value) => parse_size(&value).map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Anything that can be parsed from a string
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// A list of anything that can be parsed from a string
+fn parsed_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| value.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+/// Remotes are either just an address, such as `nas = "192.168.1.20:8080"`, or a table of client settings
+fn remotes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, ClientConfig>, D::Error> {
+    struct Remote(ClientConfig);
+
+    impl<'de> Deserialize<'de> for Remote {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct RemoteVisitor;
+
+            impl<'de> de::Visitor<'de> for RemoteVisitor {
+                type Value = Remote;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    write!(f, "an address or a table of client settings")
+                }
+
+                fn visit_str<E: de::Error>(self, address: &str) -> Result<Remote, E> {
+                    Ok(Remote(ClientConfig {
+                        address: Some(address.to_string()),
+                        ..ClientConfig::default()
+                    }))
+                }
+
+                fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Remote, A::Error> {
+                    ClientConfig::deserialize(de::value::MapAccessDeserializer::new(map))
+                        .map(Remote)
+                }
+            }
+
+            deserializer.deserialize_any(RemoteVisitor)
+        }
+    }
+
+    let remotes = BTreeMap::<String, Remote>::deserialize(deserializer)?;
+    Ok(remotes
+        .into_iter()
+        .map(|(name, Remote(remote))| (name, remote))
+        .collect())
+}
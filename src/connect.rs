@@ -0,0 +1,94 @@
+//! Connecting to a host that may resolve to several addresses, Happy Eyeballs style ([RFC 8305])
+//!
+//! Every resolved address is tried, alternating between IPv6 and IPv4. Rather than waiting for each attempt to time out
+//! before trying the next one, a new attempt is started every [ATTEMPT_DELAY] while earlier attempts are still running,
+//! and the first connection to succeed wins.
+//!
+//! [RFC 8305]: https://tools.ietf.org/html/rfc8305
+
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+
+/// How long to give a connection attempt before starting the next one alongside it
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolve `host` (e.g. `buildbox:8080` or `10.0.3.17:8080`) and connect to the first address that answers
+pub fn connect(host: &str, timeout: Duration) -> anyhow::Result<TcpStream> {
+    let addresses = host
+        .to_socket_addrs()
+        .with_context(|| format!("Could not resolve `{}`", host))?
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        bail!("`{}` did not resolve to any addresses", host);
+    }
+    let addresses = interleave(addresses);
+
+    let (sender, receiver) = mpsc::channel();
+    let attempt = |address: SocketAddr| {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            // nobody is listening anymore if another attempt already succeeded
+            let _ = sender.send((address, TcpStream::connect_timeout(&address, timeout)));
+        });
+    };
+
+    let mut next = 0;
+    let mut in_flight = 0;
+    let mut failures: Vec<(SocketAddr, io::Error)> = Vec::new();
+    attempt(addresses[next]);
+    next += 1;
+    in_flight += 1;
+    loop {
+        let result = if next < addresses.len() {
+            match receiver.recv_timeout(ATTEMPT_DELAY) {
+                Ok(result) => Some(result),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => unreachable!("we hold a sender"),
+            }
+        } else {
+            // every attempt has started, and each one will report back within the timeout
+            Some(receiver.recv().expect("we hold a sender"))
+        };
+        match result {
+            Some((_, Ok(connection))) => return Ok(connection),
+            Some((address, Err(e))) => {
+                failures.push((address, e));
+                in_flight -= 1;
+            }
+            None => {}
+        }
+        if next < addresses.len() {
+            attempt(addresses[next]);
+            next += 1;
+            in_flight += 1;
+        } else if in_flight == 0 {
+            break;
+        }
+    }
+
+    let mut message = format!("Could not connect to `{}`, tried:", host);
+    for (address, error) in failures {
+        let _ = write!(message, "\n  {}: {}", address, error);
+    }
+    bail!(message)
+}
+
+/// Order addresses so that IPv6 and IPv4 take turns, starting with IPv6
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! Sending only the parts of a file that have changed since the server's copy, in the manner of rsync
+//!
+//! The server splits its copy (the base) into blocks and sends a [Signatures] of them: a weak rolling checksum and the
+//! CRC-32 of each block. The client slides a window over its file one byte at a time, and wherever the window matches a
+//! block of the base it tells the server to copy that block instead of sending it, so content that has moved within
+//! the file is found as well as content that is unchanged. Everything else is sent as literal data.
+//!
+//! Neither checksum is cryptographic, so the server checks the CRC-32 of the whole reconstructed file as well, and the
+//! client sends the file in full if it doesn't match.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use anyhow::bail;
+
+use super::checksum::{self, Crc32};
+use super::protocol::{self, CHUNK_SIZE};
+use super::throttle::RateLimiter;
+
+/// The smallest block a base is split into, smaller blocks find more matches but cost more signatures
+const MIN_BLOCK_SIZE: u64 = 1024;
+
+/// How much of the file the client reads at a time while looking for matching blocks
+const READ_AHEAD: usize = 4 * CHUNK_SIZE;
+
+const END: u8 = 0;
+const LITERAL: u8 = 1;
+const COPY: u8 = 2;
+
+/// The checksums of each block of the server's copy of a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Signatures {
+    /// The size of the server's copy, the last block is shorter than the rest unless this is a multiple of the block size
+    pub base_size: u64,
+    pub block_size: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BlockSignature {
+    pub weak: u32,
+    pub strong: u32,
+}
+
+impl Signatures {
+    /// The block size for a base of `size` bytes, the square root of its size within the limits
+    ///
+    /// This balances the cost of the signatures (more blocks) against the cost of a mismatch (bigger blocks).
+    pub fn block_size(size: u64) -> u32 {
+        ((size as f64).sqrt() as u64).clamp(MIN_BLOCK_SIZE, CHUNK_SIZE as u64) as u32
+    }
+
+    /// Checksum each block of the first `size` bytes of `base`, writing each signature as soon as it is calculated
+    ///
+    /// `[base size: u64][block size: u32]` then `[weak: u32][strong: u32]` for each block. Writing as we go means a
+    /// large base doesn't keep the client waiting so long for the first signature that it gives up.
+    pub fn write_for(
+        base: &mut impl Read,
+        size: u64,
+        writer: &mut impl Write,
+    ) -> anyhow::Result<Signatures> {
+        let block_size = Self::block_size(size);
+        writer.write_all(&size.to_be_bytes())?;
+        writer.write_all(&block_size.to_be_bytes())?;
+        let mut buffer = vec![0; block_size as usize];
+        let mut blocks = Vec::new();
+        let mut remaining = size;
+        while remaining > 0 {
+            let block = &mut buffer[..remaining.min(block_size as u64) as usize];
+            base.read_exact(block)?;
+            let signature = BlockSignature {
+                weak: Rolling::new(block).digest(),
+                strong: checksum::crc32(block),
+            };
+            writer.write_all(&signature.weak.to_be_bytes())?;
+            writer.write_all(&signature.strong.to_be_bytes())?;
+            blocks.push(signature);
+            remaining -= block.len() as u64;
+        }
+        Ok(Signatures {
+            base_size: size,
+            block_size,
+            blocks,
+        })
+    }
+
+    /// The signatures of a base that doesn't exist, so the file must be sent in full
+    pub fn write_empty(writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_all(&0u64.to_be_bytes())?;
+        writer.write_all(&0u32.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> anyhow::Result<Signatures> {
+        let base_size = u64::from_be_bytes(protocol::read_array(reader)?);
+        let block_size = u32::from_be_bytes(protocol::read_array(reader)?);
+        if base_size == 0 {
+            return Ok(Signatures::default());
+        }
+        if block_size == 0 || block_size as usize > CHUNK_SIZE {
+            bail!("Invalid block size of {} bytes", block_size);
+        }
+        let count = base_size.div_ceil(block_size as u64);
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            blocks.push(BlockSignature {
+                weak: u32::from_be_bytes(protocol::read_array(reader)?),
+                strong: u32::from_be_bytes(protocol::read_array(reader)?),
+            });
+        }
+        Ok(Signatures {
+            base_size,
+            block_size,
+            blocks,
+        })
+    }
+
+    /// The offset and length of a block in the base
+    pub fn block(&self, index: u64) -> (u64, u64) {
+        let offset = index * self.block_size as u64;
+        (
+            offset,
+            (self.base_size - offset).min(self.block_size as u64),
+        )
+    }
+
+    /// The offset and length in the base of `count` blocks starting at block `index`, checking they are all in it
+    pub fn run(&self, index: u64, count: u32) -> anyhow::Result<(u64, u64)> {
+        let blocks = self.blocks.len() as u64;
+        if count == 0 || index >= blocks || count as u64 > blocks - index {
+            bail!(
+                "Asked to copy {} blocks from block {} of a base with {} blocks",
+                count,
+                index,
+                blocks
+            );
+        }
+        let (offset, _) = self.block(index);
+        let (last_offset, last_length) = self.block(index + count as u64 - 1);
+        Ok((offset, last_offset + last_length - offset))
+    }
+}
+
+/// The weak checksum from rsync, which can be moved along a file a byte at a time without reading the whole window
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let length = window.len() as u32;
+        let mut rolling = Rolling { a: 0, b: 0, length };
+        for (i, byte) in window.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(*byte as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add((length - i as u32).wrapping_mul(*byte as u32));
+        }
+        rolling
+    }
+
+    /// Move the window on by one byte, dropping `out` from its start and adding `into` at its end
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.length.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xFFFF) | (self.b << 16)
+    }
+}
+
+/// What the server should do to build the next part of the file
+#[derive(Debug)]
+pub(crate) enum Instruction {
+    /// The length and checksum of content the base doesn't have, which follows framed like a chunk of a full send
+    Literal { length: usize, checksum: u32 },
+    /// Copy `count` consecutive blocks of the base, starting with the block at `index`
+    Copy { index: u64, count: u32 },
+    /// The end of the file, along with the checksum of the whole file
+    End { checksum: u32 },
+}
+
+impl Instruction {
+    /// `[0][checksum: u32]`, `[1][length: u32][checksum: u32]` or `[2][index: u64][count: u32]`
+    ///
+    /// The `length` bytes of data that follow a literal are left for the caller to read.
+    pub fn read_from(reader: &mut impl Read) -> anyhow::Result<Instruction> {
+        let [tag] = protocol::read_array(reader)?;
+        match tag {
+            END => Ok(Instruction::End {
+                checksum: u32::from_be_bytes(protocol::read_array(reader)?),
+            }),
+            LITERAL => match protocol::ChunkHeader::read_from(reader)? {
+                protocol::ChunkHeader::Data { length, checksum } => {
+                    Ok(Instruction::Literal { length, checksum })
+                }
+                protocol::ChunkHeader::End { .. } => bail!("Received literal data with no content"),
+            },
+            COPY => Ok(Instruction::Copy {
+                index: u64::from_be_bytes(protocol::read_array(reader)?),
+                count: u32::from_be_bytes(protocol::read_array(reader)?),
+            }),
+            _ => bail!("Could not decode delta instruction: `{}`", tag),
+        }
+    }
+}
+
+/// How a file was sent as a delta
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DeltaSent {
+    /// Bytes of content that had to be sent, the rest was copied from the base
+    pub literal: u64,
+    /// The CRC-32 of the whole file
+    pub checksum: u32,
+}
+
+/// Writes instructions in order, merging copies of consecutive blocks and splitting literal data into chunks
+struct DeltaWriter<'a, W> {
+    writer: W,
+    rate_limit: Option<&'a RateLimiter>,
+    /// Blocks that are to be copied, but haven't been written yet in case the next block follows on
+    copy: Option<(u64, u32)>,
+    literal: u64,
+}
+
+impl<W: Write> DeltaWriter<'_, W> {
+    fn copy(&mut self, index: u64) -> io::Result<()> {
+        match &mut self.copy {
+            Some((start, count)) if *start + *count as u64 == index && *count < u32::MAX => {
+                *count += 1;
+                Ok(())
+            }
+            _ => {
+                self.flush_copy()?;
+                self.copy = Some((index, 1));
+                Ok(())
+            }
+        }
+    }
+
+    fn literal(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy()?;
+        for chunk in data.chunks(CHUNK_SIZE) {
+            if let Some(limiter) = self.rate_limit {
+                limiter.take(chunk.len());
+            }
+            self.writer.write_all(&[LITERAL])?;
+            protocol::write_chunk(&mut self.writer, chunk)?;
+        }
+        self.literal += data.len() as u64;
+        Ok(())
+    }
+
+    fn flush_copy(&mut self) -> io::Result<()> {
+        if let Some((index, count)) = self.copy.take() {
+            self.writer.write_all(&[COPY])?;
+            self.writer.write_all(&index.to_be_bytes())?;
+            self.writer.write_all(&count.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn end(mut self, checksum: u32) -> io::Result<()> {
+        self.flush_copy()?;
+        self.writer.write_all(&[END])?;
+        self.writer.write_all(&checksum.to_be_bytes())?;
+        self.writer.flush()
+    }
+}
+
+/// Send `size` bytes of `file` as instructions to rebuild it from the base described by `signatures`
+///
+/// The file is read once, keeping no more than a few chunks of it in memory.
+pub(crate) fn write_delta(
+    file: &mut impl Read,
+    size: u64,
+    signatures: &Signatures,
+    writer: &mut impl Write,
+    rate_limit: Option<&RateLimiter>,
+) -> anyhow::Result<DeltaSent> {
+    if signatures.blocks.is_empty() {
+        bail!("There is no base to send a delta from");
+    }
+    let block_size = signatures.block_size as usize;
+    // only whole blocks can match a full window, the last block is looked for at the very end
+    let mut blocks: HashMap<u32, Vec<u64>> = HashMap::new();
+    for (index, block) in signatures.blocks.iter().enumerate() {
+        if signatures.block(index as u64).1 == block_size as u64 {
+            blocks.entry(block.weak).or_default().push(index as u64);
+        }
+    }
+    let last = signatures.blocks.len() as u64 - 1;
+    let (_, last_length) = signatures.block(last);
+
+    let mut file = file.take(size);
+    let mut delta = DeltaWriter {
+        writer,
+        rate_limit,
+        copy: None,
+        literal: 0,
+    };
+    let mut checksum = Crc32::new();
+    // buffer[literal..window] is content no block matched, buffer[window..window + block_size] is being looked for
+    let mut buffer = Vec::with_capacity(READ_AHEAD + CHUNK_SIZE + block_size);
+    let mut literal = 0;
+    let mut window = 0;
+    let mut rolling: Option<Rolling> = None;
+    let mut end_of_file = false;
+    loop {
+        if buffer.len() - window < block_size && !end_of_file {
+            // let go of what has been dealt with, and read on
+            buffer.drain(..literal);
+            window -= literal;
+            literal = 0;
+            let read = (&mut file)
+                .take(READ_AHEAD as u64)
+                .read_to_end(&mut buffer)?;
+            checksum.update(&buffer[buffer.len() - read..]);
+            end_of_file = read == 0;
+            // the bytes the checksum would roll in weren't here before
+            rolling = None;
+            continue;
+        }
+        if buffer.len() - window < block_size {
+            // what's left is shorter than a block, it can only be the base's last block
+            let rest = &buffer[window..];
+            let matches_last = !rest.is_empty()
+                && rest.len() as u64 == last_length
+                && signatures.blocks[last as usize]
+                    == (BlockSignature {
+                        weak: Rolling::new(rest).digest(),
+                        strong: checksum::crc32(rest),
+                    });
+            if matches_last {
+                delta.literal(&buffer[literal..window])?;
+                delta.copy(last)?;
+            } else {
+                delta.literal(&buffer[literal..])?;
+            }
+            break;
+        }
+
+        let current = &buffer[window..window + block_size];
+        let weak = rolling
+            .get_or_insert_with(|| Rolling::new(current))
+            .digest();
+        let matched = blocks.get(&weak).and_then(|candidates| {
+            let strong = checksum::crc32(current);
+            // when the same block appears more than once, prefer the one that carries on the copy before it
+            let next = delta.copy.map(|(start, count)| start + count as u64);
+            candidates
+                .iter()
+                .filter(|&&index| signatures.blocks[index as usize].strong == strong)
+                .min_by_key(|&&index| Some(index) != next)
+        });
+        if let Some(&index) = matched {
+            delta.literal(&buffer[literal..window])?;
+            delta.copy(index)?;
+            window += block_size;
+            literal = window;
+            rolling = None;
+            continue;
+        }
+        match buffer.get(window + block_size) {
+            Some(&into) => rolling.as_mut().unwrap().roll(buffer[window], into),
+            None => rolling = None,
+        }
+        window += 1;
+        if window - literal == CHUNK_SIZE {
+            delta.literal(&buffer[literal..window])?;
+            literal = window;
+        }
+    }
+    let checksum = checksum.finish();
+    let literal = delta.literal;
+    delta.end(checksum)?;
+    Ok(DeltaSent { literal, checksum })
+}
+
+/// Copy `count` blocks of the base starting at block `index` to `writer`, returning how many bytes were copied
+pub(crate) fn copy_blocks<B: Read + Seek>(
+    base: &mut B,
+    signatures: &Signatures,
+    index: u64,
+    count: u32,
+    writer: &mut impl Write,
+    checksum: &mut Crc32,
+) -> anyhow::Result<u64> {
+    let (offset, length) = signatures.run(index, count)?;
+    base.seek(SeekFrom::Start(offset))?;
+    let mut base = base.take(length);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let read = base.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        checksum.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+    }
+    // a base that has shrunk since its signatures were sent will be caught by the checksum of the whole file
+    Ok(copied)
+}
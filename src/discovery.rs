@@ -0,0 +1,235 @@
+//! Finding fshare servers on the local network without knowing their addresses
+//!
+//! A server that has a name answers queries broadcast to a UDP port, [DISCOVERY_PORT] by default, with its name, the
+//! TCP port it receives files on and what it is capable of. Datagrams are small JSON objects, for example a query is
+//! `{"fshare":"discover"}` and an answer is `{"fshare":"announce","name":"buildbox","port":8080,...}`.
+//!
+//! Broadcasts don't cross routers, so only servers on the same network can be found.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use super::limits::format_size;
+
+/// The UDP port servers listen for queries on, the same number as the default TCP port
+pub const DISCOVERY_PORT: u16 = 8080;
+
+/// How long to wait for servers on the local network to answer, unless told otherwise
+pub const DISCOVERY_WAIT: Duration = Duration::from_secs(1);
+
+/// Big enough for any answer, and small enough to never be fragmented
+const MAX_DATAGRAM: usize = 1400;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "fshare", rename_all = "lowercase")]
+enum Datagram {
+    Discover,
+    Announce(Announcement),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Announcement {
+    name: String,
+    /// The address the server receives files on, if it only listens on one, otherwise wherever the answer came from
+    #[serde(default)]
+    ip: Option<IpAddr>,
+    /// The TCP port the server receives files on
+    port: u16,
+    version: String,
+    #[serde(default)]
+    capabilities: Capabilities,
+}
+
+/// What a server will do with the files it is sent
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Whether received files keep the modification time and permissions they were sent with
+    pub preserve_metadata: bool,
+    /// The largest file the server accepts, in bytes
+    pub max_file_size: Option<u64>,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_file_size {
+            Some(bytes) => write!(f, "files up to {}", format_size(bytes))?,
+            None => write!(f, "files of any size")?,
+        }
+        if self.preserve_metadata {
+            write!(f, ", preserves metadata")?;
+        }
+        Ok(())
+    }
+}
+
+/// A server that answered a query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub name: String,
+    /// Where to send files to the server
+    pub address: SocketAddr,
+    /// The version of fshare the server is running
+    pub version: String,
+    pub capabilities: Capabilities,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:<21} fshare {}, {}",
+            self.name, self.address, self.version, self.capabilities
+        )
+    }
+}
+
+/// Answers queries from clients looking for servers, on a thread of its own
+pub(crate) struct Announcer {
+    socket: UdpSocket,
+    announcement: Announcement,
+}
+
+impl Announcer {
+    /// Listen for queries on `port`, answering that files can be sent to `name` at `receiving_on`
+    pub(crate) fn bind(
+        port: u16,
+        name: &str,
+        receiving_on: SocketAddr,
+        capabilities: Capabilities,
+    ) -> anyhow::Result<Announcer> {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).with_context(|| {
+            format!(
+                "Could not listen for discovery queries on UDP port {}",
+                port
+            )
+        })?;
+        Ok(Announcer {
+            socket,
+            announcement: Announcement {
+                name: name.to_string(),
+                ip: Some(receiving_on.ip()).filter(|ip| !ip.is_unspecified()),
+                port: receiving_on.port(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                capabilities,
+            },
+        })
+    }
+
+    pub(crate) fn spawn(self) {
+        thread::spawn(move || {
+            if let Err(e) = self.run() {
+                warn!(error = format!("{:#}", e); "stopped answering discovery queries");
+            }
+        });
+    }
+
+    fn run(&self) -> anyhow::Result<()> {
+        let answer = serde_json::to_vec(&Datagram::Announce(self.announcement.clone()))?;
+        let mut buffer = [0; MAX_DATAGRAM];
+        loop {
+            let (length, from) = self.socket.recv_from(&mut buffer)?;
+            // anyone can send anything to a UDP port, only answer what looks like a query
+            match serde_json::from_slice(&buffer[..length]) {
+                Ok(Datagram::Discover) => {
+                    debug!(peer:% = from; "answering discovery query");
+                    if let Err(e) = self.socket.send_to(&answer, from) {
+                        debug!(peer:% = from, error:% = e; "could not answer discovery query");
+                    }
+                }
+                _ => debug!(peer:% = from; "ignoring datagram"),
+            }
+        }
+    }
+}
+
+/// Broadcast a query to `broadcast`, e.g. `255.255.255.255:8080`, and collect every server that answers within `wait`
+pub fn discover(broadcast: impl ToSocketAddrs, wait: Duration) -> anyhow::Result<Vec<Peer>> {
+    let mut peers = Vec::new();
+    query(broadcast, wait, |peer| {
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+        false
+    })?;
+    peers.sort_by(|a, b| a.name.cmp(&b.name).then(a.address.cmp(&b.address)));
+    Ok(peers)
+}
+
+/// Broadcast a query to `broadcast` and return the first server called `name` to answer within `wait`
+pub fn find_peer(
+    name: &str,
+    broadcast: impl ToSocketAddrs,
+    wait: Duration,
+) -> anyhow::Result<Peer> {
+    let mut found = None;
+    query(broadcast, wait, |peer| {
+        let done = peer.name == name;
+        if done {
+            found = Some(peer);
+        }
+        done
+    })?;
+    found.ok_or(anyhow!(
+        "No server called `{}` answered within {:?}, is it running with --announce?",
+        name,
+        wait
+    ))
+}
+
+/// Broadcast a query and hand each answer to `found`, until it returns true or `wait` is up
+fn query(
+    broadcast: impl ToSocketAddrs,
+    wait: Duration,
+    mut found: impl FnMut(Peer) -> bool,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&serde_json::to_vec(&Datagram::Discover)?, broadcast)?;
+    let deadline = Instant::now() + wait;
+    let mut buffer = [0; MAX_DATAGRAM];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return Ok(());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (length, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_slice(&buffer[..length]) {
+            Ok(Datagram::Announce(announcement)) => {
+                let peer = Peer {
+                    name: announcement.name,
+                    address: SocketAddr::new(
+                        announcement.ip.unwrap_or(from.ip()),
+                        announcement.port,
+                    ),
+                    version: announcement.version,
+                    capabilities: announcement.capabilities,
+                };
+                debug!(peer:% = peer.address, name = peer.name.as_str(); "found server");
+                if found(peer) {
+                    return Ok(());
+                }
+            }
+            _ => debug!(peer:% = from; "ignoring datagram"),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Running something of our own once a server has stored a file, such as unpacking, scanning or sending a notification
+//!
+//! Hooks are given to [crate::ServerBuilder::on_receive], or a command line to
+//! [crate::ServerBuilder::on_receive_command], e.g. `fshare server --on-receive 'tar -xzf "$FSHARE_PATH" -C /srv/unpacked'`
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use super::protocol::FileInfo;
+use super::time::format_timestamp;
+
+/// A file the server has received and stored, as told to hooks
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received {
+    /// The name, size and metadata the client sent
+    pub info: FileInfo,
+    /// Where the file was stored, if it went to the server's directory rather than a sink of our own
+    pub path: Option<PathBuf>,
+    /// The address of the client that sent the file
+    pub peer: String,
+    /// The CRC-32 of the content
+    pub checksum: u32,
+    /// How long the content took to arrive
+    pub duration: Duration,
+}
+
+type Hook = Arc<dyn Fn(&Received) + Send + Sync>;
+
+/// How many files can be waiting for a command to run for them
+const MAX_QUEUED: usize = 256;
+
+/// Everything to call once a file is stored, shared by every connection
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<Hook>,
+}
+
+impl Hooks {
+    pub fn add(&mut self, hook: impl Fn(&Received) + Send + Sync + 'static) {
+        self.hooks.push(Arc::new(hook));
+    }
+
+    /// Call every hook, in the order they were added
+    pub fn run(&self, received: &Received) {
+        for hook in &self.hooks {
+            hook(received);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hooks({})", self.hooks.len())
+    }
+}
+
+/// A hook that runs `command` with the shell for a file, logging how it exits
+///
+/// Commands run one at a time, in the order files were stored, on a thread of their own so the client isn't kept
+/// waiting. A client sending thousands of small files can't start thousands of processes at once, once
+/// [MAX_QUEUED] files are waiting the connections storing more wait for the command to catch up.
+pub(crate) fn command(command: String) -> impl Fn(&Received) + Send + Sync + 'static {
+    let (queue, files) = mpsc::sync_channel::<Received>(MAX_QUEUED);
+    // stops once the server, and so the hook holding the queue, is dropped
+    thread::spawn(move || {
+        for received in files {
+            run_command(&command, &received);
+        }
+    });
+    move |received| {
+        let _ = queue.send(received.clone());
+    }
+}
+
+/// Run `command` for a file and wait for it to finish, logging how it exits and what it wrote to stderr if it failed
+fn run_command(command: &str, received: &Received) {
+    let file = received.info.name.as_str();
+    debug!(file = file, command = command; "running command for received file");
+    let output = shell(command)
+        .envs(environment(received))
+        .stdin(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            info!(file = file, command = command, status:% = output.status; "command for received file succeeded")
+        }
+        Ok(output) => warn!(
+            file = file,
+            command = command,
+            status:% = output.status,
+            stderr = String::from_utf8_lossy(&output.stderr).trim_end();
+            "command for received file failed"
+        ),
+        Err(e) => warn!(
+            file = file,
+            command = command,
+            error:% = e;
+            "could not run command for received file"
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// The environment variables describing a received file to a command
+fn environment(received: &Received) -> Vec<(&'static str, String)> {
+    let mut environment = vec![
+        ("FSHARE_FILE", received.info.name.clone()),
+        ("FSHARE_SIZE", received.info.size.to_string()),
+        ("FSHARE_CHECKSUM", format!("{:08x}", received.checksum)),
+        ("FSHARE_PEER", received.peer.clone()),
+        (
+            "FSHARE_DURATION_MS",
+            received.duration.as_millis().to_string(),
+        ),
+    ];
+    if let Some(path) = &received.path {
+        environment.push(("FSHARE_PATH", path.to_string_lossy().to_string()));
+    }
+    if let Some(modified) = received.info.modified {
+        environment.push(("FSHARE_MODIFIED", format_timestamp(modified)));
+    }
+    if let Some(mode) = received.info.mode {
+        environment.push(("FSHARE_MODE", format!("{:o}", mode)));
+    }
+    environment
+}
@@ -4,43 +4,321 @@
 //!
 //! Having said that, it may be a useful reference to get started with `std::io` networking primitives such as `Read`, `Write` and `TcpStream`.
 //!
-//! There are no network related dependencies. Originally there were 0 dependencies but at the moment it depends on a few crates for convenience:
+//! Originally there were 0 dependencies but at the moment it depends on a few crates, only two of them network related:
 //!
 //! * **anyhow** - simple error handling ideal for applications
 //! * **argh** - opinionated command line parsing
+//! * **log** - a logging facade, so the library never prints and applications choose where logs go
+//! * **serde** and **serde_json** - for the audit log and the command line's `--log-json` output
+//! * **toml** and **toml_edit** - for the config file, which `fshare remote` edits without losing its comments
+//! * **rustls** and **ring** - for encrypting connections with TLS, and proving a client knows the server's secret
 //!
 //! It is a functional tool for sending and receiving files on the network though its features are limited in scope.
 //!
 //! # Usage
 //! ```text
-//! Usage: fshare server [<directory>] [-a <address>]
+//! Usage: fshare [-v] [-q] [--log-json] [--config <config>] <command> [<args>]
+//!
+//! send or receive files between hosts
+//!
+//! Options:
+//!   -v, --verbose     log more detail, repeat for even more (-v -v)
+//!   -q, --quiet       log less, repeat to only log errors (-q -q)
+//!   --log-json        log as JSON, one object per line, for other programs to read
+//!   --config          the config file to read settings from, instead of
+//!                     `fshare/config.toml` in the user's config directory
+//!   --help, help      display usage information
+//!
+//! Commands:
+//!   client            Run the client to send files to an fshare server
+//!   server            Run the server to receive files from an fshare client
+//!   log               Show the records in a server's audit log
+//!   remote            Manage the named remotes in the config file
+//!   peers             List the servers on the local network by name
+//!   peer              Send and receive files from the same process
+//!   watch             Send every file dropped into a directory, once each
+//!   sync              Mirror a directory onto an fshare server, sending only new
+//!                     or changed files
+//! ```
+//!
+//! ```text
+//! Usage: fshare server [-a <address>] [--preserve-metadata] [--no-preserve-metadata] [--max-file-size <max-file-size>] [--max-directory-size <max-directory-size>] [--min-free-space <min-free-space>] [--limit <limit>] [--connection-limit <connection-limit>] [--allow <allow...>] [--deny <deny...>] [--access-file <access-file>] [--audit-log <audit-log>] [--read-timeout <read-timeout>] [--write-timeout <write-timeout>] [--idle-timeout <idle-timeout>] [--goodbye-attempts <goodbye-attempts>] [--goodbye-backoff <goodbye-backoff>] [--announce <announce>] [--allow-delete] [--no-allow-delete] [--on-receive <on-receive>] [--max-connections <max-connections>] [--secret <secret>] [--tls-cert <tls-cert>] [--tls-key <tls-key>] [--] [<directory>]
 //!
 //! Run the server to receive files from an fshare client
 //!
+//! Positional Arguments:
+//!   directory         the directory in which to store received files, the current
+//!                     directory by default
+//!
 //! Options:
-//!   -a, --address     the address to bind the server to
-//!   --help            display usage information
+//!   -a, --address     the address to bind the server to, `0.0.0.0:8080` by default
+//!   --preserve-metadata
+//!                     apply the modification time and permissions of the original
+//!                     file to received files
+//!   --no-preserve-metadata
+//!                     don't apply them, even if `preserve_metadata` is set in the
+//!                     config file
+//!   --max-file-size   the largest file to accept, e.g. `512M` or `2G`
+//!   --max-directory-size
+//!                     the most to store in the directory, including files already
+//!                     there, e.g. `100G`
+//!   --min-free-space  how much disk space to always leave free, e.g. `1G`
+//!   --limit           the most bytes per second to receive at across all
+//!                     connections, e.g. `500K` or `10M`
+//!   --connection-limit
+//!                     the most bytes per second to receive at for each connection
+//!   --allow           only accept connections from this address or network, e.g.
+//!                     `10.0.3.0/24` (repeatable)
+//!   --deny            refuse connections from this address or network, even if
+//!                     allowed (repeatable)
+//!   --access-file     a file of `allow <network>` and `deny <network>` rules, one
+//!                     per line
+//!   --audit-log       append a record of every file received, refused or that
+//!                     failed to arrive to this file
+//!   --read-timeout    how long to wait for each read from a client, e.g. `5s` or
+//!                     `500ms`
+//!   --write-timeout   how long to wait for each write to a client
+//!   --idle-timeout    how long to keep an idle connection open while waiting for
+//!                     the client's next message
+//!   --goodbye-attempts
+//!                     how many times to try saying Goodbye before disconnecting
+//!                     anyway
+//!   --goodbye-backoff how long to wait before the first retry of Goodbye, doubling
+//!                     each time
+//!   --announce        let clients on the local network find the server by this
+//!                     name, see `fshare peers`
+//!   --allow-delete    let clients running `fshare sync --delete` delete files they
+//!                     no longer have
+//!   --no-allow-delete don't let them, even if `allow_delete` is set in the config
+//!                     file
+//!   --on-receive      a command for the shell to run for each file once it is
+//!                     stored, with its path in `$FSHARE_PATH`
+//!   --max-connections the most connections to handle at once, 256 by default
+//!   --secret          only talk to clients that prove they know this secret
+//!   --tls-cert        only talk TLS, proving who the server is with the
+//!                     certificate in this PEM file
+//!   --tls-key         the private key of the certificate given with --tls-cert, in
+//!                     a PEM file
+//!   --help, help      display usage information
 //! ```
 //!
 //! ```text
-//! Usage: fshare client <file> -a <address>
+//! Usage: fshare client [-a <address>] [--to <to>] [--connect-timeout <connect-timeout>] [--read-timeout <read-timeout>] [--write-timeout <write-timeout>] [--goodbye-attempts <goodbye-attempts>] [--goodbye-backoff <goodbye-backoff>] [--limit <limit>] [--retries <retries>] [--retry-backoff <retry-backoff>] [--reconnect <reconnect>] [--delta] [--no-delta] [--streams <streams>] [--secret <secret>] [--tls <tls>] [--] <file>
 //!
 //! Run the client to send files to an fshare server
 //!
+//! Positional Arguments:
+//!   file              a relative or absolute path to the file to send
+//!
+//! Options:
+//!   -a, --address     the address of the remote fshare server to send files to,
+//!                     e.g. `10.0.3.17:8080` or `buildbox:8080`, or the name of a
+//!                     remote in the config file
+//!   --to              the name of a server on the local network to send to, as
+//!                     listed by `fshare peers`, instead of an address
+//!   --connect-timeout how long to wait for the connection to the server, e.g. `5s`
+//!                     or `500ms`
+//!   --read-timeout    how long to wait for each read from the server
+//!   --write-timeout   how long to wait for each write to the server
+//!   --goodbye-attempts
+//!                     how many times to try saying Goodbye before disconnecting
+//!                     anyway
+//!   --goodbye-backoff how long to wait before the first retry of Goodbye, doubling
+//!                     each time
+//!   --limit           the most bytes per second to send at, e.g. `500K` or `10M`
+//!   --retries         how many times to try sending the file again if it fails
+//!   --retry-backoff   how long to wait before the first retry of sending the file,
+//!                     doubling each time
+//!   --reconnect       which failures to retry: `always` (the default) or
+//!                     `before-sending`, which never sends the file twice
+//!   --delta           if the server already has a copy of the file, only send the
+//!                     parts that have changed
+//!   --no-delta        send the whole file, even if `delta` is set in the config
+//!                     file
+//!   --streams         how many connections to send a large file over at once, in
+//!                     ranges, 1 by default
+//!   --secret          the secret the server only talks to clients that know
+//!   --tls             connect with TLS, trusting the certificate of the authority
+//!                     that signed the server's in this PEM file
+//!   --help, help      display usage information
+//! ```
+//!
+//! ```text
+//! Usage: fshare log [--peer <peer>] [--file <file>] [--outcome <outcome>] [--since <since>] [--last <last>] [--json] [--] <audit_log>
+//!
+//! Show the records in a server's audit log
+//!
+//! Positional Arguments:
+//!   audit_log         the audit log written by `fshare server --audit-log`
+//!
 //! Options:
-//!   -a, --address     the address of the remote fshare server to send files to
-//!   --help            display usage information
+//!   --peer            only show files sent from this address, e.g. `10.0.3.17`
+//!   --file            only show files with this name
+//!   --outcome         only show files that were `received`, `failed`, `refused` or
+//!                     `deleted`
+//!   --since           only show attempts from this time onwards, e.g. `2021-02-14`
+//!                     or `2021-02-14T09:30`, in UTC
+//!   --last            only show the most recent matching attempts
+//!   --json            show the records as JSON lines, as they are stored
+//!   --help, help      display usage information
+//! ```
+//!
+//! ```text
+//! Usage: fshare remote <command> [<args>]
+//!
+//! Manage the named remotes in the config file
+//!
+//! Options:
+//!   --help, help      display usage information
+//!
+//! Commands:
+//!   add               Add a remote, or change the address and settings of an
+//!                     existing one
+//!   list              List the remotes and their addresses
+//!   remove            Remove a remote
+//! ```
+//!
+//! ```text
+//! Usage: fshare remote add [--secret <secret>] [--tls <tls>] [--] <name> <address>
+//!
+//! Add a remote, or change the address and settings of an existing one
+//!
+//! Positional Arguments:
+//!   name              the name to send to the server by, e.g. `buildbox`
+//!   address           the address of the server, e.g. `10.0.3.17:8080`
+//!
+//! Options:
+//!   --secret          the secret the server only talks to clients that know
+//!   --tls             connect with TLS, trusting the certificate of the authority
+//!                     that signed the server's in this PEM file
+//!   --help, help      display usage information
+//! ```
+//!
+//! ```text
+//! Usage: fshare peers [--wait <wait>]
+//!
+//! List the servers on the local network by name
+//!
+//! Options:
+//!   --wait            how long to wait for servers to answer, `1s` by default
+//!   --help, help      display usage information
+//! ```
+//!
+//! ```text
+//! Usage: fshare peer [-a <address>] [--announce <announce>] [--] [<directory>]
+//!
+//! Send and receive files from the same process
+//!
+//! Positional Arguments:
+//!   directory         the directory in which to store received files, the current
+//!                     directory by default
+//!
+//! Options:
+//!   -a, --address     the address to receive files on, `0.0.0.0:8080` by default
+//!   --announce        let other peers on the local network find this one by name,
+//!                     see `fshare peers`
+//!   --help, help      display usage information
+//!
+//! Notes:
+//!   Commands are read from stdin, one per line:
+//!     send <file> [<server>]  send a file to an address, a remote or a server found
+//!                             with `peers`, the client's default address otherwise
+//!     peers                   list the servers on the local network
+//!     help                    show this help
+//!     quit                    stop sending and receiving files, even a file that
+//!                             is still arriving
+//! ```
+//!
+//! ```text
+//! Usage: fshare watch [-a <address>] [--interval <interval>] [--settle <settle>] [--] <directory>
+//!
+//! Send every file dropped into a directory, once each
+//!
+//! Positional Arguments:
+//!   directory         the directory to send files from
+//!
+//! Options:
+//!   -a, --address     the address of the fshare server to send files to, or the
+//!                     name of a remote in the config file
+//!   --interval        how often to look for new files, `2s` by default
+//!   --settle          how long a file must go unmodified before it is sent, so
+//!                     files still being written aren't sent, `2s` by default
+//!   --help, help      display usage information
+//! ```
+//!
+//! ```text
+//! Usage: fshare sync [-a <address>] [--delete] [--delta] [--no-delta] [--] <directory>
+//!
+//! Mirror a directory onto an fshare server, sending only new or changed files
+//!
+//! Positional Arguments:
+//!   directory         the directory to mirror
+//!
+//! Options:
+//!   -a, --address     the address of the fshare server to mirror onto, or the name
+//!                     of a remote in the config file
+//!   --delete          delete files from the server that no longer exist in the
+//!                     directory, the server must be run with --allow-delete
+//!   --delta           only send the parts of changed files that differ from the
+//!                     server's copy
+//!   --no-delta        send changed files whole, even if `delta` is set in the
+//!                     config file
+//!   --help, help      display usage information
+//! ```
+//!
+//! # Configuration
+//! Settings can also be kept in a TOML config file, read from `fshare/config.toml` in your config directory
+//! (e.g. `~/.config/fshare/config.toml`) or from the file given with `--config`. Every setting is named after its flag,
+//! and flags given on the command line take precedence over the file. A switch the file turns on, such as
+//! `preserve_metadata`, is turned off again with its `--no-` flag, e.g. `--no-preserve-metadata`. `fshare peer` uses
+//! both the `[server]` and `[client]` settings:
+//! ```toml
+//! [server]
+//! directory = "/srv/fshare"
+//! max_file_size = "2G"
+//! allow = ["10.0.3.0/24"]
+//! audit_log = "/var/log/fshare.jsonl"
+//! # only talk to clients that know the secret, over TLS
+//! secret = "correct horse battery staple"
+//! tls_cert = "/etc/fshare/buildbox.pem"
+//! tls_key = "/etc/fshare/buildbox.key"
+//! # let clients on the local network send with `fshare client --to buildbox`
+//! announce = "buildbox"
+//!
+//! # defaults for every file the client sends
+//! [client]
+//! retries = 3
+//!
+//! # servers to send to by name, used with `fshare client -a nas` and managed with `fshare remote`
+//! [remotes]
+//! nas = "192.168.1.20:8080"
+//!
+//! # a remote can also have settings of its own
+//! [remotes.buildbox]
+//! address = "10.0.3.17:8080"
+//! limit = "10M"
+//! # only send what has changed in files the server already has
+//! delta = true
+//! # the server's secret, and the certificate of the authority that signed the server's to connect with TLS
+//! secret = "correct horse battery staple"
+//! tls = "/etc/fshare/ca.pem"
 //! ```
+//! A remote has an address and the same settings as `[client]`, including a secret and a certificate to trust of its own,
+//! which `fshare remote add buildbox 10.0.3.17:8080 --secret <secret> --tls ca.pem` sets. A server with a secret only
+//! talks to clients that prove they know it, without the secret ever crossing the network. Without TLS everything else
+//! does as it is, files and their names included, so send to servers on networks you don't trust only with TLS. The
+//! server's certificate must name the host or address the client connects to.
 //!
 //! # Basic workflow:
 //! To send a file from A to B using fshare
 //! 1. start the server on machine B to receive files - choose a port to listen to connections on and a directory to write to
-//! 1. start the client on machine A to send files - choose a file and a Socket Address ip:port to send to
+//! 1. start the client on machine A to send files - choose a file and a Socket Address ip:port to send to, or the name of a server found with `fshare peers`
 //! 1. client connects to server
-//! 1. client sends filename of the file to be transferred to server
+//! 1. client sends the filename, size, modification time and permissions of the file to be transferred to server
 //! 1. server acknowledges and accepts (or alters) filename
-//! 1. client Streams file to server using TcpStream
-//! 1. server Streams file from TcpListener to a file with the selected name in the directory
+//! 1. client Streams file to server using TcpStream, in chunks that each carry a checksum
+//! 1. server Streams file from TcpListener to a hidden temporary file in the directory, verifying each chunk as it arrives
+//! 1. once the whole file has arrived intact, server renames it to the selected name
 //!
 //! # Internals
 //! * A shared protocol is used between client and server, as specified in [fshare::protocol]
@@ -49,10 +327,57 @@
 //!     * Each stage of the protocol maps to a specific type of Client, e.g. a `Client<Negotiating>` is in the middle of negotiating the filename of the file to transfer
 //! * [protocol::Server] takes a different, more flexible approach, using the [protocol::State] enum to match on and do control flow
 //!     * It will mutate itself rather than force you to return a new type.
+//!     * Each connection is handled by a Server of its own on a thread of its own, sharing the settings, rate limit and audit log, so the ranges of a file can arrive over several connections at once, up to `--max-connections` of them
+//! * On Linux, file content goes between the page cache and the connection with `sendfile(2)` and `splice(2)`, and through a buffer elsewhere or with TLS, see `cargo bench --bench zero_copy`. Despite the name that isn't zero copy, each chunk is still read into a buffer on both sides to checksum it
+//! * The client sends files, bytes in memory or any reader, and the server hands what it receives to a [sink::Sink], by default a [sink::DirectorySink] that writes each file to its directory, so files can be kept in memory, a database or an object store instead
+//!     * Once a file is stored and the client told so, the server calls its hooks with a [hooks::Received], and `--on-receive` runs a command with the file described in `FSHARE_*` environment variables
 //! * The difficulty of using the client's state machine approach led me to write a helper function [client::send] to make using it to send a file much simpler!
+//!     * Every transition hands back the Client in its previous state on failure, and a Client is also an Error, so transitions chain with `?`: `client.file(file)?.connect(address)?.negotiate()?.send()?.goodbye()`
+//!     * [client::Client::send_with_retry] uses the client handed back by a failed transition to reconnect and try again, according to a [timeouts::RetryPolicy]
+//! * The library never prints, it logs through the [log] facade with the peer, file and bytes attached as key-values, and the binary decides how and whether to show them
+//! * `fshare peer` is a [peer::PeerShell] reading commands from standard input, so the tests can give it commands of their own
+mod access;
+mod atomic;
+mod audit;
+mod auth;
+mod checksum;
+mod chunks;
 mod client;
+mod config;
+mod connect;
+mod delta;
+mod discovery;
+mod hooks;
+mod limits;
+mod manifest;
+mod outbox;
+mod parallel;
+mod peer;
 mod protocol;
 mod server;
+mod sink;
+mod source;
+mod sync;
+mod throttle;
+mod time;
+mod timeouts;
+mod tls;
 
-pub use client::{Client, Disconnected};
+pub use access::{AccessRules, Cidr};
+pub use audit::{AuditLog, AuditRecord, Outcome};
+pub use client::{Client, Connected, Denied, Disconnected, Negotiating, Sending};
+pub use config::{ClientConfig, Config, ServerConfig, DEFAULT_ADDRESS};
+pub use discovery::{discover, find_peer, Capabilities, Peer, DISCOVERY_PORT, DISCOVERY_WAIT};
+pub use hooks::Received;
+pub use limits::{format_size, parse_size};
+pub use manifest::{Manifest, ManifestEntry};
+pub use outbox::Outbox;
+pub use peer::{PeerCommand, PeerShell};
+pub use protocol::FileInfo;
 pub use server::ServerBuilder;
+pub use sink::{DirectorySink, Incoming, Sink};
+pub use sync::SyncSummary;
+pub use throttle::RateLimiter;
+pub use time::{format_timestamp, parse_timestamp};
+pub use timeouts::{parse_duration, Reconnect, Retry, RetryPolicy, Timeouts};
+pub use tls::{ClientTls, Connection, ServerTls};
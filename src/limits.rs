@@ -0,0 +1,261 @@
+//! Limits on how much a server will store, so a client can't fill the disk
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail};
+
+use super::atomic::PARTIAL_SUFFIX;
+
+/// Every limit is checked against the size the client announces while negotiating, before any content is written
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// The largest file the server will accept
+    pub max_file_size: Option<u64>,
+    /// The most bytes the server will store in its directory, including the incoming file
+    pub max_directory_bytes: Option<u64>,
+    /// How much free space must remain on the disk after receiving the file
+    pub min_free_space: Option<u64>,
+}
+
+/// What is already on the disk a file is checked against, measured before taking the lock on the reservations
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Usage {
+    /// The bytes stored in the directory, if there is a limit on them
+    used: Option<u64>,
+    /// The bytes free on the disk, if there is a limit on them
+    free: Option<u64>,
+    /// The size of the file that would be replaced, which doesn't count against us
+    replacing: u64,
+}
+
+impl Limits {
+    /// Measure what the limits on `directory` are checked against, for a file to be written to `path`
+    ///
+    /// This walks the whole directory, so it is done before [Reservations::reserve] takes its lock.
+    pub(crate) fn usage(&self, directory: &Path, path: &Path) -> anyhow::Result<Usage> {
+        let used = match self.max_directory_bytes {
+            Some(_) => Some(directory_size(directory)?),
+            None => None,
+        };
+        let free = match self.min_free_space {
+            Some(_) => Some(free_space(directory)?),
+            None => None,
+        };
+        Ok(Usage {
+            used,
+            free,
+            replacing: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        })
+    }
+
+    /// Check whether a file of `size` bytes fits in what was measured, while `reserved` bytes of other files are
+    /// still arriving
+    /// Returns the reason if it doesn't
+    pub(crate) fn check(&self, usage: &Usage, size: u64, reserved: u64) -> Option<String> {
+        if let Some(reason) = self.check_size(size) {
+            return Some(reason);
+        }
+        if let (Some(max), Some(used)) = (self.max_directory_bytes, usage.used) {
+            // the size is whatever the client announced, so it mustn't be added to anything before it's known to fit
+            if size > max {
+                return Some(format!(
+                    "the file is {}, larger than the {} allowed in the directory",
+                    format_size(size),
+                    format_size(max)
+                ));
+            }
+            let used = used
+                .saturating_add(reserved)
+                .saturating_sub(usage.replacing);
+            if !matches!(used.checked_add(size), Some(total) if total <= max) {
+                return Some(format!(
+                    "the file is {} but only {} of the {} allowed in the directory is left",
+                    format_size(size),
+                    format_size(max.saturating_sub(used)),
+                    format_size(max)
+                ));
+            }
+        }
+        if let (Some(min), Some(free)) = (self.min_free_space, usage.free) {
+            let free = free
+                .saturating_add(usage.replacing)
+                .saturating_sub(reserved);
+            if free.saturating_sub(size) < min {
+                return Some(format!(
+                    "the file is {} but only {} of disk space is free, and {} must be kept free",
+                    format_size(size),
+                    format_size(free),
+                    format_size(min)
+                ));
+            }
+        }
+        None
+    }
+
+    /// Whether there is a limit on the directory's size or on the free space left on its disk
+    pub(crate) fn limit_disk(&self) -> bool {
+        self.max_directory_bytes.is_some() || self.min_free_space.is_some()
+    }
+
+    /// Check only whether a file of `size` bytes is small enough, for files that aren't kept in a directory
+    /// Returns the reason if it isn't
+    pub fn check_size(&self, size: u64) -> Option<String> {
+        match self.max_file_size {
+            Some(max) if size > max => Some(format!(
+                "the file is {}, larger than the maximum file size of {}",
+                format_size(size),
+                format_size(max)
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The bytes of files that have been accepted but are still arriving, shared by every connection
+///
+/// Each file is checked against the limits along with those arriving alongside it, so together they can't exceed them.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Reservations {
+    reserved: Arc<Mutex<u64>>,
+}
+
+impl Reservations {
+    /// Check whether a file of `size` bytes may be written to `path` in `directory`, and if it may, set its bytes
+    /// aside until the returned reservation is dropped, once the file is stored or has failed to arrive
+    /// Returns the reason if it may not
+    pub fn reserve(
+        &self,
+        limits: &Limits,
+        directory: &Path,
+        path: &Path,
+        size: u64,
+    ) -> anyhow::Result<Result<Reservation, String>> {
+        let usage = limits.usage(directory, path)?;
+        // hold the lock while checking, so two files can't both be checked against the same space
+        // nothing done while it is held may panic, or it would be poisoned for every later file
+        let mut reserved = self.reserved.lock().unwrap();
+        if let Some(reason) = limits.check(&usage, size, *reserved) {
+            return Ok(Err(reason));
+        }
+        *reserved = match reserved.checked_add(size) {
+            Some(total) => total,
+            None => {
+                return Ok(Err(format!(
+                    "the file is {}, more than can be set aside alongside the {} of other files arriving",
+                    format_size(size),
+                    format_size(*reserved)
+                )))
+            }
+        };
+        Ok(Ok(Reservation {
+            size,
+            reserved: self.reserved.clone(),
+        }))
+    }
+}
+
+/// The bytes set aside for a file that is arriving, given back when dropped
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    size: u64,
+    reserved: Arc<Mutex<u64>>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.reserved.lock().unwrap() -= self.size;
+    }
+}
+
+/// The total size of all files in a directory and its subdirectories
+///
+/// Files still arriving are left out, they are counted in full by their reservations instead.
+fn directory_size(directory: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX)
+        {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += directory_size(&entry.path())?;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// The space available to us on the filesystem holding `directory`
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // the field types of statvfs differ between platforms
+fn free_space(directory: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(directory.as_os_str().as_bytes())?;
+    // SAFETY: statvfs is plain old data, and only written to by a successful call
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_directory: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "checking free space is only supported on unix",
+    ))
+}
+
+/// Parse a size such as `512`, `64K`, `10M` or `2G` into bytes, suffixes are powers of 1024
+pub fn parse_size(value: &str) -> anyhow::Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size: `{}`", value))?;
+    let multiplier: u64 = match unit.trim_end_matches(['B', 'b']) {
+        "" => 1,
+        "K" | "k" => 1 << 10,
+        "M" | "m" => 1 << 20,
+        "G" | "g" => 1 << 30,
+        "T" | "t" => 1 << 40,
+        _ => bail!(
+            "Invalid size unit in `{}`, expected one of K, M, G or T",
+            value
+        ),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or(anyhow!("Size `{}` is too large", value))
+}
+
+/// Format a number of bytes for people to read, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
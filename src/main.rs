@@ -1,62 +1,805 @@
+use std::io::{self, IsTerminal, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
 use argh::FromArgs;
+use log::kv::{self, VisitSource, VisitValue};
+use log::{info, LevelFilter, Log, Metadata, Record};
 
-use fshare::{Client, Disconnected, ServerBuilder};
+use anyhow::{anyhow, Context};
+use fshare::{
+    discover, find_peer, format_timestamp, parse_duration, parse_size, parse_timestamp, AuditLog,
+    Cidr, ClientConfig, Config, Outbox, Outcome, PeerShell, Reconnect, ServerConfig,
+    DISCOVERY_PORT, DISCOVERY_WAIT,
+};
 
 /// send or receive files between hosts
 #[derive(FromArgs, PartialEq, Debug)]
 struct Args {
+    /// log more detail, repeat for even more (-v -v)
+    #[argh(switch, short = 'v')]
+    verbose: u8,
+
+    /// log less, repeat to only log errors (-q -q)
+    #[argh(switch, short = 'q')]
+    quiet: u8,
+
+    /// log as JSON, one object per line, for other programs to read
+    #[argh(switch)]
+    log_json: bool,
+
+    /// the config file to read settings from, instead of `fshare/config.toml` in the user's config directory
+    #[argh(option)]
+    config: Option<PathBuf>,
+
     #[argh(subcommand)]
     subcommand: SubCommand,
 }
 
+impl Args {
+    fn log_level(&self) -> LevelFilter {
+        match 2 + self.verbose as i32 - self.quiet as i32 {
+            i32::MIN..=0 => LevelFilter::Error,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Info,
+            3 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum SubCommand {
     Client(ClientArgs),
     Server(ServerArgs),
+    Log(LogArgs),
+    Remote(RemoteArgs),
+    Peers(PeersArgs),
+    Peer(PeerArgs),
+    Watch(WatchArgs),
+    Sync(SyncArgs),
 }
 
 /// Run the client to send files to an fshare server
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "client")]
 struct ClientArgs {
-    /// the address of the remote fshare server to send files to
+    /// the address of the remote fshare server to send files to, e.g. `10.0.3.17:8080` or `buildbox:8080`, or the name of a remote in the config file
     #[argh(option, short = 'a')]
-    address: String,
+    address: Option<String>,
 
     /// a relative or absolute path to the file to send
     #[argh(positional)]
     file: String,
+
+    /// the name of a server on the local network to send to, as listed by `fshare peers`, instead of an address
+    #[argh(option)]
+    to: Option<String>,
+
+    /// how long to wait for the connection to the server, e.g. `5s` or `500ms`
+    #[argh(option, from_str_fn(duration))]
+    connect_timeout: Option<Duration>,
+
+    /// how long to wait for each read from the server
+    #[argh(option, from_str_fn(duration))]
+    read_timeout: Option<Duration>,
+
+    /// how long to wait for each write to the server
+    #[argh(option, from_str_fn(duration))]
+    write_timeout: Option<Duration>,
+
+    /// how many times to try saying Goodbye before disconnecting anyway
+    #[argh(option)]
+    goodbye_attempts: Option<u32>,
+
+    /// how long to wait before the first retry of Goodbye, doubling each time
+    #[argh(option, from_str_fn(duration))]
+    goodbye_backoff: Option<Duration>,
+
+    /// the most bytes per second to send at, e.g. `500K` or `10M`
+    #[argh(option, from_str_fn(size))]
+    limit: Option<u64>,
+
+    /// how many times to try sending the file again if it fails
+    #[argh(option)]
+    retries: Option<u32>,
+
+    /// how long to wait before the first retry of sending the file, doubling each time
+    #[argh(option, from_str_fn(duration))]
+    retry_backoff: Option<Duration>,
+
+    /// which failures to retry: `always` (the default) or `before-sending`, which never sends the file twice
+    #[argh(option)]
+    reconnect: Option<Reconnect>,
+
+    /// if the server already has a copy of the file, only send the parts that have changed
+    #[argh(switch)]
+    delta: bool,
+
+    /// send the whole file, even if `delta` is set in the config file
+    #[argh(switch)]
+    no_delta: bool,
+
+    /// how many connections to send a large file over at once, in ranges, 1 by default
+    #[argh(option)]
+    streams: Option<usize>,
+
+    /// the secret the server only talks to clients that know
+    #[argh(option)]
+    secret: Option<String>,
+
+    /// connect with TLS, trusting the certificate of the authority that signed the server's in this PEM file
+    #[argh(option)]
+    tls: Option<PathBuf>,
+}
+
+impl ClientArgs {
+    /// The settings given on the command line, apart from the address which may name a target in the config file
+    fn config(&self) -> ClientConfig {
+        ClientConfig {
+            address: None,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            goodbye_attempts: self.goodbye_attempts,
+            goodbye_backoff: self.goodbye_backoff,
+            limit: self.limit,
+            retries: self.retries,
+            retry_backoff: self.retry_backoff,
+            reconnect: self.reconnect,
+            delta: switch(self.delta, self.no_delta),
+            streams: self.streams,
+            secret: self.secret.clone(),
+            tls: self.tls.clone(),
+        }
+    }
 }
 
 /// Run the server to receive files from an fshare client
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "server")]
 struct ServerArgs {
-    /// the address to bind the server to
-    #[argh(option, short = 'a', default = r#"String::from("0.0.0.0:8080")"#)]
+    /// the address to bind the server to, `0.0.0.0:8080` by default
+    #[argh(option, short = 'a')]
+    address: Option<String>,
+
+    /// the directory in which to store received files, the current directory by default
+    #[argh(positional)]
+    directory: Option<PathBuf>,
+
+    /// apply the modification time and permissions of the original file to received files
+    #[argh(switch)]
+    preserve_metadata: bool,
+
+    /// don't apply them, even if `preserve_metadata` is set in the config file
+    #[argh(switch)]
+    no_preserve_metadata: bool,
+
+    /// the largest file to accept, e.g. `512M` or `2G`
+    #[argh(option, from_str_fn(size))]
+    max_file_size: Option<u64>,
+
+    /// the most to store in the directory, including files already there, e.g. `100G`
+    #[argh(option, from_str_fn(size))]
+    max_directory_size: Option<u64>,
+
+    /// how much disk space to always leave free, e.g. `1G`
+    #[argh(option, from_str_fn(size))]
+    min_free_space: Option<u64>,
+
+    /// the most bytes per second to receive at across all connections, e.g. `500K` or `10M`
+    #[argh(option, from_str_fn(size))]
+    limit: Option<u64>,
+
+    /// the most bytes per second to receive at for each connection
+    #[argh(option, from_str_fn(size))]
+    connection_limit: Option<u64>,
+
+    /// only accept connections from this address or network, e.g. `10.0.3.0/24` (repeatable)
+    #[argh(option)]
+    allow: Vec<Cidr>,
+
+    /// refuse connections from this address or network, even if allowed (repeatable)
+    #[argh(option)]
+    deny: Vec<Cidr>,
+
+    /// a file of `allow <network>` and `deny <network>` rules, one per line
+    #[argh(option)]
+    access_file: Option<PathBuf>,
+
+    /// append a record of every file received, refused or that failed to arrive to this file
+    #[argh(option)]
+    audit_log: Option<PathBuf>,
+
+    /// how long to wait for each read from a client, e.g. `5s` or `500ms`
+    #[argh(option, from_str_fn(duration))]
+    read_timeout: Option<Duration>,
+
+    /// how long to wait for each write to a client
+    #[argh(option, from_str_fn(duration))]
+    write_timeout: Option<Duration>,
+
+    /// how long to keep an idle connection open while waiting for the client's next message
+    #[argh(option, from_str_fn(duration))]
+    idle_timeout: Option<Duration>,
+
+    /// how many times to try saying Goodbye before disconnecting anyway
+    #[argh(option)]
+    goodbye_attempts: Option<u32>,
+
+    /// how long to wait before the first retry of Goodbye, doubling each time
+    #[argh(option, from_str_fn(duration))]
+    goodbye_backoff: Option<Duration>,
+
+    /// let clients on the local network find the server by this name, see `fshare peers`
+    #[argh(option)]
+    announce: Option<String>,
+
+    /// let clients running `fshare sync --delete` delete files they no longer have
+    #[argh(switch)]
+    allow_delete: bool,
+
+    /// don't let them, even if `allow_delete` is set in the config file
+    #[argh(switch)]
+    no_allow_delete: bool,
+
+    /// a command for the shell to run for each file once it is stored, with its path in `$FSHARE_PATH`
+    #[argh(option)]
+    on_receive: Option<String>,
+
+    /// the most connections to handle at once, 256 by default
+    #[argh(option)]
+    max_connections: Option<usize>,
+
+    /// only talk to clients that prove they know this secret
+    #[argh(option)]
+    secret: Option<String>,
+
+    /// only talk TLS, proving who the server is with the certificate in this PEM file
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+
+    /// the private key of the certificate given with --tls-cert, in a PEM file
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    /// The settings given on the command line
+    fn config(self) -> ServerConfig {
+        ServerConfig {
+            address: self.address,
+            directory: self.directory,
+            preserve_metadata: switch(self.preserve_metadata, self.no_preserve_metadata),
+            max_file_size: self.max_file_size,
+            max_directory_size: self.max_directory_size,
+            min_free_space: self.min_free_space,
+            limit: self.limit,
+            connection_limit: self.connection_limit,
+            allow: self.allow,
+            deny: self.deny,
+            access_file: self.access_file,
+            audit_log: self.audit_log,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            idle_timeout: self.idle_timeout,
+            goodbye_attempts: self.goodbye_attempts,
+            goodbye_backoff: self.goodbye_backoff,
+            announce: self.announce,
+            allow_delete: switch(self.allow_delete, self.no_allow_delete),
+            on_receive: self.on_receive,
+            max_connections: self.max_connections,
+            secret: self.secret,
+            tls_cert: self.tls_cert,
+            tls_key: self.tls_key,
+        }
+    }
+}
+
+/// Show the records in a server's audit log
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "log")]
+struct LogArgs {
+    /// the audit log written by `fshare server --audit-log`
+    #[argh(positional)]
+    audit_log: String,
+
+    /// only show files sent from this address, e.g. `10.0.3.17`
+    #[argh(option)]
+    peer: Option<String>,
+
+    /// only show files with this name
+    #[argh(option)]
+    file: Option<String>,
+
+    /// only show files that were `received`, `failed`, `refused` or `deleted`
+    #[argh(option)]
+    outcome: Option<Outcome>,
+
+    /// only show attempts from this time onwards, e.g. `2021-02-14` or `2021-02-14T09:30`, in UTC
+    #[argh(option, from_str_fn(timestamp))]
+    since: Option<SystemTime>,
+
+    /// only show the most recent matching attempts
+    #[argh(option)]
+    last: Option<usize>,
+
+    /// show the records as JSON lines, as they are stored
+    #[argh(switch)]
+    json: bool,
+}
+
+/// List the servers on the local network by name
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "peers")]
+struct PeersArgs {
+    /// how long to wait for servers to answer, `1s` by default
+    #[argh(option, from_str_fn(duration), default = "DISCOVERY_WAIT")]
+    wait: Duration,
+}
+
+/// Send and receive files from the same process
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(
+    subcommand,
+    name = "peer",
+    note = "Commands are read from stdin, one per line:
+  send <file> [<server>]  send a file to an address, a remote or a server found
+                          with `peers`, the client's default address otherwise
+  peers                   list the servers on the local network
+  help                    show this help
+  quit                    stop sending and receiving files, even a file that
+                          is still arriving"
+)]
+struct PeerArgs {
+    /// the address to receive files on, `0.0.0.0:8080` by default
+    #[argh(option, short = 'a')]
+    address: Option<String>,
+
+    /// the directory in which to store received files, the current directory by default
+    #[argh(positional)]
+    directory: Option<PathBuf>,
+
+    /// let other peers on the local network find this one by name, see `fshare peers`
+    #[argh(option)]
+    announce: Option<String>,
+}
+
+/// Send every file dropped into a directory, once each
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "watch")]
+struct WatchArgs {
+    /// the directory to send files from
+    #[argh(positional)]
+    directory: PathBuf,
+
+    /// the address of the fshare server to send files to, or the name of a remote in the config file
+    #[argh(option, short = 'a')]
+    address: Option<String>,
+
+    /// how often to look for new files, `2s` by default
+    #[argh(option, from_str_fn(duration), default = "Duration::from_secs(2)")]
+    interval: Duration,
+
+    /// how long a file must go unmodified before it is sent, so files still being written aren't sent, `2s` by default
+    #[argh(option, from_str_fn(duration), default = "Duration::from_secs(2)")]
+    settle: Duration,
+}
+
+/// Mirror a directory onto an fshare server, sending only new or changed files
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "sync")]
+struct SyncArgs {
+    /// the directory to mirror
+    #[argh(positional)]
+    directory: PathBuf,
+
+    /// the address of the fshare server to mirror onto, or the name of a remote in the config file
+    #[argh(option, short = 'a')]
+    address: Option<String>,
+
+    /// delete files from the server that no longer exist in the directory, the server must be run with --allow-delete
+    #[argh(switch)]
+    delete: bool,
+
+    /// only send the parts of changed files that differ from the server's copy
+    #[argh(switch)]
+    delta: bool,
+
+    /// send changed files whole, even if `delta` is set in the config file
+    #[argh(switch)]
+    no_delta: bool,
+}
+
+/// Manage the named remotes in the config file
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "remote")]
+struct RemoteArgs {
+    #[argh(subcommand)]
+    subcommand: RemoteCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum RemoteCommand {
+    Add(RemoteAddArgs),
+    List(RemoteListArgs),
+    Remove(RemoteRemoveArgs),
+}
+
+/// Add a remote, or change the address and settings of an existing one
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "add")]
+struct RemoteAddArgs {
+    /// the name to send to the server by, e.g. `buildbox`
+    #[argh(positional)]
+    name: String,
+
+    /// the address of the server, e.g. `10.0.3.17:8080`
+    #[argh(positional)]
     address: String,
 
-    /// the directory in which to store received files
-    #[argh(positional, default = r#"String::from("./")"#)]
-    directory: String,
+    /// the secret the server only talks to clients that know
+    #[argh(option)]
+    secret: Option<String>,
+
+    /// connect with TLS, trusting the certificate of the authority that signed the server's in this PEM file
+    #[argh(option)]
+    tls: Option<PathBuf>,
+}
+
+/// List the remotes and their addresses
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+struct RemoteListArgs {}
+
+/// Remove a remote
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "remove")]
+struct RemoteRemoveArgs {
+    /// the name of the remote
+    #[argh(positional)]
+    name: String,
+}
+
+fn duration(value: &str) -> Result<Duration, String> {
+    parse_duration(value).map_err(|e| e.to_string())
+}
+
+fn timestamp(value: &str) -> Result<SystemTime, String> {
+    parse_timestamp(value).map_err(|e| e.to_string())
+}
+
+fn size(value: &str) -> Result<u64, String> {
+    parse_size(value).map_err(|e| e.to_string())
+}
+
+/// A setting turned on by one switch and off by another, left to the config file unless either is given
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (false, true) => Some(false),
+        (false, false) => None,
+    }
+}
+
+/// Writes log records to stderr, either as text for people or as JSON lines for other programs
+struct Logger {
+    json: bool,
+}
+
+impl Logger {
+    fn text(record: &Record) -> String {
+        let mut line = format!(
+            "{} {:<5} {}",
+            format_timestamp(SystemTime::now()),
+            record.level(),
+            record.args()
+        );
+        let _ = record.key_values().visit(&mut TextFields(&mut line));
+        line
+    }
+
+    fn json(record: &Record) -> String {
+        let mut object = serde_json::Map::new();
+        object.insert("time".into(), format_timestamp(SystemTime::now()).into());
+        object.insert("level".into(), record.level().as_str().into());
+        object.insert("target".into(), record.target().into());
+        object.insert("message".into(), record.args().to_string().into());
+        let _ = record.key_values().visit(&mut JsonFields(&mut object));
+        serde_json::Value::Object(object).to_string()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = if self.json {
+            Logger::json(record)
+        } else {
+            Logger::text(record)
+        };
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Appends `key=value` to a line of text, quoting values with spaces in them
+struct TextFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        if value.contains(char::is_whitespace) || value.is_empty() {
+            self.0.push_str(&format!(" {}={:?}", key, value));
+        } else {
+            self.0.push_str(&format!(" {}={}", key, value));
+        }
+        Ok(())
+    }
+}
+
+/// Adds each key and value to a JSON object, keeping numbers and booleans as they are
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(serde_json::Value::Null);
+        value.visit(&mut json)?;
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+struct JsonValue(serde_json::Value);
+
+impl<'v> VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: kv::Value) -> Result<(), kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = serde_json::Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args: Args = argh::from_env();
+    log::set_boxed_logger(Box::new(Logger {
+        json: args.log_json,
+    }))?;
+    log::set_max_level(args.log_level());
+    // `fshare remote add` can create the config file, so it doesn't have to exist yet
+    let config = Config::find(args.config.as_deref());
     match args.subcommand {
-        SubCommand::Client(args) => client(args.address, args.file),
-        SubCommand::Server(args) => server(args.address, args.directory),
+        SubCommand::Client(args) => client(args, config?),
+        SubCommand::Server(args) => server(args, config?),
+        SubCommand::Log(args) => audit_log(args),
+        SubCommand::Peers(args) => peers(args),
+        SubCommand::Peer(args) => peer(args, config?),
+        SubCommand::Watch(args) => watch(args, config?),
+        SubCommand::Sync(args) => sync(args, config?),
+        SubCommand::Remote(remote) => {
+            let path = args.config.or_else(Config::default_path).ok_or(anyhow!(
+                "Could not find your config directory, give a config file with --config"
+            ))?;
+            match remote.subcommand {
+                RemoteCommand::Add(args) => remote_add(args, &path),
+                RemoteCommand::List(_) => remote_list(config?),
+                RemoteCommand::Remove(args) => remote_remove(args, &path),
+            }
+        }
     }
 }
 
-fn client(address: String, file: String) -> anyhow::Result<()> {
-    Client::<Disconnected>::new().send(address, file)
+fn client(args: ClientArgs, config: Config) -> anyhow::Result<()> {
+    let name = match &args.to {
+        Some(_) if args.address.is_some() => {
+            return Err(anyhow!("Give either an address with -a or a server's name with --to, not both"))
+        }
+        Some(to) => find_peer(to, ("255.255.255.255", DISCOVERY_PORT), DISCOVERY_WAIT)?
+            .address
+            .to_string(),
+        None => args
+            .address
+            .clone()
+            .or_else(|| config.client.address.clone())
+            .ok_or(anyhow!(
+                "No server to send to, give one with -a or set `address` in the [client] section of the config file"
+            ))?,
+    };
+    send_file(&args.file, &name, args.config(), &config)
+}
+
+/// Send `file` to `name`, an address or a remote, with the settings in `flags` winning over the config file
+fn send_file(file: &str, name: &str, flags: ClientConfig, config: &Config) -> anyhow::Result<()> {
+    let settings = flags.or(config.client_for(name));
+    settings
+        .client()?
+        .file(file)?
+        .send_with_retry(settings.address.as_ref().unwrap(), settings.retry_policy())?;
+    Ok(())
 }
 
-fn server(address: String, directory: String) -> anyhow::Result<()> {
-    let mut server = ServerBuilder::new();
-    server.directory(directory)?;
-    let mut server = server.build()?;
-    server.run(address)
+fn server(args: ServerArgs, config: Config) -> anyhow::Result<()> {
+    // flags given on the command line win over the config file
+    let settings = args.config().or(config.server);
+    let mut server = settings.builder()?.build()?;
+    server.run(settings.address())
+}
+
+fn peer(args: PeerArgs, config: Config) -> anyhow::Result<()> {
+    // anything not given on the command line comes from the config file, for both directions
+    let settings = ServerConfig {
+        address: args.address,
+        directory: args.directory,
+        announce: args.announce,
+        ..ServerConfig::default()
+    }
+    .or(config.server.clone());
+    let server = settings.builder()?.build()?;
+    let listener = TcpListener::bind(settings.address())?;
+    let help = PeerArgs::from_args(&["fshare", "peer"], &["--help"])
+        .unwrap_err()
+        .output;
+    PeerShell::new(config)
+        // prompt people, but keep the output of scripts clean
+        .prompt(io::stdin().is_terminal())
+        .help(help)
+        .run(server, listener, io::stdin().lock(), io::stdout())
+}
+
+fn watch(args: WatchArgs, config: Config) -> anyhow::Result<()> {
+    let name = args
+        .address
+        .or_else(|| config.client.address.clone())
+        .ok_or(anyhow!(
+            "No server to send to, give one with -a or set `address` in the [client] section of the config file"
+        ))?;
+    info!(directory:? = args.directory, server = name.as_str(); "watching for files to send");
+    Outbox::open(args.directory)?
+        .interval(args.interval)
+        .settle(args.settle)
+        .watch(|path| {
+            let file = path
+                .to_str()
+                .ok_or(anyhow!("Can't send {:?}, its path isn't valid UTF-8", path))?;
+            send_file(file, &name, ClientConfig::default(), &config)
+        })
+}
+
+fn sync(args: SyncArgs, config: Config) -> anyhow::Result<()> {
+    let name = args
+        .address
+        .or_else(|| config.client.address.clone())
+        .ok_or(anyhow!(
+            "No server to sync to, give one with -a or set `address` in the [client] section of the config file"
+        ))?;
+    let settings = ClientConfig {
+        delta: switch(args.delta, args.no_delta),
+        ..ClientConfig::default()
+    }
+    .or(config.client_for(&name));
+    settings.client()?.sync(
+        &args.directory,
+        settings.address.as_ref().unwrap(),
+        args.delete,
+    )?;
+    Ok(())
+}
+
+fn audit_log(args: LogArgs) -> anyhow::Result<()> {
+    let records: Vec<_> = AuditLog::read(&args.audit_log)?
+        .into_iter()
+        .filter(|record| {
+            // the peer's port changes with every connection, so match on the address alone
+            let peer_matches = |peer: &String| {
+                record.peer == *peer
+                    || record.peer.starts_with(&format!("{}:", peer))
+                    || record.peer.starts_with(&format!("[{}]:", peer))
+            };
+            args.peer.as_ref().is_none_or(peer_matches)
+                && args.file.as_ref().is_none_or(|file| record.file == *file)
+                && args.outcome.is_none_or(|outcome| record.outcome == outcome)
+                && args.since.is_none_or(|since| {
+                    parse_timestamp(&record.time).is_ok_and(|time| time >= since)
+                })
+        })
+        .collect();
+    let skip = records
+        .len()
+        .saturating_sub(args.last.unwrap_or(records.len()));
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for record in &records[skip..] {
+        if args.json {
+            writeln!(stdout, "{}", serde_json::to_string(record)?)?;
+        } else {
+            writeln!(stdout, "{}", record)?;
+        }
+    }
+    Ok(())
+}
+
+fn peers(args: PeersArgs) -> anyhow::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for peer in discover(("255.255.255.255", DISCOVERY_PORT), args.wait)? {
+        writeln!(stdout, "{}", peer)?;
+    }
+    Ok(())
+}
+
+fn remote_add(args: RemoteAddArgs, path: &Path) -> anyhow::Result<()> {
+    // the config file is read from wherever fshare is run
+    let tls = args
+        .tls
+        .map(|tls| {
+            tls.canonicalize()
+                .with_context(|| format!("Could not find the certificate {:?}", tls))
+        })
+        .transpose()?;
+    Config::add_remote(
+        path,
+        &args.name,
+        &args.address,
+        args.secret.as_deref(),
+        tls.as_deref(),
+    )
+}
+
+fn remote_list(config: Config) -> anyhow::Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for (name, remote) in &config.remotes {
+        writeln!(
+            stdout,
+            "{:<16} {}",
+            name,
+            remote.address.as_deref().unwrap_or(name)
+        )?;
+    }
+    Ok(())
+}
+
+fn remote_remove(args: RemoteRemoveArgs, path: &Path) -> anyhow::Result<()> {
+    if !Config::remove_remote(path, &args.name)? {
+        return Err(anyhow!(
+            "There is no remote named `{}` in {:?}",
+            args.name,
+            path
+        ));
+    }
+    Ok(())
 }
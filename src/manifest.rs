@@ -0,0 +1,204 @@
+//! A list of the files in a directory tree, used to work out which files a sync needs to send
+//!
+//! Names are relative to the directory, with `/` between their components on every platform. Hidden files and
+//! directories, such as the partial files of transfers still in progress, are left out.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use super::atomic::PARTIAL_SUFFIX;
+use super::checksum::Crc32;
+use super::protocol;
+
+/// A file as listed in a manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    /// The CRC-32 of the file's content
+    pub checksum: u32,
+}
+
+/// The files in a directory tree, by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+/// A file found by walking a directory tree
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LocalFile {
+    /// The name to use for the file in a manifest, or when sending it
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl Manifest {
+    /// List every file under `directory`, reading each one to calculate its checksum
+    pub fn of_directory(directory: &Path) -> anyhow::Result<Manifest> {
+        let mut files = BTreeMap::new();
+        for file in walk(directory)? {
+            let checksum = checksum(&file.path)?;
+            files.insert(
+                file.name,
+                ManifestEntry {
+                    size: file.size,
+                    checksum,
+                },
+            );
+        }
+        Ok(Manifest { files })
+    }
+
+    /// A series of records, each starting with a tag:
+    /// ```text
+    /// [1][name][size: u64][checksum: u32]   a file
+    /// [2]                                   nothing yet, the server is still reading a file to checksum it
+    /// [0]                                   the end
+    /// ```
+    ///
+    /// Each entry stands alone, so a server can send them as it checksums each file rather than all at the end, and
+    /// keep the client waiting while it reads a large one.
+    pub fn write_to(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        for (name, entry) in &self.files {
+            entry.write_to(writer, name)?;
+        }
+        ManifestEntry::write_end(writer)
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> anyhow::Result<Manifest> {
+        let mut files = BTreeMap::new();
+        loop {
+            match protocol::read_array(reader)? {
+                [END] => return Ok(Manifest { files }),
+                [FILE] => {
+                    let name = protocol::read_string(reader)?;
+                    let entry = ManifestEntry {
+                        size: u64::from_be_bytes(protocol::read_array(reader)?),
+                        checksum: u32::from_be_bytes(protocol::read_array(reader)?),
+                    };
+                    files.insert(name, entry);
+                }
+                [KEEP_ALIVE] => continue,
+                [tag] => bail!("Unknown manifest record: {}", tag),
+            }
+        }
+    }
+}
+
+impl ManifestEntry {
+    pub fn write_to(&self, writer: &mut impl Write, name: &str) -> anyhow::Result<()> {
+        if name.is_empty() {
+            bail!("Files in a manifest must have a name");
+        }
+        writer.write_all(&[FILE])?;
+        protocol::write_string(writer, name)?;
+        writer.write_all(&self.size.to_be_bytes())?;
+        writer.write_all(&self.checksum.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Mark the end of a manifest
+    pub fn write_end(writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_all(&[END])?;
+        Ok(())
+    }
+
+    /// Tell the client reading a manifest that more is coming, while a file takes a while to checksum
+    pub(crate) fn write_keep_alive(writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_all(&[KEEP_ALIVE])?;
+        Ok(())
+    }
+}
+
+/// The tags that start each record of a manifest on the wire, see [Manifest::write_to]
+const END: u8 = 0;
+const FILE: u8 = 1;
+const KEEP_ALIVE: u8 = 2;
+
+/// Every file under `directory` that isn't hidden, in no particular order
+pub(crate) fn walk(directory: &Path) -> anyhow::Result<Vec<LocalFile>> {
+    let mut files = Vec::new();
+    walk_into(directory, "", &mut files)
+        .with_context(|| format!("Could not list the files in {:?}", directory))?;
+    Ok(files)
+}
+
+fn walk_into(directory: &Path, prefix: &str, files: &mut Vec<LocalFile>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        // names that aren't UTF-8 can't be sent, so they can't be synced either
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => format!("{}{}", prefix, name),
+            _ => continue,
+        };
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk_into(&entry.path(), &format!("{}/", name), files)?;
+        } else if metadata.is_file() {
+            files.push(LocalFile {
+                name,
+                path: entry.path(),
+                size: metadata.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The CRC-32 of the content of the file at `path`
+pub(crate) fn checksum(path: &Path) -> anyhow::Result<u32> {
+    checksum_with_progress(path, || Ok(()))
+}
+
+/// The CRC-32 of the content of the file at `path`, calling `progress` after each chunk is read
+pub(crate) fn checksum_with_progress(
+    path: &Path,
+    mut progress: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<u32> {
+    let mut file =
+        File::open(path).with_context(|| format!("Could not read {:?} to checksum it", path))?;
+    let mut buffer = vec![0; protocol::CHUNK_SIZE];
+    let mut checksum = Crc32::new();
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(checksum.finish());
+        }
+        checksum.update(&buffer[..read]);
+        progress()?;
+    }
+}
+
+/// Where the file called `name` in a manifest lives under `directory`
+///
+/// Names come from the other end of a connection, so anything that could reach outside the directory is refused, as is
+/// anything that could be mistaken for a file still being received.
+pub(crate) fn resolve(directory: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(name);
+    let inside = !name.is_empty()
+        && !name.contains('\\')
+        && relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !inside {
+        bail!("`{}` is not a relative path inside the directory", name);
+    }
+    if relative.components().any(|component| {
+        component
+            .as_os_str()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX)
+    }) {
+        bail!(
+            "`{}` ends with `{}`, which is kept for files still being received",
+            name,
+            PARTIAL_SUFFIX
+        );
+    }
+    Ok(directory.join(relative))
+}
@@ -0,0 +1,258 @@
+//! Sending every file dropped into a directory, once it has finished arriving there
+//!
+//! The directory is polled rather than watched with inotify, which works the same on every platform and on network
+//! filesystems. A file counts as finished once it hasn't been modified for a while, so files that are still being
+//! written or copied in aren't sent half done.
+//!
+//! What has been sent is remembered in a hidden `.fshare-sent` file in the directory, one JSON object per line, so
+//! nothing is sent twice even across restarts. A file that is changed after it was sent is sent again.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::timeouts::Retry;
+
+/// Where an outbox remembers the files it has sent, hidden files are never sent so it won't send itself
+const SENT: &str = ".fshare-sent";
+
+/// A version of a file that was sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Sent {
+    file: String,
+    size: u64,
+    /// Milliseconds since the Unix epoch
+    modified: u64,
+}
+
+/// A file that couldn't be sent, and when to try it again
+struct Failed {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// A directory whose files are each sent once, see the [module docs](self)
+pub struct Outbox {
+    directory: PathBuf,
+    interval: Duration,
+    settle: Duration,
+    retry: Retry,
+    sent: HashMap<String, Sent>,
+    sent_log: File,
+    failed: HashMap<String, Failed>,
+}
+
+impl Outbox {
+    /// Open the outbox in `directory`, remembering what was sent from it before
+    pub fn open<P: Into<PathBuf>>(directory: P) -> anyhow::Result<Outbox> {
+        let directory = directory.into();
+        let path = directory.join(SENT);
+        let (sent, kept) = match fs::read_to_string(&path) {
+            Ok(text) => read_sent(&text)
+                .with_context(|| format!("Invalid record of sent files {:?}", path))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (HashMap::new(), 0),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Could not read the record of sent files {:?}", path))
+            }
+        };
+        let sent_log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Could not open the record of sent files {:?}", path))?;
+        if kept < sent_log.metadata()?.len() {
+            warn!(path:? = path; "dropping the half written last line of the record of sent files");
+            // so the next file recorded starts a line of its own
+            sent_log
+                .set_len(kept)
+                .with_context(|| format!("Could not repair the record of sent files {:?}", path))?;
+        }
+        Ok(Outbox {
+            directory,
+            interval: Duration::from_secs(2),
+            settle: Duration::from_secs(2),
+            retry: Retry {
+                attempts: u32::MAX,
+                backoff: Duration::from_secs(5),
+                max_backoff: Duration::from_secs(5 * 60),
+            },
+            sent,
+            sent_log,
+            failed: HashMap::new(),
+        })
+    }
+
+    /// How often to look for new files, every 2 seconds by default
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long a file must go unmodified before it is sent, 2 seconds by default
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// How long to wait before trying a file that failed again, doubling with each failure, from 5 seconds up to
+    /// 5 minutes by default. Files are tried for as long as the outbox is watched, so `attempts` isn't used.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Look for new files forever, handing each to `send`
+    pub fn watch(
+        &mut self,
+        mut send: impl FnMut(&Path) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        loop {
+            // the directory is shared with whoever drops files into it, so a poll that fails is tried again next time
+            if let Err(e) = self.poll(&mut send) {
+                warn!(error = format!("{:#}", e); "could not look for files in the outbox");
+            }
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Hand every finished file that hasn't been sent yet to `send` once, returning how many were sent
+    ///
+    /// A file that fails to send is left for a later poll, once its retry delay has passed.
+    pub fn poll(
+        &mut self,
+        mut send: impl FnMut(&Path) -> anyhow::Result<()>,
+    ) -> anyhow::Result<usize> {
+        let mut count = 0;
+        for file in self.finished()? {
+            if self.sent.get(&file.file) == Some(&file) {
+                continue;
+            }
+            if let Some(failed) = self.failed.get(&file.file) {
+                if failed.retry_at > Instant::now() {
+                    continue;
+                }
+            }
+            let path = self.directory.join(&file.file);
+            match send(&path) {
+                Ok(()) => {
+                    info!(file = file.file.as_str(), bytes = file.size; "sent file from the outbox");
+                    self.remember(file)?;
+                    count += 1;
+                }
+                Err(e) => {
+                    let failures = self
+                        .failed
+                        .get(&file.file)
+                        .map_or(1, |failed| failed.failures + 1);
+                    let delay = self.retry.delay(failures);
+                    warn!(
+                        file = file.file.as_str(),
+                        failures = failures,
+                        retry_in:? = delay,
+                        error = format!("{:#}", e);
+                        "could not send file from the outbox"
+                    );
+                    self.failed.insert(
+                        file.file,
+                        Failed {
+                            failures,
+                            retry_at: Instant::now() + delay,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// The files in the directory that haven't been modified for long enough to be finished, oldest first
+    fn finished(&self) -> anyhow::Result<Vec<Sent>> {
+        let now = SystemTime::now();
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.directory)
+            .with_context(|| format!("Could not read the outbox {:?}", self.directory))?
+        {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // moved out or deleted since the directory was read
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Could not read {:?}", entry.path()))
+                }
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            let modified = metadata.modified()?;
+            // a clock that went backwards shouldn't stop files from being sent
+            let age = now.duration_since(modified).unwrap_or(self.settle);
+            if age < self.settle {
+                debug!(file = name.as_str(); "waiting for file to finish arriving");
+                continue;
+            }
+            files.push(Sent {
+                file: name,
+                size: metadata.len(),
+                modified: modified
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64,
+            });
+        }
+        files.sort_by(|a, b| a.modified.cmp(&b.modified).then(a.file.cmp(&b.file)));
+        Ok(files)
+    }
+
+    /// Record that `file` was sent, one line of the record at a time
+    fn remember(&mut self, file: Sent) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&file)?;
+        line.push(b'\n');
+        self.sent_log
+            .write_all(&line)
+            .and_then(|()| self.sent_log.sync_data())
+            .with_context(|| format!("Could not record that {} was sent", file.file))?;
+        self.failed.remove(&file.file);
+        self.sent.insert(file.file.clone(), file);
+        Ok(())
+    }
+}
+
+/// Read the record of sent files, returning the files and how many bytes of the record to keep
+///
+/// A crash while a file was being recorded can leave the last line half written. That file was never counted as sent,
+/// so the line is left out to be cut from the record, rather than refusing to open the outbox ever again.
+fn read_sent(text: &str) -> anyhow::Result<(HashMap<String, Sent>, u64)> {
+    let mut sent = HashMap::new();
+    let mut kept = 0;
+    let mut lines = text.split_inclusive('\n').peekable();
+    while let Some(line) = lines.next() {
+        let last = lines.peek().is_none();
+        if last && !line.ends_with('\n') {
+            break;
+        }
+        if !line.trim().is_empty() {
+            match serde_json::from_str::<Sent>(line) {
+                Ok(file) => {
+                    sent.insert(file.file.clone(), file);
+                }
+                Err(_) if last => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        kept += line.len() as u64;
+    }
+    Ok((sent, kept))
+}
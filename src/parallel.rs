@@ -0,0 +1,333 @@
+//! Sending one large file over several connections at once, each carrying a range of it
+//!
+//! A single TCP stream often can't fill a fast link on its own. The client negotiates the file once on its first
+//! connection, and the server creates a partial file of its full size and answers with a transfer id. The client then
+//! opens a connection for each range of the file, and the server writes each range straight to its place in the
+//! partial file as it arrives. Once every range is done, the client sends the checksum of the whole file on the first
+//! connection, and the server checks it against the checksums of the ranges combined before moving the file into place.
+//!
+//! The partial file only has its disk space claimed up front when the server limits the size of its directory or the
+//! free space on its disk, and the file has been checked against them. Otherwise it is sparse, so a client can't claim
+//! the whole disk just by announcing a large file, and the space is taken as the ranges arrive.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, bail, Context};
+use log::debug;
+
+use super::checksum::{self, Crc32};
+use super::chunks::ChunkSender;
+use super::client::{self, Settings};
+use super::protocol::{self, ProtocolConnection, CHUNK_SIZE};
+use super::source::Source;
+use super::tls::Connection;
+
+/// The smallest range worth opening another connection for
+const MIN_RANGE: u64 = 1024 * 1024;
+
+/// The ranges to split a file of `size` bytes into to send it over at most `streams` connections
+///
+/// Every range but the last is a whole number of chunks. Small files get fewer ranges, possibly just one.
+pub(crate) fn ranges(size: u64, streams: usize) -> Vec<(u64, u64)> {
+    let count = (streams.max(1) as u64).min(size.div_ceil(MIN_RANGE)).max(1);
+    let length = size.div_ceil(count).next_multiple_of(CHUNK_SIZE as u64);
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < size || ranges.is_empty() {
+        let range = length.min(size - offset);
+        ranges.push((offset, range));
+        offset += range;
+    }
+    ranges
+}
+
+/// A file arriving over several connections, shared by each of them
+#[derive(Debug)]
+pub(crate) struct ParallelTransfer {
+    /// A handle on the partial file, each range is written at its own offset
+    file: File,
+    size: u64,
+    ranges: Mutex<Vec<Range>>,
+    /// Goes up whenever a connection claims a range or receives content, to tell a slow transfer from a stalled one
+    activity: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    offset: u64,
+    length: u64,
+    /// Known once all of the range has arrived
+    checksum: Option<u32>,
+}
+
+impl ParallelTransfer {
+    pub fn new(file: File, size: u64) -> ParallelTransfer {
+        ParallelTransfer {
+            file,
+            size,
+            ranges: Mutex::new(Vec::new()),
+            activity: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserve `length` bytes from `offset` for a connection to fill, unless they aren't in the file or another
+    /// connection already has some of them
+    pub fn claim(&self, offset: u64, length: u64) -> Result<(), String> {
+        if length == 0 || offset.checked_add(length).is_none_or(|end| end > self.size) {
+            return Err(format!(
+                "{} bytes from offset {} is not a range of a {} byte file",
+                length, offset, self.size
+            ));
+        }
+        let mut ranges = self.ranges.lock().unwrap();
+        if let Some(other) = ranges
+            .iter()
+            .find(|other| offset < other.offset + other.length && other.offset < offset + length)
+        {
+            return Err(format!(
+                "{} bytes from offset {} overlaps the range from offset {} that is already being sent",
+                length, offset, other.offset
+            ));
+        }
+        ranges.push(Range {
+            offset,
+            length,
+            checksum: None,
+        });
+        self.activity.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The partial file, for each range to be written to its place in
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Record that all of the range starting at `offset` has been written
+    pub fn complete(&self, offset: u64, checksum: u32) {
+        let mut ranges = self.ranges.lock().unwrap();
+        if let Some(range) = ranges.iter_mut().find(|range| range.offset == offset) {
+            range.checksum = Some(checksum);
+        }
+    }
+
+    /// Count bytes that have arrived for a range as activity on this transfer
+    pub fn record(&self, bytes: usize) {
+        self.activity.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn activity(&self) -> u64 {
+        self.activity.load(Ordering::Relaxed)
+    }
+
+    /// The number of ranges that have been claimed
+    pub fn range_count(&self) -> usize {
+        self.ranges.lock().unwrap().len()
+    }
+
+    /// The checksum of the whole file, put together from the checksums of its ranges, or what is missing
+    pub fn checksum(&self) -> Result<u32, String> {
+        let mut ranges = self.ranges.lock().unwrap().clone();
+        ranges.sort_by_key(|range| range.offset);
+        let mut checksum = Crc32::new().finish();
+        let mut offset = 0;
+        for range in ranges {
+            match range.checksum {
+                Some(range_checksum) if range.offset == offset => {
+                    checksum = checksum::combine(checksum, range_checksum, range.length);
+                    offset += range.length;
+                }
+                Some(_) => break,
+                None => {
+                    return Err(format!(
+                        "the range from offset {} did not finish arriving",
+                        range.offset
+                    ))
+                }
+            }
+        }
+        if offset != self.size {
+            return Err(format!(
+                "nothing arrived from offset {} of the {} byte file",
+                offset, self.size
+            ));
+        }
+        Ok(checksum)
+    }
+}
+
+/// The files arriving in ranges, by transfer id, shared by every connection to a server
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Transfers {
+    registry: Arc<Mutex<Registry>>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Keyed randomly for each server, so the id of one transfer says nothing about the ids of any others
+    ids: RandomState,
+    started: u64,
+    transfers: HashMap<u64, Arc<ParallelTransfer>>,
+}
+
+impl Transfers {
+    /// Register a transfer for connections to send ranges of, returning its id
+    ///
+    /// Ids can't be guessed, so a client can only join a transfer it was told the id of.
+    pub fn start(&self, transfer: ParallelTransfer) -> u64 {
+        let mut registry = self.registry.lock().unwrap();
+        let id = loop {
+            let mut hasher = registry.ids.build_hasher();
+            hasher.write_u64(registry.started);
+            registry.started += 1;
+            let id = hasher.finish();
+            if !registry.transfers.contains_key(&id) {
+                break id;
+            }
+        };
+        registry.transfers.insert(id, Arc::new(transfer));
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<ParallelTransfer>> {
+        self.registry.lock().unwrap().transfers.get(&id).cloned()
+    }
+
+    /// Stop accepting ranges for a transfer
+    pub fn finish(&self, id: u64) {
+        self.registry.lock().unwrap().transfers.remove(&id);
+    }
+}
+
+/// A connection opened to send one range of a file
+struct RangeConnection {
+    connection: Connection,
+}
+
+impl ProtocolConnection for RangeConnection {
+    fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
+}
+
+/// Send every range of `size` bytes of `source` over its own connection to `address`, returning the checksum of the
+/// whole file once the server has acknowledged them all
+///
+/// Each connection is set up like the first, to `host` if that is the name it was made to with TLS.
+pub(crate) fn send_ranges(
+    address: SocketAddr,
+    host: Option<&str>,
+    id: u64,
+    source: &Source,
+    size: u64,
+    settings: &Settings,
+) -> anyhow::Result<u32> {
+    let ranges = ranges(size, settings.streams);
+    let host = &host.map_or_else(|| address.to_string(), String::from);
+    let checksums = thread::scope(|scope| {
+        let sending: Vec<_> = ranges
+            .iter()
+            .map(|&(offset, length)| {
+                scope.spawn(move || {
+                    send_range(address, host, id, source, offset, length, settings).with_context(
+                        || format!("Failed to send {} bytes from offset {}", length, offset),
+                    )
+                })
+            })
+            .collect();
+        sending
+            .into_iter()
+            .map(|range| range.join().unwrap())
+            .collect::<anyhow::Result<Vec<u32>>>()
+    })?;
+    let mut checksum = Crc32::new().finish();
+    for (&(_, length), range_checksum) in ranges.iter().zip(checksums) {
+        checksum = checksum::combine(checksum, range_checksum, length);
+    }
+    Ok(checksum)
+}
+
+fn send_range(
+    address: SocketAddr,
+    host: &str,
+    id: u64,
+    source: &Source,
+    offset: u64,
+    length: u64,
+    settings: &Settings,
+) -> anyhow::Result<u32> {
+    let socket = TcpStream::connect_timeout(&address, settings.timeouts.connect)?;
+    let connection = client::open(socket, host, settings)?;
+    let mut range = RangeConnection { connection };
+    range.send_message(protocol::Message::RangeTransferRequest)?;
+    expect_ack(&mut range)?;
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&id.to_be_bytes());
+    header.extend_from_slice(&offset.to_be_bytes());
+    header.extend_from_slice(&length.to_be_bytes());
+    range.connection.write_all(&header)?;
+    expect_ack(&mut range)?;
+
+    let mut sender = ChunkSender::new(source, &range.connection, settings.zero_copy);
+    let mut checksum = Crc32::new();
+    let mut position = 0;
+    while position < length {
+        let sent = sender.send(offset + position, length - position, &mut checksum)?;
+        if sent == 0 {
+            bail!("The content ended at offset {}", offset + position);
+        }
+        if let Some(limiter) = &settings.rate_limit {
+            limiter.take(sent);
+        }
+        position += sent as u64;
+    }
+    protocol::write_end(&mut range.connection, checksum.finish())?;
+    expect_ack(&mut range)?;
+    debug!(server:% = address, offset = offset, bytes = length; "sent range");
+
+    // the range has arrived, the server hanging up without a Goodbye doesn't change that
+    if range.send_message(protocol::Message::Goodbye).is_ok() {
+        let _ = range.receive_message();
+    }
+    Ok(checksum.finish())
+}
+
+fn expect_ack(range: &mut RangeConnection) -> anyhow::Result<()> {
+    match range.receive_message()? {
+        protocol::Message::Ack => Ok(()),
+        protocol::Message::RequestDenied => {
+            let reason = protocol::read_string(range.connection())?;
+            Err(anyhow!("The server refused the range: {}", reason))
+        }
+        message => bail!("Expected Ack, received: `{:?}`", message),
+    }
+}
+
+/// Reserve `size` bytes of disk for `file`, so ranges can be written anywhere in it without running out of space
+///
+/// Only for files that have been checked against limits on the disk, see the module docs.
+pub(crate) fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        if size > 0 {
+            // SAFETY: the file descriptor belongs to `file` and stays open for the duration of the call
+            match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, size as libc::off_t) } {
+                0 => return Ok(()),
+                // not every filesystem can, setting the length still works there
+                libc::EOPNOTSUPP | libc::EINVAL => {}
+                error => return Err(io::Error::from_raw_os_error(error)),
+            }
+        }
+    }
+    file.set_len(size)
+}
@@ -0,0 +1,188 @@
+//! Sending files from the same process that receives them, as `fshare peer` does
+//!
+//! A server receives files on a thread of its own while commands to send files are read one per line, e.g. from
+//! standard input. Both directions work at once, so a file can be sent while another is still arriving.
+
+use std::io::{BufRead, Write};
+use std::net::TcpListener;
+use std::str::FromStr;
+use std::thread;
+
+use anyhow::anyhow;
+use log::{error, info};
+
+use super::config::Config;
+use super::discovery::{discover, find_peer, DISCOVERY_PORT, DISCOVERY_WAIT};
+use super::server::Server;
+
+/// Runs the commands of a peer, read one per line, while its server receives files in the background
+pub struct PeerShell {
+    config: Config,
+    prompt: bool,
+    help: String,
+}
+
+impl PeerShell {
+    /// Send files with the `[client]` settings and remotes of `config`
+    pub fn new(config: Config) -> PeerShell {
+        PeerShell {
+            config,
+            prompt: false,
+            help: String::new(),
+        }
+    }
+
+    /// Whether to write a prompt before reading each command, for people rather than scripts, off by default
+    pub fn prompt(mut self, prompt: bool) -> Self {
+        self.prompt = prompt;
+        self
+    }
+
+    /// What to write for the `help` command
+    pub fn help<T: Into<String>>(mut self, help: T) -> Self {
+        self.help = help.into();
+        self
+    }
+
+    /// Receive files with `server` on `listener`, while running each command read from `commands`, until they end or
+    /// one says `quit`
+    ///
+    /// What `peers` finds and `help` says is written to `output`. A command that fails is logged, and doesn't stop
+    /// the server or the commands after it. The server carries on receiving on its thread once this returns.
+    pub fn run(
+        &self,
+        mut server: Server,
+        listener: TcpListener,
+        mut commands: impl BufRead,
+        mut output: impl Write,
+    ) -> anyhow::Result<()> {
+        info!(address:% = listener.local_addr()?; "receiving files");
+        thread::spawn(move || {
+            if let Err(e) = server.serve(listener) {
+                error!(error = format!("{:#}", e); "stopped receiving files");
+            }
+        });
+
+        let mut line = String::new();
+        loop {
+            if self.prompt {
+                write!(output, "fshare> ")?;
+                output.flush()?;
+            }
+            line.clear();
+            if commands.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let result = match line.parse() {
+                Ok(PeerCommand::Nothing) => Ok(()),
+                Ok(PeerCommand::Send { file, to: None }) => self
+                    .config
+                    .client
+                    .address
+                    .clone()
+                    .ok_or(anyhow!(
+                        "No server to send to, give one or set `address` in the [client] section of the config file"
+                    ))
+                    .and_then(|name| self.send(&file, &name)),
+                Ok(PeerCommand::Send { file, to: Some(name) }) => {
+                    self.resolve(&name).and_then(|name| self.send(&file, &name))
+                }
+                Ok(PeerCommand::Peers) => self.peers(&mut output),
+                Ok(PeerCommand::Help) => writeln!(output, "{}", self.help).map_err(Into::into),
+                Ok(PeerCommand::Quit) => return Ok(()),
+                Err(e) => Err(e),
+            };
+            // a failed command shouldn't stop the peer from receiving files, or from running the next command
+            if let Err(e) = result {
+                error!(error = format!("{:#}", e); "command failed");
+            }
+        }
+    }
+
+    /// Send `file` to the address or remote `name`
+    fn send(&self, file: &str, name: &str) -> anyhow::Result<()> {
+        let settings = self.config.client_for(name);
+        settings
+            .client()?
+            .file(file)?
+            .send_with_retry(settings.address.as_ref().unwrap(), settings.retry_policy())?;
+        Ok(())
+    }
+
+    /// An address or remote as it is, or the address of a server on the local network with that name
+    fn resolve(&self, name: &str) -> anyhow::Result<String> {
+        if name.contains(':') || self.config.remotes.contains_key(name) {
+            return Ok(name.to_string());
+        }
+        let peer = find_peer(name, ("255.255.255.255", DISCOVERY_PORT), DISCOVERY_WAIT)?;
+        Ok(peer.address.to_string())
+    }
+
+    /// List the servers on the local network
+    fn peers(&self, output: &mut impl Write) -> anyhow::Result<()> {
+        for peer in discover(("255.255.255.255", DISCOVERY_PORT), DISCOVERY_WAIT)? {
+            writeln!(output, "{}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+/// A line read by a [PeerShell]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerCommand {
+    /// A blank line or a comment, starting with `#`
+    Nothing,
+    /// `send <file> [<server>]`, to the client's default address unless a server is given
+    Send { file: String, to: Option<String> },
+    /// `peers`
+    Peers,
+    /// `help`
+    Help,
+    /// `quit` or `exit`
+    Quit,
+}
+
+impl FromStr for PeerCommand {
+    type Err = anyhow::Error;
+
+    /// Double quotes keep a word with spaces in it together, e.g. `send "my file.txt" nas`
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words = words(line);
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        Ok(match words.as_slice() {
+            [] => PeerCommand::Nothing,
+            [comment, ..] if comment.starts_with('#') => PeerCommand::Nothing,
+            ["send", file] => PeerCommand::Send {
+                file: file.to_string(),
+                to: None,
+            },
+            ["send", file, to] => PeerCommand::Send {
+                file: file.to_string(),
+                to: Some(to.to_string()),
+            },
+            ["peers"] => PeerCommand::Peers,
+            ["help"] => PeerCommand::Help,
+            ["quit"] | ["exit"] => PeerCommand::Quit,
+            _ => return Err(anyhow!("Unknown command `{}`, try `help`", line.trim())),
+        })
+    }
+}
+
+/// Split a command into words, double quotes keep a word with spaces in it together
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
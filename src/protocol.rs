@@ -9,11 +9,11 @@
 //!    Connected |                             | Connected
 //!              |<---------- Ack -------------|
 //!  Negotiating |                             | Negotiating
-//!              |----- <Stream FileName> ---->|
+//!              |------ <File Info> --------->|
 //!  Negotiating |                             | Negotiating
 //!              |<---------- Ack -------------|
 //!      Sending |                             | Receiving
-//!              |--- <Stream File Content> -->|
+//!              |--- <Stream File Chunks> --->|
 //!      Sending |                             | Receiving
 //!              |<---------- Ack -------------|
 //!    Connected |                             | Connected
@@ -22,13 +22,121 @@
 //!              |<-------- Goodbye -----------|
 //! Disconnected |                             | Listening
 //! ```
+//!
+//! # Authentication
+//! A server with a secret only talks to clients that prove they know it, see [crate::auth]. Before anything else, the
+//! client asks to authenticate and answers the server's challenge:
+//! ```text
+//!   Client     |                             | Server
+//!  ------------|                             |------------------
+//!    Connected |------- AuthRequest -------->| Connected
+//!              |<--- Ack + <Challenge> ------|
+//!              |-------- <Answer> ---------->|
+//!              |<---------- Ack -------------|
+//!    Connected |                             | Connected
+//! ```
+//! The challenge and answer are 32 bytes each. A wrong answer is met with RequestDenied and a reason, as is anything
+//! but AuthRequest first, and the server hangs up. A server without a secret accepts any answer.
+//!
+//! With TLS, see [crate::ServerBuilder::tls], all of this happens over the encrypted connection.
+//!
+//! # File info
+//! While negotiating, the client describes the file it wants to send, see [FileInfo].
+//! All integers are big-endian:
+//! ```text
+//! [name length: u16][name: utf8][size: u64][flags: u8][modified seconds: u64][modified nanoseconds: u32][mode: u32]
+//! ```
+//! The flags say which of the optional fields are present (see [FileInfo::write_to]), absent fields are sent as 0.
+//!
+//! # Content framing
+//! File content is streamed as a series of chunks:
+//! ```text
+//! [length: u32][checksum: u32][data: length bytes]
+//! ```
+//! Each chunk carries at most [CHUNK_SIZE] bytes and the CRC-32 of its data, so the receiver can detect
+//! corruption as soon as it happens and report the offset at which it occurred.
+//!
+//! A chunk with a length of 0 ends the stream, its checksum field is the CRC-32 of the whole file.
+//!
+//! # Syncing a directory
+//! While connected, a client can also ask what the server already has, and remove files it no longer needs:
+//! ```text
+//!   Client     |                             | Server
+//!  ------------|                             |------------------
+//!    Connected |------ ManifestRequest ----->| Connected
+//!              |<---- Ack + <Manifest> ------|
+//!    Connected |-- DeleteRequest + <Name> -->| Connected
+//!              |<---------- Ack -------------|
+//! ```
+//! The manifest lists every file in the server's directory with its size and checksum, see
+//! [crate::Manifest::write_to]. File names may contain `/` to put files in subdirectories, in a manifest, a delete
+//! request or file info.
+//! Either request may be answered with RequestDenied and a reason instead, e.g. if the server doesn't allow deleting.
+//!
+//! # Delta transfer
+//! A client sending a new version of a file the server already has can send only what has changed, see
+//! [crate::Client::delta]. It asks with DeltaTransferRequest in place of FileTransferRequest, and once the server has
+//! accepted the file info it describes its copy:
+//! ```text
+//!   Client     |                             | Server
+//!  ------------|                             |------------------
+//!  Negotiating |<-- Ack + <Block Signatures> | Negotiating
+//!      Sending |----- <Instructions> ------->| ReceivingDelta
+//!              |<---------- Ack -------------|
+//!    Connected |                             | Connected
+//! ```
+//! The signatures are `[base size: u64][block size: u32]`, then `[weak: u32][CRC-32: u32]` for each block of the
+//! server's copy. A base size of 0 means there is nothing to build on, and the content is streamed in full as usual.
+//!
+//! Otherwise the client sends instructions to rebuild the file, each starting with a tag:
+//! ```text
+//! [1][length: u32][checksum: u32][data: length bytes]   literal content, framed like a chunk
+//! [2][block index: u64][count: u32]                     copy consecutive blocks of the server's copy
+//! [0][checksum: u32]                                    the end, with the CRC-32 of the whole file
+//! ```
+//! If the rebuilt file doesn't match that checksum the server answers RequestDenied and a reason in place of Ack, and
+//! the client streams the content in full as usual.
+//!
+//! # Parallel transfer
+//! A client can send a large file over several connections at once, see [crate::Client::streams]. It asks with
+//! ParallelTransferRequest in place of FileTransferRequest, and once the server has accepted the file info and
+//! created a partial file for it, it answers with an id for the transfer. Each range of the file is then sent over
+//! a connection of its own:
+//! ```text
+//!   Client     |                             | Server
+//!  ------------|                             |------------------
+//!  Negotiating |<--- Ack + <Transfer id> ----| Negotiating
+//!      Sending |                             | Assembling
+//!
+//!    Connected |--- RangeTransferRequest --->| Connected      (on each new connection)
+//!              |<---------- Ack -------------|
+//!              |--- <Id, Offset, Length> --->|
+//!              |<---------- Ack -------------|
+//!              |--- <Stream File Chunks> --->| Receiving
+//!              |<---------- Ack -------------|
+//!    Connected |                             | Connected
+//!
+//!      Sending |--- <End of the content> --->| Assembling     (on the first connection)
+//!              |<---------- Ack -------------|
+//!    Connected |                             | Connected
+//! ```
+//! The id, offset and length are all u64. The chunks of a range end with the CRC-32 of the range, and once every range
+//! has been acknowledged the client sends just the end of the content, with the CRC-32 of the whole file. The server
+//! refuses a range that overlaps another, and only moves the file into place once the ranges cover all of it and their
+//! checksums combined match the checksum of the whole file.
 
 use std::convert::TryFrom;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::bail;
 
+use super::checksum;
+use super::tls::Connection;
+
+/// The maximum number of content bytes sent in a single chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
 /// "Phases" of the protocol, or states for the server to track progress of each connection
 /// The server will match on this to decide how to read incoming data and interpret messages
 #[derive(Debug)]
@@ -36,36 +144,56 @@ pub enum State {
     Connected,
     Negotiating,
     Receiving,
+    ReceivingDelta,
+    /// Waiting for the ranges of a file to arrive over other connections
+    Assembling,
 }
 
 /// Both Client and Server while connected can send and receive protocol messages
 pub(crate) trait ProtocolConnection {
     /// A mutable reference to your connection, used to send and receive protocol messages
-    fn connection(&mut self) -> &mut TcpStream;
+    fn connection(&mut self) -> &mut Connection;
 
     /// Send a protocol message through the connection
     fn send_message(&mut self, message: Message) -> anyhow::Result<()> {
-        self.connection().write(&message.as_bytes())?;
+        self.connection().write_all(&message.as_bytes())?;
         Ok(())
     }
 
     /// Receive a protocol message from the connection
     fn receive_message(&mut self) -> anyhow::Result<Message> {
         let mut buffer = [0; 1];
-        self.connection().read(&mut buffer)?;
+        self.connection().read_exact(&mut buffer)?;
         let message = Message::try_from(buffer[0])?;
         Ok(message)
     }
+
+    /// The address of the other end of the connection, for logging
+    fn peer(&mut self) -> String {
+        match self.connection().peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => String::from("unknown"),
+        }
+    }
 }
 
-trait Client {
-    fn send_filename<T: Into<String>>(&mut self, filename: T) -> anyhow::Result<()>;
+/// A connection on its own, e.g. while it is being set up
+impl ProtocolConnection for Connection {
+    fn connection(&mut self) -> &mut Connection {
+        self
+    }
 }
 
 /// Messages passed between Client and Server
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Message {
     FileTransferRequest,
+    ManifestRequest,
+    DeleteRequest,
+    DeltaTransferRequest,
+    ParallelTransferRequest,
+    RangeTransferRequest,
+    AuthRequest,
     RequestDenied,
     Ack,
     Goodbye,
@@ -77,6 +205,12 @@ impl TryFrom<u8> for Message {
     fn try_from(byte: u8) -> Result<Self, Self::Error> {
         match byte {
             30 => Ok(Message::FileTransferRequest),
+            31 => Ok(Message::ManifestRequest),
+            32 => Ok(Message::DeleteRequest),
+            33 => Ok(Message::DeltaTransferRequest),
+            34 => Ok(Message::ParallelTransferRequest),
+            35 => Ok(Message::RangeTransferRequest),
+            36 => Ok(Message::AuthRequest),
             43 => Ok(Message::RequestDenied),
             200 => Ok(Message::Ack),
             255 => Ok(Message::Goodbye),
@@ -86,12 +220,161 @@ impl TryFrom<u8> for Message {
 }
 
 impl Message {
-    pub fn as_bytes(self) -> [u8; 1] {
+    pub fn as_bytes(&self) -> [u8; 1] {
         match self {
             Message::FileTransferRequest => [30],
+            Message::ManifestRequest => [31],
+            Message::DeleteRequest => [32],
+            Message::DeltaTransferRequest => [33],
+            Message::ParallelTransferRequest => [34],
+            Message::RangeTransferRequest => [35],
+            Message::AuthRequest => [36],
             Message::RequestDenied => [43],
             Message::Ack => [200],
             Message::Goodbye => [255],
         }
     }
 }
+
+/// What the client tells the server about the file it would like to send
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub name: String,
+    /// The number of bytes of content that will be sent
+    pub size: u64,
+    /// When the file was last modified, if the client knows
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits such as `0o755`, if the client has them
+    pub mode: Option<u32>,
+}
+
+impl FileInfo {
+    const HAS_MODIFIED: u8 = 0b01;
+    const HAS_MODE: u8 = 0b10;
+
+    pub(crate) fn write_to(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        // in one write, as the writer may be an unbuffered connection
+        let mut message = Vec::with_capacity(2 + self.name.len() + 25);
+        write_string(&mut message, &self.name)?;
+        message.extend_from_slice(&self.size.to_be_bytes());
+
+        let mut flags = 0;
+        let modified = match self.modified.map(|time| time.duration_since(UNIX_EPOCH)) {
+            Some(Ok(since_epoch)) => {
+                flags |= Self::HAS_MODIFIED;
+                since_epoch
+            }
+            // times before 1970 aren't worth the trouble, we just don't send them
+            _ => Duration::from_secs(0),
+        };
+        if self.mode.is_some() {
+            flags |= Self::HAS_MODE;
+        }
+        message.push(flags);
+        message.extend_from_slice(&modified.as_secs().to_be_bytes());
+        message.extend_from_slice(&modified.subsec_nanos().to_be_bytes());
+        message.extend_from_slice(&self.mode.unwrap_or(0).to_be_bytes());
+        writer.write_all(&message)?;
+        Ok(())
+    }
+
+    pub(crate) fn read_from(reader: &mut impl Read) -> anyhow::Result<FileInfo> {
+        let name = read_string(reader)?;
+        let size = u64::from_be_bytes(read_array(reader)?);
+        let [flags] = read_array(reader)?;
+        let secs = u64::from_be_bytes(read_array(reader)?);
+        let nanos = u32::from_be_bytes(read_array(reader)?);
+        let mode = u32::from_be_bytes(read_array(reader)?);
+        Ok(FileInfo {
+            name,
+            size,
+            modified: if flags & Self::HAS_MODIFIED != 0 {
+                Some(UNIX_EPOCH + Duration::new(secs, nanos))
+            } else {
+                None
+            },
+            mode: if flags & Self::HAS_MODE != 0 {
+                Some(mode)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Write a string prefixed with its length in bytes as a u16
+pub fn write_string(writer: &mut impl Write, string: &str) -> anyhow::Result<()> {
+    if string.len() > u16::MAX as usize {
+        bail!("`{}` is too long to send", string);
+    }
+    // in one write, as the writer may be an unbuffered connection
+    let mut message = Vec::with_capacity(2 + string.len());
+    message.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    message.extend_from_slice(string.as_bytes());
+    writer.write_all(&message)?;
+    Ok(())
+}
+
+/// Read a string prefixed with its length in bytes as a u16
+pub fn read_string(reader: &mut impl Read) -> anyhow::Result<String> {
+    let length = u16::from_be_bytes(read_array(reader)?);
+    let mut buffer = vec![0; length as usize];
+    reader.read_exact(&mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Read a fixed number of bytes, such as a big-endian integer
+pub fn read_array<const N: usize>(reader: &mut impl Read) -> anyhow::Result<[u8; N]> {
+    let mut buffer = [0; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// What comes before the data of a chunk, which the reader reads into a buffer or file of its own
+#[derive(Debug)]
+pub enum ChunkHeader {
+    /// The length of the data that follows, and the checksum the sender calculated for it
+    Data { length: usize, checksum: u32 },
+    /// The end of the content, along with the checksum of the whole file
+    End { checksum: u32 },
+}
+
+impl ChunkHeader {
+    /// The size of a header on the wire: the length and checksum as u32s
+    pub const SIZE: usize = 8;
+
+    pub fn read_from(reader: &mut impl Read) -> anyhow::Result<ChunkHeader> {
+        // in one read, as the reader may be an unbuffered connection
+        let header: [u8; Self::SIZE] = read_array(reader)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let checksum = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        if length == 0 {
+            return Ok(ChunkHeader::End { checksum });
+        }
+        if length > CHUNK_SIZE {
+            bail!(
+                "Chunk of {} bytes exceeds the maximum chunk size of {} bytes",
+                length,
+                CHUNK_SIZE
+            );
+        }
+        Ok(ChunkHeader::Data { length, checksum })
+    }
+}
+
+/// Write a chunk of content, along with its length and checksum
+pub fn write_chunk(writer: &mut impl Write, data: &[u8]) -> anyhow::Result<()> {
+    debug_assert!(!data.is_empty() && data.len() <= CHUNK_SIZE);
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(&checksum::crc32(data).to_be_bytes())?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+/// Write the chunk that ends the content, carrying the checksum of the whole file
+pub fn write_end(writer: &mut impl Write, checksum: u32) -> anyhow::Result<()> {
+    let mut header = [0; ChunkHeader::SIZE];
+    header[4..].copy_from_slice(&checksum.to_be_bytes());
+    writer.write_all(&header)?;
+    Ok(())
+}
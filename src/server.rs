@@ -1,35 +1,161 @@
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use super::access::{AccessRules, Cidr};
+use super::audit::{AuditLog, AuditRecord, Outcome};
+use super::auth;
+use super::checksum::{self, Crc32};
+use super::chunks::ChunkReceiver;
+use super::delta::{self, Instruction, Signatures};
+use super::discovery::{Announcer, Capabilities, DISCOVERY_PORT};
+use super::hooks::{self, Hooks, Received};
+use super::limits::{Limits, Reservation, Reservations};
+use super::manifest::{self, ManifestEntry};
+use super::parallel::{self, ParallelTransfer, Transfers};
 use super::protocol::{self, ProtocolConnection};
+use super::sink::{DirectorySink, Incoming, Sink};
+use super::throttle::RateLimiter;
+use super::time::format_timestamp;
+use super::timeouts::Timeouts;
+use super::tls::{Connection, ServerTls};
 
 use anyhow::{anyhow, bail};
+use log::{debug, error, info, warn};
+
+/// How many connections a server handles at once unless configured otherwise
+const MAX_CONNECTIONS: usize = 256;
+
+/// How many connections over the limit to tell why at once, any more are closed without a word
+const MAX_REFUSALS: usize = 16;
+
+/// How often to tell a client we're still working on their manifest, well within how long they wait for each read
+const KEEP_ALIVE: Duration = Duration::from_millis(250);
 
 /// The server needs to know what port to listen to and what directory to save incoming files to
 /// The server maintains the TcpStream and communicates with the client to acknowledge incoming files
 pub struct ServerBuilder {
     directory: Option<PathBuf>,
+    sink: Option<Arc<dyn Sink>>,
+    timeouts: Timeouts,
+    preserve_metadata: bool,
+    limits: Limits,
+    rate_limit: Option<RateLimiter>,
+    connection_rate_limit: Option<u64>,
+    access: AccessRules,
+    audit_log: Option<AuditLog>,
+    announce: Option<String>,
+    discovery_port: u16,
+    allow_delete: bool,
+    zero_copy: bool,
+    hooks: Hooks,
+    max_connections: usize,
+    secret: Option<String>,
+    tls: Option<ServerTls>,
 }
 
 #[derive(Debug)]
 pub struct Server {
-    connection: Option<TcpStream>,
-    directory: PathBuf,
+    connection: Option<Connection>,
+    /// Where files are kept, for limits, manifests and deletes, unless they only go to a sink of our own
+    directory: Option<PathBuf>,
+    /// Where received files go, shared by every connection
+    sink: Arc<dyn Sink>,
     state: Option<protocol::State>,
-    filename: Option<String>,
+    file_info: Option<protocol::FileInfo>,
+    /// How the client asked to send the file being negotiated
+    mode: Mode,
+    /// What we told the client about our copy of the file being received as a delta
+    signatures: Option<Signatures>,
+    /// The id and partial file of a file whose ranges are arriving over other connections
+    assembling: Option<(u64, Box<dyn Incoming>)>,
+    timeouts: Timeouts,
+    preserve_metadata: bool,
+    limits: Limits,
+    /// The bytes of files still arriving, shared by every connection
+    reservations: Reservations,
+    /// What is set aside for the file being received, given back once it is stored or has failed
+    reservation: Option<Reservation>,
+    /// Shared by every connection
+    rate_limit: Option<RateLimiter>,
+    /// Bytes per second for each connection, each gets its own limiter when it connects
+    connection_rate_limit: Option<u64>,
+    connection_limiter: Option<RateLimiter>,
+    access: AccessRules,
+    audit_log: Option<AuditLog>,
+    /// The name to answer discovery queries with, if the server should be discoverable
+    announce: Option<String>,
+    discovery_port: u16,
+    /// Whether clients syncing a directory may delete files from ours
+    allow_delete: bool,
+    /// Files arriving in ranges, shared by every connection
+    transfers: Transfers,
+    /// Whether to splice received content into files where the platform allows
+    zero_copy: bool,
+    /// What to call once a file is stored
+    hooks: Hooks,
+    /// The most connections to handle at once, each has a thread of its own
+    max_connections: usize,
+    /// The connections being handled, shared by every connection
+    connections: Connections,
+    /// The connections over the limit being refused, shared by every connection
+    refusals: Connections,
+    /// Our place among them, given up when the connection closes
+    slot: Option<Slot>,
+    /// What clients must prove they know before anything else, if anything
+    secret: Option<String>,
+    /// Whether the client on our connection has proved it, so far as it needs to
+    authenticated: bool,
+    /// Our certificate and key, if connections are encrypted
+    tls: Option<ServerTls>,
+}
+
+/// How a client has asked to send a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Whole,
+    Delta,
+    Parallel,
 }
 
 impl ProtocolConnection for Server {
-    fn connection(&mut self) -> &mut TcpStream {
+    fn connection(&mut self) -> &mut Connection {
         self.connection.as_mut().unwrap()
     }
 }
 
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ServerBuilder {
     pub fn new() -> Self {
-        ServerBuilder { directory: None }
+        ServerBuilder {
+            directory: None,
+            sink: None,
+            timeouts: Timeouts::default(),
+            preserve_metadata: false,
+            limits: Limits::default(),
+            rate_limit: None,
+            connection_rate_limit: None,
+            access: AccessRules::default(),
+            audit_log: None,
+            announce: None,
+            discovery_port: DISCOVERY_PORT,
+            allow_delete: false,
+            zero_copy: true,
+            hooks: Hooks::default(),
+            max_connections: MAX_CONNECTIONS,
+            secret: None,
+            tls: None,
+        }
     }
 
     /// Configures a directory to save received files to
@@ -45,17 +171,206 @@ impl ServerBuilder {
         Ok(())
     }
 
+    /// Configures where received files go, instead of the directory, see [Sink]
+    ///
+    /// A directory can still be configured for its size limits, and for clients syncing to list and delete its files.
+    pub fn sink(&mut self, sink: impl Sink + 'static) -> &mut Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Configures how long to wait on clients, and how hard to try saying Goodbye
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Configures whether to apply the modification time and permissions sent by the client to received files
+    ///
+    /// This only applies to the built-in [DirectorySink] used with [ServerBuilder::directory]. A custom
+    /// [ServerBuilder::sink] is handed the metadata in the [crate::FileInfo] of each file, and applies it or not itself.
+    pub fn preserve_metadata(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_metadata = preserve;
+        self
+    }
+
+    /// Configures the largest file, in bytes, that the server will accept
+    pub fn max_file_size(&mut self, bytes: u64) -> &mut Self {
+        self.limits.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Configures the most bytes the server will store in its directory, including files that were already there
+    pub fn max_directory_bytes(&mut self, bytes: u64) -> &mut Self {
+        self.limits.max_directory_bytes = Some(bytes);
+        self
+    }
+
+    /// Configures how many bytes of disk space must remain free after receiving a file
+    pub fn min_free_space(&mut self, bytes: u64) -> &mut Self {
+        self.limits.min_free_space = Some(bytes);
+        self
+    }
+
+    /// Configures the most bytes per second to receive at, across all connections
+    pub fn rate_limit(&mut self, bytes_per_second: u64) -> &mut Self {
+        self.rate_limit = Some(RateLimiter::new(bytes_per_second));
+        self
+    }
+
+    /// Configures the most bytes per second to receive at, for each connection
+    pub fn connection_rate_limit(&mut self, bytes_per_second: u64) -> &mut Self {
+        self.connection_rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Configures the server to accept connections from a network, once any network is allowed all others are refused
+    pub fn allow(&mut self, network: Cidr) -> &mut Self {
+        self.access.allow.push(network);
+        self
+    }
+
+    /// Configures the server to refuse connections from a network, even if it is also allowed
+    pub fn deny(&mut self, network: Cidr) -> &mut Self {
+        self.access.deny.push(network);
+        self
+    }
+
+    /// Configures the server with a set of allow and deny rules, in addition to any already configured
+    pub fn access_rules(&mut self, rules: AccessRules) -> &mut Self {
+        self.access.extend(rules);
+        self
+    }
+
+    /// Configures a file to append a record of every attempt to receive a file to, see [AuditLog]
+    pub fn audit_log<T: Into<PathBuf>>(&mut self, path: T) -> anyhow::Result<()> {
+        self.audit_log = Some(AuditLog::open(path)?);
+        Ok(())
+    }
+
+    /// Configures the server to answer clients looking for servers on the local network, calling itself `name`
+    pub fn announce<T: Into<String>>(&mut self, name: T) -> &mut Self {
+        self.announce = Some(name.into());
+        self
+    }
+
+    /// Configures the UDP port to answer discovery queries on, see [crate::discover]
+    pub fn discovery_port(&mut self, port: u16) -> &mut Self {
+        self.discovery_port = port;
+        self
+    }
+
+    /// Configures whether clients syncing a directory may delete the files they no longer have
+    pub fn allow_delete(&mut self, allow: bool) -> &mut Self {
+        self.allow_delete = allow;
+        self
+    }
+
+    /// Configures whether to move received files from the connection into the file with `splice(2)` where the platform
+    /// allows, which is the default on Linux
+    ///
+    /// Despite the name this isn't zero copy, each chunk is still read back from the file into a buffer to check its
+    /// checksum, but it isn't first copied through a buffer on its way into the file.
+    pub fn zero_copy(&mut self, zero_copy: bool) -> &mut Self {
+        self.zero_copy = zero_copy;
+        self
+    }
+
+    /// Configures the most connections to handle at once, 256 by default
+    ///
+    /// Each connection has a thread of its own, and any more are told they were refused and closed, each on a short-lived
+    /// thread of its own so the next connection isn't kept waiting. Past 16 being refused at once, they are closed without
+    /// being told why.
+    /// A client sending a file in ranges uses one connection for each stream. Peers refused by the allow and deny rules
+    /// are refused before they are counted, so they can't take the place of anyone else.
+    pub fn max_connections(&mut self, connections: usize) -> &mut Self {
+        self.max_connections = connections;
+        self
+    }
+
+    /// Configures a secret clients must prove they know before sending anything, see [crate::Client::secret]
+    ///
+    /// The secret itself never crosses the network, but without [ServerBuilder::tls] everything after it does as it is.
+    pub fn secret<T: Into<String>>(&mut self, secret: T) -> &mut Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    /// Configures the server to only talk TLS, proving who it is with a certificate, see [ServerTls]
+    pub fn tls(&mut self, tls: ServerTls) -> &mut Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Configures a function to call with each file once it has been stored and the client told so
+    ///
+    /// Hooks are called in the order they were configured, on the thread of the connection the file arrived on, so
+    /// one that takes a while holds up the client's next file.
+    pub fn on_receive(&mut self, hook: impl Fn(&Received) + Send + Sync + 'static) -> &mut Self {
+        self.hooks.add(hook);
+        self
+    }
+
+    /// Configures a command for the shell to run for each file once it has been stored, logging how it exits
+    ///
+    /// Commands run one at a time, in the order files were stored, on a thread of their own. Once 256 files are waiting
+    /// for the command, connections storing more wait for it to catch up. Each runs with the file described in
+    /// environment variables:
+    /// * `FSHARE_PATH` where the file was stored, if it went to our directory
+    /// * `FSHARE_FILE` the name the client gave the file
+    /// * `FSHARE_SIZE` its size in bytes
+    /// * `FSHARE_CHECKSUM` the CRC-32 of its content, as 8 hex digits
+    /// * `FSHARE_PEER` the address of the client that sent it
+    /// * `FSHARE_DURATION_MS` how long it took to arrive, in milliseconds
+    /// * `FSHARE_MODIFIED` when the client says it was last modified, as an RFC 3339 timestamp, if it said
+    /// * `FSHARE_MODE` its Unix permission bits in octal, e.g. `755`, if the client sent them
+    pub fn on_receive_command<T: Into<String>>(&mut self, command: T) -> &mut Self {
+        self.hooks.add(hooks::command(command.into()));
+        self
+    }
+
     /// Builds the Server and has it listen to a given address
-    /// Returns a ServerBuildError if a directory hasn't previously been configured
+    /// Returns an error if neither a directory nor a sink has previously been configured
     pub fn build(self) -> anyhow::Result<Server> {
-        if self.directory.is_none() {
-            bail!("Please configure a directory before listening")
-        }
+        let sink: Arc<dyn Sink> = match (self.sink, &self.directory) {
+            (Some(sink), _) => sink,
+            (None, Some(directory)) => {
+                Arc::new(DirectorySink::new(directory).preserve_metadata(self.preserve_metadata))
+            }
+            (None, None) => bail!("Please configure a directory before listening"),
+        };
         Ok(Server {
             connection: None,
-            directory: self.directory.unwrap(),
-            filename: None,
+            directory: self.directory,
+            sink,
+            file_info: None,
+            mode: Mode::Whole,
+            signatures: None,
+            assembling: None,
             state: None,
+            timeouts: self.timeouts,
+            preserve_metadata: self.preserve_metadata,
+            limits: self.limits,
+            reservations: Reservations::default(),
+            reservation: None,
+            rate_limit: self.rate_limit,
+            connection_rate_limit: self.connection_rate_limit,
+            connection_limiter: None,
+            access: self.access,
+            audit_log: self.audit_log,
+            announce: self.announce,
+            discovery_port: self.discovery_port,
+            allow_delete: self.allow_delete,
+            transfers: Transfers::default(),
+            zero_copy: self.zero_copy,
+            hooks: self.hooks,
+            max_connections: self.max_connections,
+            connections: Connections::default(),
+            refusals: Connections::default(),
+            slot: None,
+            secret: self.secret,
+            authenticated: false,
+            tls: self.tls,
         })
     }
 }
@@ -63,125 +378,1062 @@ impl ServerBuilder {
 impl Server {
     pub fn run(&mut self, addr: impl ToSocketAddrs) -> anyhow::Result<()> {
         let listener = TcpListener::bind(addr)?;
+        self.serve(listener)
+    }
+
+    /// Accept connections from a listener that is already bound, e.g. to an address chosen by the OS
+    pub fn serve(&mut self, listener: TcpListener) -> anyhow::Result<()> {
+        if let Some(name) = &self.announce {
+            let capabilities = Capabilities {
+                preserve_metadata: self.preserve_metadata,
+                max_file_size: self.limits.max_file_size,
+            };
+            let address = listener.local_addr()?;
+            // being found is a convenience, the server is still useful to anyone who knows its address
+            match Announcer::bind(self.discovery_port, name, address, capabilities) {
+                Ok(announcer) => {
+                    info!(name = name.as_str(), port = self.discovery_port; "answering discovery queries");
+                    announcer.spawn();
+                }
+                Err(e) => {
+                    warn!(error = format!("{:#}", e); "could not make the server discoverable")
+                }
+            }
+        }
         for stream in listener.incoming() {
             // set timeout
             let stream = stream?;
-            stream
-                .set_read_timeout(Some(std::time::Duration::new(5, 0)))
-                .unwrap();
-            // Connect to the incoming stream
-            self.connection = Some(stream);
-            self.state = Some(protocol::State::Connected);
-            self.progress_protocol()?;
-            println!("Protocol Completed");
+            stream.set_read_timeout(Some(self.timeouts.read))?;
+            stream.set_write_timeout(Some(self.timeouts.write))?;
+            // replies are small and the client waits on each, so send them straight away rather than waiting for more
+            stream.set_nodelay(true)?;
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(e) => {
+                    warn!(error:% = e; "could not get the address of a new connection");
+                    continue;
+                }
+            };
+            let mut connection = self.for_connection(stream);
+            // checked before the connection takes a slot, so a flood of refused peers can't lock out everyone else
+            if let Err(reason) = self.access.check(peer.ip()) {
+                // an encrypted connection can't be told anything until the client has done its part of the handshake,
+                // which mustn't hold up accepting the next connection
+                let encrypted = self.tls.is_some();
+                if !encrypted {
+                    connection.refuse(peer, &reason);
+                }
+                match self.refusals.open(MAX_REFUSALS) {
+                    Some(slot) => {
+                        connection.slot = Some(slot);
+                        thread::spawn(move || {
+                            if encrypted && connection.encrypt(peer) {
+                                connection.refuse(peer, &reason);
+                            }
+                            connection.hang_up();
+                        });
+                    }
+                    None => {
+                        warn!(peer:% = peer; "closing refused connection, too many are already being refused")
+                    }
+                }
+                continue;
+            }
+            // each connection gets a thread of its own, so a slow client doesn't hold up the rest
+            // and the ranges of a file sent over several connections can arrive at once, but only so many
+            match self.connections.open(self.max_connections) {
+                Some(slot) => {
+                    connection.slot = Some(slot);
+                    thread::spawn(move || connection.handle(peer));
+                }
+                // refusing waits for the client to hang up, which mustn't hold up accepting the next connection
+                None => match self.refusals.open(MAX_REFUSALS) {
+                    Some(slot) => {
+                        connection.slot = Some(slot);
+                        let reason = format!(
+                            "the server is already handling the most connections it will, {}",
+                            self.max_connections
+                        );
+                        thread::spawn(move || {
+                            if connection.encrypt(peer) {
+                                connection.refuse(peer, &reason);
+                            }
+                            connection.hang_up();
+                        });
+                    }
+                    None => {
+                        warn!(peer:% = peer; "closing connection, too many are already being refused")
+                    }
+                },
+            }
         }
         Ok(())
     }
+
+    /// A server to handle a single connection, sharing our settings, rate limit, audit log and transfers
+    fn for_connection(&self, connection: TcpStream) -> Server {
+        Server {
+            connection: Some(Connection::plain(connection)),
+            directory: self.directory.clone(),
+            sink: self.sink.clone(),
+            state: None,
+            file_info: None,
+            mode: Mode::Whole,
+            signatures: None,
+            assembling: None,
+            timeouts: self.timeouts,
+            preserve_metadata: self.preserve_metadata,
+            limits: self.limits,
+            reservations: self.reservations.clone(),
+            reservation: None,
+            rate_limit: self.rate_limit.clone(),
+            connection_rate_limit: self.connection_rate_limit,
+            connection_limiter: self.connection_rate_limit.map(RateLimiter::new),
+            access: self.access.clone(),
+            audit_log: self.audit_log.clone(),
+            announce: None,
+            discovery_port: self.discovery_port,
+            allow_delete: self.allow_delete,
+            transfers: self.transfers.clone(),
+            zero_copy: self.zero_copy,
+            hooks: self.hooks.clone(),
+            max_connections: self.max_connections,
+            connections: self.connections.clone(),
+            refusals: self.refusals.clone(),
+            slot: None,
+            secret: self.secret.clone(),
+            authenticated: false,
+            tls: self.tls.clone(),
+        }
+    }
+
+    /// Talk to the client on our connection until it says Goodbye
+    fn handle(mut self, peer: SocketAddr) {
+        if !self.encrypt(peer) {
+            return;
+        }
+        self.state = Some(protocol::State::Connected);
+        debug!(peer:% = peer; "accepted connection");
+        // a misbehaving client should only cost us their connection
+        match self.progress_protocol() {
+            Ok(()) => debug!(peer:% = peer; "connection closed"),
+            Err(e) => warn!(peer:% = peer, error = format!("{:#}", e); "connection closed"),
+        }
+    }
+
+    /// Encrypt our connection if we only talk TLS, returning whether it is ready to talk over
+    fn encrypt(&mut self, peer: SocketAddr) -> bool {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return true,
+        };
+        let connection = self.connection.take().unwrap();
+        match connection.encrypt(tls) {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                true
+            }
+            Err(e) => {
+                warn!(peer:% = peer, error = format!("{:#}", e); "closing connection");
+                false
+            }
+        }
+    }
+
+    /// Tell the client on our connection why we won't talk to them, before hanging up
+    fn refuse(&mut self, peer: SocketAddr, reason: &str) {
+        info!(peer:% = peer, reason = reason; "refusing connection");
+        if let Err(e) = self.deny(reason) {
+            warn!(
+                peer:% = peer,
+                error = format!("{:#}", e);
+                "could not tell the peer they were refused"
+            );
+        }
+    }
+}
+
+/// The number of connections a server is handling, shared by all of them
+#[derive(Debug, Clone, Default)]
+struct Connections {
+    open: Arc<AtomicUsize>,
+}
+
+impl Connections {
+    /// Count another connection, unless there are already `max`
+    fn open(&self, max: usize) -> Option<Slot> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()?;
+        Some(Slot {
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// A connection being handled, no longer counted once dropped
+#[derive(Debug)]
+struct Slot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 impl Server {
-    /// Recursively read data from the stream (self.connection) and act according to internal state
+    /// Read data from the stream (self.connection) and act according to internal state, until the connection closes
     /// The connection will close if/when we receive a Goodbye Message while in a Connected state
     fn progress_protocol(&mut self) -> anyhow::Result<()> {
-        match self.state {
-            Some(protocol::State::Connected) => {
+        // one step at a time rather than recursing, so a client sending any number of files can't exhaust our stack
+        while let Some(state) = self.state.take() {
+            self.state = self.step(state)?;
+        }
+        Ok(())
+    }
+
+    /// Act on what the client sends next in `state`, returning the state to carry on in, None once we've said Goodbye
+    fn step(&mut self, state: protocol::State) -> anyhow::Result<Option<protocol::State>> {
+        match state {
+            protocol::State::Connected => {
+                // whatever file we were receiving has been stored or refused, so its space no longer needs setting aside
+                self.reservation = None;
+                // the client may take a while to decide what to do next, but not forever
+                self.set_read_timeout(self.timeouts.idle)?;
                 let message = self.receive_message()?;
+                self.set_read_timeout(self.timeouts.read)?;
                 self.handle_message(message)
             }
-            Some(protocol::State::Negotiating) => {
-                self.receive_filename()?;
+            protocol::State::Negotiating => {
+                self.receive_file_info()?;
+                let state = match self.refusal()? {
+                    Some(reason) => {
+                        info!(
+                            peer = self.peer(),
+                            file = self.file_info.as_ref().unwrap().name.as_str(),
+                            reason = reason.as_str();
+                            "refusing file"
+                        );
+                        self.audit(
+                            SystemTime::now(),
+                            Duration::from_secs(0),
+                            Outcome::Refused,
+                            Err(reason.clone()),
+                        );
+                        self.deny(&reason)?;
+                        protocol::State::Connected
+                    }
+                    None => self.accept()?,
+                };
+                Ok(Some(state))
+            }
+            protocol::State::Receiving => {
+                let started = SystemTime::now();
+                let timer = Instant::now();
+                let received = self.receive_file();
+                match &received {
+                    Ok(checksum) => self.audit(
+                        started,
+                        timer.elapsed(),
+                        Outcome::Received,
+                        Ok(Some(*checksum)),
+                    ),
+                    Err(e) => self.audit(
+                        started,
+                        timer.elapsed(),
+                        Outcome::Failed,
+                        Err(format!("{:#}", e)),
+                    ),
+                }
+                let checksum = received?;
                 self.send_message(protocol::Message::Ack)?;
-                self.state = Some(protocol::State::Receiving);
-                self.progress_protocol()
+                self.run_hooks(timer.elapsed(), checksum);
+                Ok(Some(protocol::State::Connected))
             }
-            Some(protocol::State::Receiving) => {
-                self.receive_file()?;
+            protocol::State::Assembling => {
+                let started = SystemTime::now();
+                let timer = Instant::now();
+                let assembled = self.assemble();
+                match &assembled {
+                    Ok(checksum) => self.audit(
+                        started,
+                        timer.elapsed(),
+                        Outcome::Received,
+                        Ok(Some(*checksum)),
+                    ),
+                    Err(e) => self.audit(
+                        started,
+                        timer.elapsed(),
+                        Outcome::Failed,
+                        Err(format!("{:#}", e)),
+                    ),
+                }
+                let checksum = assembled?;
                 self.send_message(protocol::Message::Ack)?;
-                self.state = Some(protocol::State::Connected);
-                self.progress_protocol()
+                self.run_hooks(timer.elapsed(), checksum);
+                Ok(Some(protocol::State::Connected))
             }
-            None => {
-                bail!("Server is in Invalid state:\n{:?}", &self);
+            protocol::State::ReceivingDelta => {
+                let started = SystemTime::now();
+                let timer = Instant::now();
+                let received = self.receive_delta();
+                match &received {
+                    Ok(Ok(checksum)) => self.audit(
+                        started,
+                        timer.elapsed(),
+                        Outcome::Received,
+                        Ok(Some(*checksum)),
+                    ),
+                    Ok(Err(reason)) => self.audit(
+                        started,
+                        timer.elapsed(),
+                        Outcome::Failed,
+                        Err(reason.clone()),
+                    ),
+                    Err(e) => self.audit(
+                        started,
+                        timer.elapsed(),
+                        Outcome::Failed,
+                        Err(format!("{:#}", e)),
+                    ),
+                }
+                match received? {
+                    Ok(checksum) => {
+                        self.send_message(protocol::Message::Ack)?;
+                        self.run_hooks(timer.elapsed(), checksum);
+                        Ok(Some(protocol::State::Connected))
+                    }
+                    Err(reason) => {
+                        warn!(
+                            peer = self.peer(),
+                            file = self.file_info.as_ref().unwrap().name.as_str(),
+                            reason = reason.as_str();
+                            "could not rebuild the file from the delta, asking for all of it"
+                        );
+                        self.deny(&reason)?;
+                        Ok(Some(protocol::State::Receiving))
+                    }
+                }
             }
         }
     }
 
-    fn receive_filename(&mut self) -> anyhow::Result<()> {
-        // Currently we auto accept any filename
-        let mut reader = BufReader::new(self.connection.as_mut().unwrap());
-        let received: Vec<u8> = reader.fill_buf()?.to_vec();
-        // dbg!(&received);
-        reader.consume(received.len());
-        self.filename = Some(String::from_utf8(received)?);
-        println!("filename received: {:?}", &self.filename);
+    fn set_read_timeout(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        self.connection().set_read_timeout(Some(timeout))?;
         Ok(())
     }
 
-    fn receive_file(&mut self) -> anyhow::Result<()> {
-        // prepare reader (stream)
-        let mut reader = BufReader::new(self.connection.as_mut().unwrap());
+    fn receive_file_info(&mut self) -> anyhow::Result<()> {
+        let info = protocol::FileInfo::read_from(self.connection())?;
+        debug!(
+            peer = self.peer(),
+            file = info.name.as_str(),
+            bytes = info.size;
+            "received file info"
+        );
+        self.file_info = Some(info);
+        Ok(())
+    }
 
-        // read file size
-        let mut size = [0; 8];
-        reader.read(&mut size)?;
-        let mut size = u64::from_be_bytes(size);
+    /// Where the file being negotiated will be saved, which may be in a subdirectory
+    ///
+    /// This is only where it goes in our directory, if there is one, but checks the name for any sink.
+    fn destination(&self) -> anyhow::Result<PathBuf> {
+        let info = self.file_info.as_ref().unwrap();
+        let directory = self.directory.as_deref().unwrap_or_else(|| Path::new(""));
+        manifest::resolve(directory, &info.name)
+    }
+
+    /// The reason we won't accept the file being negotiated, if there is one
+    fn refusal(&mut self) -> anyhow::Result<Option<String>> {
+        let size = self.file_info.as_ref().unwrap().size;
+        match (self.destination(), &self.directory) {
+            (Ok(destination), Some(directory)) => {
+                match self
+                    .reservations
+                    .reserve(&self.limits, directory, &destination, size)?
+                {
+                    Ok(reservation) => {
+                        self.reservation = Some(reservation);
+                        Ok(None)
+                    }
+                    Err(reason) => Ok(Some(reason)),
+                }
+            }
+            (Ok(_), None) => Ok(self.limits.check_size(size)),
+            (Err(e), _) => Ok(Some(format!("{:#}", e))),
+        }
+    }
+
+    /// Refuse the client's request, telling them why
+    fn deny(&mut self, reason: &str) -> anyhow::Result<()> {
+        self.send_message(protocol::Message::RequestDenied)?;
+        protocol::write_string(self.connection(), reason)
+    }
+
+    /// Close the connection without waiting for Goodbye, giving the client a moment to read what we've sent
+    fn hang_up(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            // closing with unread data would reset the connection, and may discard what we sent before the client reads it
+            // so stop writing, then read (and ignore) whatever the client sends until it hangs up too
+            let _ = connection.shutdown(Shutdown::Write);
+            let _ = connection.set_read_timeout(Some(Duration::from_secs(1)));
+            let _ = io::copy(&mut (&mut connection).take(1024), &mut io::sink());
+        }
+        self.state = None;
+    }
+
+    /// Receive the content of the file and store it, returning its checksum
+    fn receive_file(&mut self) -> anyhow::Result<u32> {
+        // we were told the file size while negotiating, and have already checked it against our limits
+        let size = self.file_info.as_ref().unwrap().size;
 
         // prepare writer (file) so that we can start writing to the file
-        let mut full_path = self.directory.clone();
-        let temp_path = PathBuf::from(self.filename.as_ref().unwrap());
-        let filename = temp_path
-            .file_name()
-            .ok_or(anyhow!("Empty filename received!"))?;
-        full_path.push(filename);
-        println!("saving received file to: {:?}", &full_path);
-        let file = File::create(full_path)?;
-        let mut writer = BufWriter::new(file);
-
-        // read until we have read all of the file according to the size received from client
-        // TODO: Security sanity check on file size?
-        while size > 0 {
-            let received: Vec<u8> = reader.fill_buf()?.to_vec();
-            writer.write_all(&received)?;
-            size -= received.len() as u64;
-            reader.consume(received.len());
+        let full_path = self.destination()?;
+        debug!(
+            peer = self.peer(),
+            path:? = full_path,
+            bytes = size;
+            "receiving file"
+        );
+        // nothing is kept until all of the content has arrived and been checked
+        // and if anything goes wrong the sink discards what it has when it is dropped
+        let mut incoming = self.sink.create(self.file_info.as_ref().unwrap())?;
+        let mut receiver = ChunkReceiver::new(self.connection.as_ref().unwrap(), self.zero_copy);
+
+        // read chunks until the client signals the end of the content, checking each one as it arrives
+        let mut offset: u64 = 0;
+        let mut checksum = Crc32::new();
+        loop {
+            match receiver.header()? {
+                protocol::ChunkHeader::Data {
+                    length,
+                    checksum: expected,
+                } => {
+                    if offset + length as u64 > size {
+                        bail!(
+                            "Received more content than the announced size of {} bytes at offset {}",
+                            size,
+                            offset
+                        );
+                    }
+                    for limiter in self.rate_limit.iter().chain(&self.connection_limiter) {
+                        limiter.take(length);
+                    }
+                    let data = receiver.receive(length, offset, incoming.file())?;
+                    verify_chunk(data, expected, offset)?;
+                    checksum.update(data);
+                    // content that didn't go straight into a file is handed over once it is checked
+                    if incoming.file().is_none() {
+                        incoming.write_all(data)?;
+                    }
+                    offset += length as u64;
+                }
+                protocol::ChunkHeader::End { checksum: expected } => {
+                    if offset != size {
+                        bail!(
+                            "Content ended at offset {} before the announced size of {} bytes",
+                            offset,
+                            size
+                        );
+                    }
+                    if checksum.finish() != expected {
+                        bail!(
+                            "Checksum mismatch for the whole file: expected {:08x}, calculated {:08x}",
+                            expected,
+                            checksum.finish()
+                        );
+                    }
+                    break;
+                }
+            }
+        }
+        incoming.commit()?;
+        info!(
+            peer = self.peer(),
+            file = self.file_info.as_ref().unwrap().name.as_str(),
+            bytes = size;
+            "received file"
+        );
+        Ok(checksum.finish())
+    }
+
+    /// Accept the file being negotiated, returning the state to receive it in
+    fn accept(&mut self) -> anyhow::Result<protocol::State> {
+        match self.mode {
+            Mode::Whole => {
+                self.send_message(protocol::Message::Ack)?;
+                Ok(protocol::State::Receiving)
+            }
+            Mode::Delta => {
+                self.send_message(protocol::Message::Ack)?;
+                self.send_signatures()
+            }
+            Mode::Parallel => match self.prepare_ranges() {
+                Ok(id) => {
+                    self.send_message(protocol::Message::Ack)?;
+                    self.connection().write_all(&id.to_be_bytes())?;
+                    Ok(protocol::State::Assembling)
+                }
+                Err(e) => {
+                    let reason = format!("could not prepare to receive it in ranges: {:#}", e);
+                    info!(peer = self.peer(), reason = reason.as_str(); "refusing file");
+                    self.deny(&reason)?;
+                    Ok(protocol::State::Connected)
+                }
+            },
+        }
+    }
+
+    /// Preallocate the file being negotiated for its ranges to be written to, returning the id of the transfer
+    fn prepare_ranges(&mut self) -> anyhow::Result<u64> {
+        let size = self.file_info.as_ref().unwrap().size;
+        let incoming = self.sink.create(self.file_info.as_ref().unwrap())?;
+        // ranges arrive in any order, so they can only be written to a file
+        let file = match incoming.file() {
+            Some(file) => file.try_clone()?,
+            None => bail!("files are not kept in a file here"),
+        };
+        // only claim the disk up front for files that have been checked against limits on it
+        if self.directory.is_some() && self.limits.limit_disk() {
+            parallel::preallocate(&file, size)?;
+        } else {
+            file.set_len(size)?;
+        }
+        let id = self.transfers.start(ParallelTransfer::new(file, size));
+        debug!(peer = self.peer(), id = id, bytes = size; "waiting for ranges");
+        self.assembling = Some((id, incoming));
+        Ok(id)
+    }
+
+    /// Wait for every range of the file being assembled, and store it once it is all there, returning its checksum
+    fn assemble(&mut self) -> anyhow::Result<u32> {
+        let (id, incoming) = self.assembling.take().unwrap();
+        let transfer = self.transfers.get(id).unwrap();
+        let expected = self.wait_for_ranges(&transfer);
+        // no more ranges are accepted once we've stopped waiting, whether or not they all arrived
+        self.transfers.finish(id);
+        let expected = expected?;
+        let checksum = transfer.checksum().map_err(|reason| anyhow!(reason))?;
+        if checksum != expected {
+            bail!(
+                "Checksum mismatch for the whole file: expected {:08x}, calculated {:08x}",
+                expected,
+                checksum
+            );
+        }
+        incoming.commit()?;
+        info!(
+            peer = self.peer(),
+            file = self.file_info.as_ref().unwrap().name.as_str(),
+            bytes = self.file_info.as_ref().unwrap().size,
+            ranges = transfer.range_count();
+            "received file"
+        );
+        Ok(checksum)
+    }
+
+    /// Wait for the client to say every range has been sent, returning the checksum it gives for the whole file
+    ///
+    /// The ranges may take much longer than a read timeout to arrive, but only as long as one of them is arriving.
+    fn wait_for_ranges(&mut self, transfer: &ParallelTransfer) -> anyhow::Result<u32> {
+        let mut activity = transfer.activity();
+        loop {
+            match self.connection().peek(&mut [0; 1]) {
+                Ok(0) => bail!("The client hung up before sending every range"),
+                Ok(_) => break,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if transfer.activity() == activity {
+                        bail!(
+                            "Nothing has arrived in any range for {:?}",
+                            self.timeouts.read
+                        );
+                    }
+                    activity = transfer.activity();
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
+        match protocol::ChunkHeader::read_from(self.connection())? {
+            protocol::ChunkHeader::End { checksum } => Ok(checksum),
+            protocol::ChunkHeader::Data { .. } => {
+                bail!("Expected the end of the content, received more content")
+            }
+        }
+    }
+
+    /// Receive a range of a file that is arriving over several connections, writing it straight to its place
+    fn receive_range(&mut self) -> anyhow::Result<()> {
+        let id = u64::from_be_bytes(protocol::read_array(self.connection())?);
+        let offset = u64::from_be_bytes(protocol::read_array(self.connection())?);
+        let length = u64::from_be_bytes(protocol::read_array(self.connection())?);
+        let transfer = match self.transfers.get(id) {
+            Some(transfer) => transfer,
+            None => return self.deny("no file is being received with that id"),
+        };
+        if let Err(reason) = transfer.claim(offset, length) {
+            return self.deny(&reason);
+        }
+        self.send_message(protocol::Message::Ack)?;
+
+        let mut receiver = ChunkReceiver::new(self.connection.as_ref().unwrap(), self.zero_copy);
+        let mut position: u64 = 0;
+        let mut checksum = Crc32::new();
+        loop {
+            let header = receiver.header()?;
+            transfer.record(protocol::ChunkHeader::SIZE);
+            match header {
+                protocol::ChunkHeader::Data {
+                    length: chunk_length,
+                    checksum: expected,
+                } => {
+                    if position + chunk_length as u64 > length {
+                        bail!(
+                            "Received more than the {} bytes of the range at offset {}",
+                            length,
+                            offset
+                        );
+                    }
+                    for limiter in self.rate_limit.iter().chain(&self.connection_limiter) {
+                        limiter.take(chunk_length);
+                    }
+                    let data =
+                        receiver.receive(chunk_length, offset + position, Some(transfer.file()))?;
+                    transfer.record(chunk_length);
+                    verify_chunk(data, expected, offset + position)?;
+                    checksum.update(data);
+                    position += chunk_length as u64;
+                }
+                protocol::ChunkHeader::End { checksum: expected } => {
+                    if position != length {
+                        bail!(
+                            "The range at offset {} ended after {} of its {} bytes",
+                            offset,
+                            position,
+                            length
+                        );
+                    }
+                    if checksum.finish() != expected {
+                        bail!(
+                            "Checksum mismatch for the range at offset {}: expected {:08x}, calculated {:08x}",
+                            offset,
+                            expected,
+                            checksum.finish()
+                        );
+                    }
+                    break;
+                }
+            }
+        }
+        transfer.complete(offset, checksum.finish());
+        debug!(peer = self.peer(), id = id, offset = offset, bytes = length; "received range");
+        self.send_message(protocol::Message::Ack)
+    }
+
+    /// Describe our copy of the file being negotiated, returning the state to receive it in
+    ///
+    /// Without a copy to build on, the client is told to send the file in full.
+    fn send_signatures(&mut self) -> anyhow::Result<protocol::State> {
+        let name = self.file_info.as_ref().unwrap().name.clone();
+        let base = self
+            .sink
+            .base(&name)
+            .and_then(|base| Some((base.metadata().ok()?.len(), base)))
+            .filter(|(size, _)| *size > 0);
+        let mut writer = BufWriter::new(self.connection.as_mut().unwrap());
+        let state = match base {
+            Some((size, base)) => {
+                let signatures =
+                    Signatures::write_for(&mut BufReader::new(base), size, &mut writer)?;
+                debug!(
+                    file = name.as_str(),
+                    bytes = size,
+                    blocks = signatures.blocks.len();
+                    "sent signatures of our copy"
+                );
+                self.signatures = Some(signatures);
+                protocol::State::ReceivingDelta
+            }
+            None => {
+                Signatures::write_empty(&mut writer)?;
+                debug!(file = name.as_str(); "no copy to build on, asking for all of the file");
+                protocol::State::Receiving
+            }
+        };
         writer.flush()?;
-        Ok(())
+        Ok(state)
     }
 
-    fn handle_message(&mut self, message: protocol::Message) -> anyhow::Result<()> {
+    /// Rebuild the file from our copy and the instructions the client sends, and store it, returning its checksum
+    ///
+    /// If the rebuilt file isn't what the client has, nothing is stored and the reason is returned instead, so the
+    /// client can send it in full.
+    fn receive_delta(&mut self) -> anyhow::Result<Result<u32, String>> {
+        let size = self.file_info.as_ref().unwrap().size;
+        let signatures = self.signatures.take().unwrap();
+        let full_path = self.destination()?;
+        debug!(
+            peer = self.peer(),
+            path:? = full_path,
+            bytes = size;
+            "receiving changes to file"
+        );
+        // the file being replaced is only read, the new content goes to the sink until it is complete
+        let info = self.file_info.as_ref().unwrap();
+        let mut base = self
+            .sink
+            .base(&info.name)
+            .ok_or_else(|| anyhow!("Our copy of {} has gone", info.name))?;
+        let mut writer = BufWriter::new(self.sink.create(info)?);
+        // read exactly what each instruction says comes next, so nothing after the delta is taken from the connection
+        let mut connection = self.connection.as_ref().unwrap();
+        let mut buffer = vec![0; protocol::CHUNK_SIZE];
+
+        let mut offset: u64 = 0;
+        let mut literal: u64 = 0;
+        let mut checksum = Crc32::new();
+        loop {
+            match Instruction::read_from(&mut connection)? {
+                Instruction::Literal {
+                    length,
+                    checksum: expected,
+                } => {
+                    check_room(offset, length as u64, size)?;
+                    let data = &mut buffer[..length];
+                    connection.read_exact(data)?;
+                    verify_chunk(data, expected, offset)?;
+                    for limiter in self.rate_limit.iter().chain(&self.connection_limiter) {
+                        limiter.take(length);
+                    }
+                    checksum.update(data);
+                    writer.write_all(data)?;
+                    offset += length as u64;
+                    literal += length as u64;
+                }
+                Instruction::Copy { index, count } => {
+                    let (_, length) = signatures.run(index, count)?;
+                    check_room(offset, length, size)?;
+                    offset += delta::copy_blocks(
+                        &mut base,
+                        &signatures,
+                        index,
+                        count,
+                        &mut writer,
+                        &mut checksum,
+                    )?;
+                }
+                Instruction::End { checksum: expected } => {
+                    if offset != size {
+                        return Ok(Err(format!(
+                            "the rebuilt file is {} bytes rather than the announced {} bytes",
+                            offset, size
+                        )));
+                    }
+                    if checksum.finish() != expected {
+                        return Ok(Err(format!(
+                            "checksum mismatch for the rebuilt file: expected {:08x}, calculated {:08x}",
+                            expected,
+                            checksum.finish()
+                        )));
+                    }
+                    break;
+                }
+            }
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.commit()?;
+        info!(
+            peer = self.peer(),
+            file = self.file_info.as_ref().unwrap().name.as_str(),
+            bytes = size,
+            sent = literal;
+            "received changes to file"
+        );
+        Ok(Ok(checksum.finish()))
+    }
+
+    /// Record an attempt to receive or delete a file in the audit log, if there is one
+    /// `result` is the checksum of the received file if there is one, or what went wrong
+    fn audit(
+        &mut self,
+        started: SystemTime,
+        duration: Duration,
+        outcome: Outcome,
+        result: Result<Option<u32>, String>,
+    ) {
+        if self.audit_log.is_none() {
+            return;
+        }
+        let peer = self.peer();
+        let path = self.destination().unwrap_or_default();
+        let info = self.file_info.as_ref().unwrap();
+        let (checksum, error) = match result {
+            Ok(checksum) => (checksum.map(|checksum| format!("{:08x}", checksum)), None),
+            Err(error) => (None, Some(error)),
+        };
+        let record = AuditRecord {
+            time: format_timestamp(started),
+            peer,
+            file: info.name.clone(),
+            path,
+            size: info.size,
+            checksum,
+            duration_ms: duration.as_millis() as u64,
+            outcome,
+            error,
+        };
+        if let Err(e) = self.audit_log.as_ref().unwrap().record(&record) {
+            error!(
+                file = record.file.as_str(),
+                error = format!("{:#}", e);
+                "could not audit an attempt to receive a file"
+            );
+        }
+    }
+
+    /// Tell the hooks about the file that has just been stored
+    fn run_hooks(&mut self, duration: Duration, checksum: u32) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let info = self.file_info.clone().unwrap();
+        let received = Received {
+            path: self.sink.path(&info.name),
+            info,
+            peer: self.peer(),
+            checksum,
+            duration,
+        };
+        self.hooks.run(&received);
+    }
+
+    /// Act on a message from the client, returning the state to carry on in
+    fn handle_message(
+        &mut self,
+        message: protocol::Message,
+    ) -> anyhow::Result<Option<protocol::State>> {
+        if self.secret.is_some() && !self.authenticated && message != protocol::Message::AuthRequest
+        {
+            let peer = self.connection().peer_addr()?;
+            self.refuse(
+                peer,
+                "this server only talks to clients that know its secret",
+            );
+            self.hang_up();
+            return Ok(None);
+        }
         match message {
+            protocol::Message::AuthRequest => {
+                self.send_message(protocol::Message::Ack)?;
+                self.authenticate()
+            }
             protocol::Message::Goodbye => {
                 // This should finish the protocol and now we can continue listening for new connections
-                self.goodbye()
+                self.goodbye()?;
+                Ok(None)
             }
-            protocol::Message::FileTransferRequest => {
+            protocol::Message::FileTransferRequest
+            | protocol::Message::DeltaTransferRequest
+            | protocol::Message::ParallelTransferRequest => {
+                self.mode = match message {
+                    protocol::Message::DeltaTransferRequest => Mode::Delta,
+                    protocol::Message::ParallelTransferRequest => Mode::Parallel,
+                    _ => Mode::Whole,
+                };
                 // Send Ack in reply
                 self.send_message(protocol::Message::Ack)?;
                 // change state to Negotiating
-                self.state = Some(protocol::State::Negotiating);
-                self.progress_protocol()
+                Ok(Some(protocol::State::Negotiating))
+            }
+            protocol::Message::ManifestRequest => {
+                self.send_manifest()?;
+                Ok(Some(protocol::State::Connected))
             }
-            _ => {
+            protocol::Message::DeleteRequest => {
+                self.delete_file()?;
+                Ok(Some(protocol::State::Connected))
+            }
+            protocol::Message::RangeTransferRequest => {
+                self.send_message(protocol::Message::Ack)?;
+                self.receive_range()?;
+                Ok(Some(protocol::State::Connected))
+            }
+            message => {
                 // Unexpected message, error and Goodbye (MVP)
-                eprintln!("UNEXPECTED MESSAGE RECEIVED GOODBYE!");
-                self.goodbye()
+                warn!(
+                    peer = self.peer(),
+                    message:? = message;
+                    "unexpected message, saying Goodbye"
+                );
+                self.goodbye()?;
+                Ok(None)
             }
         }
     }
 
+    /// Challenge the client to prove it knows our secret, hanging up if it doesn't, see [crate::auth]
+    ///
+    /// Without a secret any answer will do, so a client with one can still talk to us.
+    fn authenticate(&mut self) -> anyhow::Result<Option<protocol::State>> {
+        let challenge = auth::challenge()?;
+        self.connection().write_all(&challenge)?;
+        let answer: [u8; auth::CHALLENGE_SIZE] = protocol::read_array(self.connection())?;
+        if let Some(secret) = &self.secret {
+            if !auth::check(secret, &challenge, &answer) {
+                let peer = self.connection().peer_addr()?;
+                self.refuse(peer, "the secret doesn't match the server's");
+                self.hang_up();
+                return Ok(None);
+            }
+        }
+        self.authenticated = true;
+        debug!(peer = self.peer(); "client authenticated");
+        self.send_message(protocol::Message::Ack)?;
+        Ok(Some(protocol::State::Connected))
+    }
+
+    /// List every file in the directory for a client that is syncing, sending each one as soon as it is checksummed
+    fn send_manifest(&mut self) -> anyhow::Result<()> {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => return self.deny("this server does not keep its files in a directory"),
+        };
+        let files = match manifest::walk(directory) {
+            Ok(files) => files,
+            Err(e) => return self.deny(&format!("could not list its files: {:#}", e)),
+        };
+        debug!(peer = self.peer(), files = files.len(); "sending manifest");
+        self.send_message(protocol::Message::Ack)?;
+        let mut writer = BufWriter::new(self.connection.as_mut().unwrap());
+        for file in files {
+            // a large file can take longer to read than the client waits for each read, so let them know we're at it
+            let mut last_sent = Instant::now();
+            let mut kept_alive = Ok(());
+            let checksum = manifest::checksum_with_progress(&file.path, || {
+                if last_sent.elapsed() >= KEEP_ALIVE {
+                    kept_alive = ManifestEntry::write_keep_alive(&mut writer)
+                        .and_then(|()| Ok(writer.flush()?));
+                    last_sent = Instant::now();
+                }
+                // no point reading on once the client has gone
+                match &kept_alive {
+                    Ok(()) => Ok(()),
+                    Err(_) => bail!("the client has gone"),
+                }
+            });
+            kept_alive?;
+            // a file that can't be read is left out, so the client will send it again
+            match checksum {
+                Ok(checksum) => {
+                    let entry = ManifestEntry {
+                        size: file.size,
+                        checksum,
+                    };
+                    entry.write_to(&mut writer, &file.name)?;
+                    writer.flush()?;
+                }
+                Err(e) => warn!(
+                    file = file.name.as_str(),
+                    error = format!("{:#}", e);
+                    "leaving file out of the manifest"
+                ),
+            }
+        }
+        ManifestEntry::write_end(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Delete a file a client that is syncing no longer has, if we allow it
+    fn delete_file(&mut self) -> anyhow::Result<()> {
+        let name = protocol::read_string(self.connection())?;
+        let path = match &self.directory {
+            Some(directory) => manifest::resolve(directory, &name),
+            None => Err(anyhow!(
+                "this server does not keep its files in a directory"
+            )),
+        };
+        let size = path
+            .as_ref()
+            .ok()
+            .and_then(|path| fs::metadata(path).ok())
+            .map_or(0, |metadata| metadata.len());
+        self.file_info = Some(protocol::FileInfo {
+            name,
+            size,
+            modified: None,
+            mode: None,
+        });
+        let deleted = match path {
+            _ if !self.allow_delete => Err("this server does not allow deleting files".to_string()),
+            Ok(path) => self.remove(&path).map_err(|e| format!("{:#}", e)),
+            Err(e) => Err(format!("{:#}", e)),
+        };
+        let file = self.file_info.as_ref().unwrap().name.clone();
+        match deleted {
+            Ok(()) => {
+                info!(peer = self.peer(), file = file.as_str(), bytes = size; "deleted file");
+                self.audit(
+                    SystemTime::now(),
+                    Duration::from_secs(0),
+                    Outcome::Deleted,
+                    Ok(None),
+                );
+                self.send_message(protocol::Message::Ack)
+            }
+            Err(reason) => {
+                info!(peer = self.peer(), file = file.as_str(), reason = reason.as_str(); "refusing to delete file");
+                self.audit(
+                    SystemTime::now(),
+                    Duration::from_secs(0),
+                    Outcome::Refused,
+                    Err(reason.clone()),
+                );
+                self.deny(&reason)
+            }
+        }
+    }
+
+    /// Remove a file, and any of its directories that are left empty, without touching our own directory
+    fn remove(&self, path: &Path) -> anyhow::Result<()> {
+        fs::remove_file(path)?;
+        let mut directory = path.parent();
+        while let Some(parent) = directory {
+            if Some(parent) == self.directory.as_deref() || fs::remove_dir(parent).is_err() {
+                break;
+            }
+            directory = parent.parent();
+        }
+        Ok(())
+    }
+
     fn goodbye(&mut self) -> anyhow::Result<()> {
         // Send a Goodbye in reply
         // close the connection and reset state
         // this function must not be called if connection is not yet initialised
-        let max_attempts = 10;
-        let attempt = 0;
+        let retry = self.timeouts.goodbye;
+        let mut attempt = 0;
         loop {
+            thread::sleep(retry.delay(attempt));
             if let Err(e) = self.send_message(protocol::Message::Goodbye) {
-                eprintln!("Error saying Goodbye: Attempt {}", attempt);
-                if attempt < max_attempts {
-                    eprintln!("Max attempts to say Goodbye reached");
-                    break (Err(e));
+                attempt += 1;
+                debug!(
+                    peer = self.peer(),
+                    attempt = attempt,
+                    error = format!("{:#}", e);
+                    "could not say Goodbye"
+                );
+                if attempt >= retry.attempts {
+                    debug!("out of attempts to say Goodbye");
+                    break Err(e);
                 }
             } else {
                 self.connection
@@ -195,3 +1447,30 @@ impl Server {
         }
     }
 }
+
+/// Check that `length` more bytes at `offset` still fit in the announced size of the file, before writing any of them
+fn check_room(offset: u64, length: u64, size: u64) -> anyhow::Result<()> {
+    if offset + length > size {
+        bail!(
+            "Asked to write {} bytes at offset {}, past the announced size of {} bytes",
+            length,
+            offset,
+            size
+        );
+    }
+    Ok(())
+}
+
+/// Check a chunk of content against the checksum the client sent with it
+fn verify_chunk(data: &[u8], expected: u32, offset: u64) -> anyhow::Result<()> {
+    let actual = checksum::crc32(data);
+    if actual != expected {
+        bail!(
+            "Checksum mismatch in chunk at offset {}: expected {:08x}, calculated {:08x}",
+            offset,
+            expected,
+            actual
+        );
+    }
+    Ok(())
+}
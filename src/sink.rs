@@ -0,0 +1,202 @@
+//! Where a server puts the files it receives
+//!
+//! By default files are stored in the server's directory, see [DirectorySink]. Any other [Sink] can be given to
+//! [crate::ServerBuilder::sink] instead, to keep files in memory, a database or an object store:
+//! ```
+//! use std::collections::HashMap;
+//! use std::io::{self, Write};
+//! use std::sync::{Arc, Mutex};
+//!
+//! use fshare::{FileInfo, Incoming, ServerBuilder, Sink};
+//!
+//! /// Keeps every file it receives in memory, by name
+//! #[derive(Debug, Default, Clone)]
+//! struct Memory {
+//!     files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+//! }
+//!
+//! #[derive(Debug)]
+//! struct Arriving {
+//!     name: String,
+//!     content: Vec<u8>,
+//!     files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+//! }
+//!
+//! impl Sink for Memory {
+//!     fn create(&self, info: &FileInfo) -> anyhow::Result<Box<dyn Incoming>> {
+//!         Ok(Box::new(Arriving {
+//!             name: info.name.clone(),
+//!             // the size is only what the client announced, so don't allocate it up front
+//!             content: Vec::new(),
+//!             files: self.files.clone(),
+//!         }))
+//!     }
+//! }
+//!
+//! impl Write for Arriving {
+//!     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+//!         self.content.write(data)
+//!     }
+//!
+//!     fn flush(&mut self) -> io::Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! impl Incoming for Arriving {
+//!     fn commit(self: Box<Self>) -> anyhow::Result<()> {
+//!         self.files.lock().unwrap().insert(self.name, self.content);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut server = ServerBuilder::new();
+//! server.sink(Memory::default());
+//! let server = server.build()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use super::atomic::AtomicFile;
+use super::manifest;
+use super::protocol::FileInfo;
+
+/// Receives the files a server accepts
+///
+/// A sink is shared by every connection to the server, so it may be asked for several files at once.
+pub trait Sink: fmt::Debug + Send + Sync {
+    /// Start receiving a file
+    ///
+    /// The name in `info` is a relative path that can't reach outside wherever the sink keeps files, and may contain
+    /// `/` to put the file in a subdirectory.
+    fn create(&self, info: &FileInfo) -> anyhow::Result<Box<dyn Incoming>>;
+
+    /// The content already stored under `name`, for a client to only send what has changed since, see
+    /// [crate::Client::delta]
+    ///
+    /// Without one, such as by default, clients send the whole file instead.
+    fn base(&self, _name: &str) -> Option<File> {
+        None
+    }
+
+    /// Where the file called `name` is kept, if it is on disk for others to find, see [crate::Received::path]
+    fn path(&self, _name: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// A file a [Sink] is receiving
+///
+/// The content is written in order, and only once all of it has arrived and been checked is the file committed.
+/// Dropping it without committing it must discard what has been written, e.g. because the transfer failed part way.
+pub trait Incoming: fmt::Debug + Write + Send {
+    /// Keep the file, all of its content has arrived intact
+    fn commit(self: Box<Self>) -> anyhow::Result<()>;
+
+    /// The file the content is going to, if it goes to one
+    ///
+    /// The server then writes to it directly rather than through [Write], e.g. to splice content from the connection
+    /// straight into it. Only files received this way can be sent in ranges, see [crate::Client::streams].
+    fn file(&self) -> Option<&File> {
+        None
+    }
+}
+
+/// The default [Sink], which stores each file under its name in a directory
+///
+/// Nothing appears at a file's path until all of its content has arrived and been checked, see [AtomicFile].
+#[derive(Debug, Clone)]
+pub struct DirectorySink {
+    directory: PathBuf,
+    preserve_metadata: bool,
+}
+
+impl DirectorySink {
+    pub fn new<T: Into<PathBuf>>(directory: T) -> DirectorySink {
+        DirectorySink {
+            directory: directory.into(),
+            preserve_metadata: false,
+        }
+    }
+
+    /// Configures whether to apply the modification time and permissions the client sent to received files
+    pub fn preserve_metadata(mut self, preserve: bool) -> DirectorySink {
+        self.preserve_metadata = preserve;
+        self
+    }
+}
+
+impl Sink for DirectorySink {
+    fn create(&self, info: &FileInfo) -> anyhow::Result<Box<dyn Incoming>> {
+        let path = manifest::resolve(&self.directory, &info.name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Box::new(Stored {
+            file: AtomicFile::create(path)?,
+            metadata: if self.preserve_metadata {
+                Some(info.clone())
+            } else {
+                None
+            },
+        }))
+    }
+
+    fn base(&self, name: &str) -> Option<File> {
+        File::open(self.path(name)?).ok()
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        manifest::resolve(&self.directory, name).ok()
+    }
+}
+
+/// A file arriving in a [DirectorySink]
+#[derive(Debug)]
+struct Stored {
+    file: AtomicFile,
+    /// The file info to apply the metadata of, if we preserve it
+    metadata: Option<FileInfo>,
+}
+
+impl Write for Stored {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.file.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Incoming for Stored {
+    fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        if let Some(info) = &self.metadata {
+            apply_metadata(self.file.file(), info)?;
+        }
+        self.file.commit()?;
+        Ok(())
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(self.file.file())
+    }
+}
+
+/// Apply the modification time and permissions the client sent to a received file
+fn apply_metadata(file: &File, info: &FileInfo) -> anyhow::Result<()> {
+    if let Some(modified) = info.modified {
+        file.set_modified(modified)?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = info.mode {
+        use std::os::unix::fs::PermissionsExt;
+        // never let a client hand us setuid, setgid or sticky files
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    Ok(())
+}
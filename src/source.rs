@@ -0,0 +1,176 @@
+//! What a client sends: a file on disk, bytes in memory, or anything else that can be read
+//!
+//! Files and bytes can be read from anywhere at any time, so they can be sent as deltas, in ranges, or again after a
+//! failed attempt. A reader can only be read once, from start to end, so it is always sent whole over one connection.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::sync::Mutex;
+
+use super::chunks;
+use super::protocol::FileInfo;
+
+pub(crate) enum Source {
+    File(File),
+    Bytes(Vec<u8>),
+    /// Shared by whatever sends it, but only ever read in order
+    Reader(Mutex<Stream>),
+}
+
+pub(crate) struct Stream {
+    reader: Box<dyn Read + Send>,
+    /// The number of bytes the reader was said to have
+    size: u64,
+    /// How far it has been read
+    position: u64,
+}
+
+impl Source {
+    pub fn reader(reader: impl Read + Send + 'static, size: u64) -> Source {
+        Source::Reader(Mutex::new(Stream {
+            reader: Box::new(reader),
+            size,
+            position: 0,
+        }))
+    }
+
+    /// The number of bytes of content
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(match self {
+            Source::File(file) => file.metadata()?.len(),
+            Source::Bytes(bytes) => bytes.len() as u64,
+            Source::Reader(stream) => stream.lock().unwrap().size,
+        })
+    }
+
+    /// Describe the content for the server, under `name`
+    ///
+    /// Only files have a modification time and permissions to send along.
+    pub fn info(&self, name: String) -> io::Result<FileInfo> {
+        if let Source::File(file) = self {
+            let metadata = file.metadata()?;
+            return Ok(FileInfo {
+                name,
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+                mode: mode(&metadata),
+            });
+        }
+        Ok(FileInfo {
+            name,
+            size: self.size()?,
+            modified: None,
+            mode: None,
+        })
+    }
+
+    /// Whether the content can be read from any offset, rather than only once in order
+    pub fn seekable(&self) -> bool {
+        !matches!(self, Source::Reader(_))
+    }
+
+    /// The file the content is in, if it is in one, so it can be sent straight from the page cache
+    pub fn file(&self) -> Option<&File> {
+        match self {
+            Source::File(file) => Some(file),
+            _ => None,
+        }
+    }
+
+    /// Read content from `offset`, returning how many bytes were read, 0 at the end
+    ///
+    /// A reader can only be read from where it was left off.
+    pub fn read_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self {
+            Source::File(file) => chunks::read_at(file, buffer, offset),
+            Source::Bytes(bytes) => {
+                let start = offset.min(bytes.len() as u64) as usize;
+                let read = buffer.len().min(bytes.len() - start);
+                buffer[..read].copy_from_slice(&bytes[start..start + read]);
+                Ok(read)
+            }
+            Source::Reader(stream) => {
+                let mut stream = stream.lock().unwrap();
+                if offset != stream.position {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "a reader can only be sent once, in order: asked for offset {} after reading {} bytes",
+                            offset, stream.position
+                        ),
+                    ));
+                }
+                let read = loop {
+                    match stream.reader.read(buffer) {
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        read => break read?,
+                    }
+                };
+                stream.position += read as u64;
+                Ok(read)
+            }
+        }
+    }
+
+    /// Read the content in order from the start
+    pub fn positioned(&self) -> Positioned<'_> {
+        Positioned {
+            source: self,
+            offset: 0,
+        }
+    }
+
+    /// Make sure the content can be sent again from the start
+    pub fn rewind(&self) -> io::Result<()> {
+        match self {
+            Source::Reader(stream) if stream.lock().unwrap().position > 0 => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a reader can't be read again once it has been sent",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(file) => f.debug_tuple("File").field(file).finish(),
+            Source::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Source::Reader(stream) => match stream.try_lock() {
+                Ok(stream) => write!(
+                    f,
+                    "Reader({} of {} bytes read)",
+                    stream.position, stream.size
+                ),
+                Err(_) => write!(f, "Reader"),
+            },
+        }
+    }
+}
+
+/// Reads a [Source] in order, without moving the cursor of the file it may be in
+pub(crate) struct Positioned<'a> {
+    source: &'a Source,
+    offset: u64,
+}
+
+impl Read for Positioned<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.source.read_at(buffer, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
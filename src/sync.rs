@@ -0,0 +1,110 @@
+//! Mirroring a directory tree onto a server, sending only what has changed
+//!
+//! The client asks for the server's [Manifest] and compares it to the local files. Files the server doesn't have, or
+//! has with a different size or checksum, are sent over another connection one after another. Local files are only
+//! read to checksum them when the server has a file of the same name and size, between the two connections so the
+//! server isn't kept waiting on a large one. A client configured with
+//! [Client::delta] only sends the parts of each changed file that differ from the server's copy.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+
+use super::client::{Client, Denied, Disconnected};
+use super::manifest;
+
+/// What a sync did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    /// Files that were new or had changed, and were sent
+    pub sent: Vec<String>,
+    /// How many files the server already had
+    pub unchanged: usize,
+    /// Files the server refused, e.g. because of its limits
+    pub refused: Vec<String>,
+    /// Files the server had that no longer exist locally, and were deleted
+    pub deleted: Vec<String>,
+}
+
+impl Client<Disconnected> {
+    /// Mirror `directory` onto the server at `address`, and if `delete` is set, delete the server's files that aren't in
+    /// `directory`
+    ///
+    /// Hidden files are neither sent nor deleted.
+    pub fn sync<P: AsRef<Path>>(
+        self,
+        directory: P,
+        address: &str,
+        delete: bool,
+    ) -> anyhow::Result<SyncSummary> {
+        let directory = directory.as_ref();
+        let mut local = manifest::walk(directory)?;
+        local.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut client = self.connect(address)?;
+        let mut remote = client.manifest()?.files;
+        // reading files to checksum them may take longer than the server waits for our next message
+        let client = client.goodbye();
+
+        let mut summary = SyncSummary::default();
+        let mut changed = Vec::new();
+        for file in local {
+            let unchanged = match remote.remove(&file.name) {
+                Some(entry) if entry.size == file.size => {
+                    entry.checksum == manifest::checksum(&file.path)?
+                }
+                _ => false,
+            };
+            if unchanged {
+                debug!(file = file.name.as_str(); "the server already has the file");
+                summary.unchanged += 1;
+            } else {
+                changed.push(file);
+            }
+        }
+
+        let mut client = client.connect(address)?;
+        for file in changed {
+            let path = file.path.to_str().ok_or(anyhow!(
+                "Can't send {:?}, its path isn't valid UTF-8",
+                file.path
+            ))?;
+            client = match client.file_named(path, file.name.as_str())?.negotiate() {
+                Ok(sending) => {
+                    let connected = sending.send()?;
+                    summary.sent.push(file.name);
+                    connected
+                }
+                // one file being too big shouldn't stop the rest from being synced
+                Err(mut refused) if refused.error.as_ref().is_some_and(|e| e.is::<Denied>()) => {
+                    warn!(
+                        file = file.name.as_str(),
+                        error = format!("{:#}", refused.error.take().unwrap());
+                        "the server refused the file"
+                    );
+                    summary.refused.push(file.name);
+                    refused
+                }
+                Err(failed) => return Err(failed.into()),
+            };
+        }
+        if delete {
+            // whatever is left in the server's manifest no longer exists here
+            for name in remote.into_keys() {
+                client.delete(&name)?;
+                summary.deleted.push(name);
+            }
+        }
+        client.goodbye();
+        info!(
+            server = address,
+            sent = summary.sent.len(),
+            unchanged = summary.unchanged,
+            refused = summary.refused.len(),
+            deleted = summary.deleted.len();
+            "synced directory"
+        );
+        Ok(summary)
+    }
+}
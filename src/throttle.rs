@@ -0,0 +1,59 @@
+//! Limiting how fast content is sent and received, so a big transfer doesn't saturate the network
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled at a fixed number of bytes per second
+///
+/// Clones share the same bucket, so one RateLimiter can limit several connections at once.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_second: f64,
+    /// The most tokens that can build up while nothing is being sent
+    capacity: f64,
+    /// May go negative, which is how much a taker has to wait for
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        let bytes_per_second = bytes_per_second.max(1) as f64;
+        // allow bursts of a tenth of a second
+        let capacity = bytes_per_second / 10.0;
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                bytes_per_second,
+                capacity,
+                tokens: capacity,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take `bytes` tokens from the bucket, blocking until they have been earned
+    pub fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens =
+                (bucket.tokens + elapsed * bucket.bytes_per_second).min(bucket.capacity);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / bucket.bytes_per_second)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+        // sleep without holding the lock, anyone taking after us will also wait for the tokens we owe
+        thread::sleep(wait);
+    }
+}
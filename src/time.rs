@@ -0,0 +1,126 @@
+//! Timestamps for people and machines to read, without pulling in a date library
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail};
+
+/// Format a time as an RFC 3339 timestamp in UTC with millisecond precision, e.g. `2021-02-14T09:30:00.000Z`
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let time_of_day = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parse a timestamp written by [format_timestamp], or the start of one, e.g. `2021-02-14` or `2021-02-14T09:30`
+///
+/// Anything left out is taken to be zero. Timestamps are always in UTC, so a `Z` on the end is allowed but no other
+/// timezone is.
+pub fn parse_timestamp(value: &str) -> anyhow::Result<SystemTime> {
+    let invalid = || {
+        anyhow!(
+            "Invalid timestamp `{}`, expected e.g. `2021-02-14`, `2021-02-14T09:30` or `2021-02-14T09:30:00.000Z`",
+            value
+        )
+    };
+    let text = value.strip_suffix('Z').unwrap_or(value);
+    // each field is a fixed number of digits, after the separator that comes before it
+    let fields = [
+        ("", 4),
+        ("-", 2),
+        ("-", 2),
+        ("T", 2),
+        (":", 2),
+        (":", 2),
+        (".", 3),
+    ];
+    let mut numbers = [0u64; 7];
+    let mut rest = text;
+    for (i, (separator, digits)) in fields.iter().enumerate() {
+        // the date must be whole, and minutes must follow hours
+        if rest.is_empty() && (i == 3 || i > 4) {
+            break;
+        }
+        let field = rest.strip_prefix(separator).ok_or_else(invalid)?;
+        // `get` rather than slicing, a character wider than a byte mustn't be cut in half
+        let number = match field.get(..*digits) {
+            Some(number) if number.bytes().all(|b| b.is_ascii_digit()) => number,
+            _ => return Err(invalid()),
+        };
+        numbers[i] = number.parse()?;
+        rest = &field[*digits..];
+    }
+    if !rest.is_empty() {
+        return Err(invalid());
+    }
+    let [year, month, day, hour, minute, second, millis] = numbers;
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year as i64, month as u32) as u64
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        bail!(
+            "Invalid timestamp `{}`, it isn't a real date and time",
+            value
+        );
+    }
+    let days = days_from_civil(year as i64, month as u32, day as u32);
+    if days < 0 {
+        bail!("Invalid timestamp `{}`, it is before 1970", value);
+    }
+    let seconds = days as u64 * 86_400 + hour * 3600 + minute * 60 + second;
+    Ok(UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis))
+}
+
+/// The year, month and day of a number of days since 1970-01-01
+///
+/// This is Howard Hinnant's `civil_from_days`, which treats March as the first month so leap days come last
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The number of days since 1970-01-01 of a year, month and day, the inverse of [civil_from_days]
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
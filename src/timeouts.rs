@@ -0,0 +1,143 @@
+//! How long the client and server wait on the network before giving up, and how hard they try before disconnecting
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+
+/// Timeouts applied to connections made by the client and accepted by the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// How long to wait for a connection to be established
+    pub connect: Duration,
+    /// How long a single read from the connection may block
+    pub read: Duration,
+    /// How long a single write to the connection may block
+    pub write: Duration,
+    /// How long the server keeps a connection open while waiting for the next message
+    pub idle: Duration,
+    /// How many times to try saying Goodbye before disconnecting anyway
+    pub goodbye: Retry,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(5),
+            read: Duration::from_secs(5),
+            write: Duration::from_secs(5),
+            idle: Duration::from_secs(5),
+            goodbye: Retry::default(),
+        }
+    }
+}
+
+/// A number of attempts, with a delay between each that doubles every time (up to `max_backoff`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Retry {
+    /// The delay to wait before making the given attempt, attempts are counted from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        if attempt == 0 {
+            return Duration::from_secs(0);
+        }
+        let factor = 2u32.saturating_pow(attempt - 1);
+        self.backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+/// Parse a duration such as `500ms`, `5s`, `2m` or `1h`, a plain number is taken as seconds
+pub fn parse_duration(value: &str) -> anyhow::Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration: `{}`", value))?;
+    if number == 0 {
+        bail!(
+            "Invalid duration: `{}`, it must be greater than zero",
+            value
+        );
+    }
+    let seconds: u64 = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => bail!(
+            "Invalid duration unit in `{}`, expected one of ms, s, m or h",
+            value
+        ),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or(anyhow!("Duration `{}` is too large", value))
+}
+
+/// How hard to try delivering a file before giving up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// How many attempts to make in total, and how long to wait between them
+    pub retry: Retry,
+    /// Which failures are worth reconnecting and trying again for
+    pub reconnect: Reconnect,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retry: Retry {
+                attempts: 1,
+                backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(60),
+            },
+            reconnect: Reconnect::Always,
+        }
+    }
+}
+
+/// Which failures are worth reconnecting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reconnect {
+    /// Only try again if nothing has been sent yet, i.e. connecting or negotiating failed
+    BeforeSending,
+    /// Try again whenever the connection fails, sending the whole file again from the start
+    Always,
+}
+
+impl FromStr for Reconnect {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "before-sending" => Ok(Reconnect::BeforeSending),
+            "always" => Ok(Reconnect::Always),
+            _ => bail!(
+                "Invalid reconnect policy `{}`, expected `before-sending` or `always`",
+                value
+            ),
+        }
+    }
+}
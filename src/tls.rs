@@ -0,0 +1,340 @@
+//! Encrypting connections with TLS, so files and their names don't cross the network as they are
+//!
+//! A server given a certificate and its private key with [crate::ServerBuilder::tls] only talks TLS, and a client
+//! connects to it with [crate::Client::tls], trusting the certificate of the authority that signed the server's. The
+//! server's certificate must name the host the client connects to, whether that is a name such as `buildbox` or an
+//! address such as `10.0.3.17`.
+//!
+//! Either way the rest of the protocol is the same, over a [Connection] that is encrypted or not. Content can't go
+//! straight between the page cache and an encrypted connection, so it always goes through a buffer, see
+//! [crate::Client::zero_copy].
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{
+    ClientConnection, ConnectionCommon, RootCertStore, ServerConnection, SideData, StreamOwned,
+};
+
+/// The authorities a client trusts to vouch for the servers it connects to with TLS
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    config: Arc<rustls::ClientConfig>,
+}
+
+impl ClientTls {
+    /// Trust the certificates in the PEM file at `path`, e.g. of the authority that signed the server's certificate
+    pub fn trust<P: AsRef<Path>>(path: P) -> anyhow::Result<ClientTls> {
+        let path = path.as_ref();
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(path)
+            .with_context(|| format!("Could not read the certificates in {:?}", path))?
+        {
+            let certificate =
+                certificate.with_context(|| format!("Invalid certificate in {:?}", path))?;
+            roots
+                .add(certificate)
+                .with_context(|| format!("Invalid certificate in {:?}", path))?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("There are no certificates in {:?}", path));
+        }
+        let config = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(ClientTls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// The certificate a server proves who it is with, and its private key
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl ServerTls {
+    /// Load a certificate, followed by any that vouch for it, and its private key from the PEM files at the paths
+    pub fn load<C: AsRef<Path>, K: AsRef<Path>>(
+        certificate: C,
+        key: K,
+    ) -> anyhow::Result<ServerTls> {
+        let (certificate, key) = (certificate.as_ref(), key.as_ref());
+        let chain = CertificateDer::pem_file_iter(certificate)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Could not read the certificates in {:?}", certificate))?;
+        if chain.is_empty() {
+            return Err(anyhow!("There are no certificates in {:?}", certificate));
+        }
+        let key = PrivateKeyDer::from_pem_file(key)
+            .with_context(|| format!("Could not read the private key in {:?}", key))?;
+        let config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .context("The private key doesn't go with the certificate")?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// The same cryptography whichever other crates in the program would choose by default
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// A connection between client and server, which may be encrypted
+///
+/// Like a [TcpStream] it can be read from and written to through a shared reference, so what is sent and received
+/// can be handled separately.
+pub struct Connection {
+    socket: TcpStream,
+    tls: Option<Mutex<Tls>>,
+    /// The host the client connected to, to check the certificates of any more connections to the same server
+    host: Option<String>,
+}
+
+enum Tls {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl Connection {
+    /// A connection that isn't encrypted
+    pub fn plain(socket: TcpStream) -> Connection {
+        Connection {
+            socket,
+            tls: None,
+            host: None,
+        }
+    }
+
+    /// Encrypt a client's connection to `host`, checking that the server's certificate is for it
+    pub(crate) fn client(
+        socket: TcpStream,
+        tls: &ClientTls,
+        host: &str,
+    ) -> anyhow::Result<Connection> {
+        let name = server_name(host)?;
+        let session = ClientConnection::new(tls.config.clone(), name)?;
+        let mut stream = StreamOwned::new(session, socket.try_clone()?);
+        // the handshake would happen on the first read or write anyway, but fails more clearly on its own
+        while stream.conn.is_handshaking() {
+            stream
+                .conn
+                .complete_io(&mut stream.sock)
+                .with_context(|| format!("TLS handshake with {} failed", host))?;
+        }
+        Ok(Connection {
+            socket,
+            tls: Some(Mutex::new(Tls::Client(stream))),
+            host: Some(host.to_string()),
+        })
+    }
+
+    /// Encrypt a connection a server has accepted, proving who we are with our certificate
+    pub(crate) fn encrypt(self, tls: &ServerTls) -> anyhow::Result<Connection> {
+        let socket = self.socket;
+        let session = ServerConnection::new(tls.config.clone())?;
+        let mut stream = StreamOwned::new(session, socket.try_clone()?);
+        while stream.conn.is_handshaking() {
+            stream
+                .conn
+                .complete_io(&mut stream.sock)
+                .context("TLS handshake failed")?;
+        }
+        Ok(Connection {
+            socket,
+            tls: Some(Mutex::new(Tls::Server(stream))),
+            host: None,
+        })
+    }
+
+    /// The connection itself, but only if it isn't encrypted, for content to go straight into or out of it
+    pub(crate) fn unencrypted(&self) -> Option<&TcpStream> {
+        match self.tls {
+            None => Some(&self.socket),
+            Some(_) => None,
+        }
+    }
+
+    /// The host a client connected to with TLS, see [Connection::client]
+    pub(crate) fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.socket.set_nodelay(nodelay)
+    }
+
+    /// Wait for something to read without reading it, returning 0 if the other end has hung up
+    pub fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.tls() {
+            Some(mut tls) => match &mut *tls {
+                Tls::Client(stream) => peek(stream, buffer),
+                Tls::Server(stream) => peek(stream, buffer),
+            },
+            None => self.socket.peek(buffer),
+        }
+    }
+
+    /// Shut down reading, writing or both, telling the other end we're done writing if the connection is encrypted
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            if let Some(mut tls) = self.tls() {
+                match &mut *tls {
+                    Tls::Client(stream) => stream.conn.send_close_notify(),
+                    Tls::Server(stream) => stream.conn.send_close_notify(),
+                }
+                // the other end may already be gone, shutting down anyway is all that's left to do
+                let _ = tls.flush();
+            }
+        }
+        self.socket.shutdown(how)
+    }
+
+    fn tls(&self) -> Option<MutexGuard<'_, Tls>> {
+        // a panic while reading or writing leaves the session in no state to carry on, but the socket is still fine
+        // to shut down, so a poisoned lock is used anyway
+        self.tls
+            .as_ref()
+            .map(|tls| tls.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+/// Wait for something to read from an encrypted connection, see [Connection::peek]
+fn peek<C, S>(stream: &mut StreamOwned<C, TcpStream>, buffer: &mut [u8]) -> io::Result<usize>
+where
+    C: DerefMut<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    loop {
+        match stream.conn.reader().fill_buf() {
+            Ok(data) => {
+                let length = data.len().min(buffer.len());
+                buffer[..length].copy_from_slice(&data[..length]);
+                return Ok(length);
+            }
+            // nothing has arrived yet
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+}
+
+/// The name to check the server's certificate against, which may be an address
+fn server_name(host: &str) -> anyhow::Result<ServerName<'static>> {
+    // `buildbox:8080`, `10.0.3.17:8080` or `[fd00::17]:8080`
+    let name = host.rsplit_once(':').map_or(host, |(name, _port)| name);
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(name.to_string()).map_err(|_| {
+        anyhow!(
+            "`{}` isn't a host name or address TLS can check a certificate for",
+            host
+        )
+    })
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("socket", &self.socket)
+            .field("encrypted", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl Read for Tls {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Tls::Client(stream) => stream.read(buffer),
+            Tls::Server(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Tls {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Tls::Client(stream) => stream.write(data),
+            Tls::Server(stream) => stream.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Tls::Client(stream) => stream.flush(),
+            Tls::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.tls() {
+            Some(mut tls) => tls.read(buffer),
+            None => (&self.socket).read(buffer),
+        }
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.tls() {
+            // written out before returning, as a write to a socket would be
+            Some(mut tls) => tls
+                .write(data)
+                .and_then(|written| tls.flush().map(|()| written)),
+            None => (&self.socket).write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.tls() {
+            Some(mut tls) => tls.flush(),
+            None => (&self.socket).flush(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buffer)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        (&*self).write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+
+use fshare::{Client, Denied, Disconnected};
+
+use common::{send, start_server, test_dir};
+
+fn denial_reason(error: anyhow::Error) -> String {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Denied>())
+        .expect("the server should deny the request")
+        .reason
+        .clone()
+}
+
+#[test]
+fn denied_peer_is_told_why() {
+    let dir = test_dir("access-deny");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.deny("127.0.0.0/8".parse().unwrap());
+    });
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    let reason = denial_reason(send(&file, &address).unwrap_err());
+    assert!(reason.contains("deny 127.0.0.0/8"), "reason: {}", reason);
+    assert!(!dir.join("receive").join("file.txt").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn peer_outside_allowlist_is_refused() {
+    let dir = test_dir("access-allowlist");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.access_rules("allow 10.0.0.0/8\nallow fd00::/8".parse().unwrap());
+    });
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    let reason = denial_reason(send(&file, &address).unwrap_err());
+    assert!(
+        reason.contains("not in any allowed network"),
+        "reason: {}",
+        reason
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn peer_inside_allowlist_is_accepted() {
+    let dir = test_dir("access-allowed");
+    let address = start_server(&dir.join("receive"), |server| {
+        server
+            .allow("127.0.0.1".parse().unwrap())
+            .deny("10.0.0.0/8".parse().unwrap());
+    });
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    send(&file, &address).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("file.txt")).unwrap(),
+        "hello"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn denied_peers_do_not_take_up_connections() {
+    let dir = test_dir("access-connections");
+    let address = start_server(&dir.join("receive"), |server| {
+        server
+            .deny("127.0.0.0/8".parse().unwrap())
+            .max_connections(1);
+    });
+
+    // peers that never send anything or hang up, which the server waits on for a while as it refuses them
+    let mut idle: Vec<_> = (0..3)
+        .map(|_| TcpStream::connect(&address).unwrap())
+        .collect();
+    for connection in &mut idle {
+        // RequestDenied, and a reason prefixed with its length
+        let mut message = [0; 3];
+        connection.read_exact(&mut message).unwrap();
+        assert_eq!(message[0], 43);
+        let mut reason = vec![0; u16::from_be_bytes([message[1], message[2]]) as usize];
+        connection.read_exact(&mut reason).unwrap();
+        let reason = String::from_utf8(reason).unwrap();
+        // each is told it was denied, rather than that the server is busy with the others
+        assert!(reason.contains("deny 127.0.0.0/8"), "reason: {}", reason);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn clients_must_know_the_secret() {
+    let dir = test_dir("access-secret");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.secret("hunter2");
+    });
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+    let send_with = |secret: Option<&str>| {
+        let client = Client::<Disconnected>::new();
+        let client = match secret {
+            Some(secret) => client.secret(secret),
+            None => client,
+        };
+        client.send(address.clone(), file.to_str().unwrap().to_string())
+    };
+
+    let reason = denial_reason(send_with(None).unwrap_err());
+    assert!(reason.contains("know its secret"), "reason: {}", reason);
+    let reason = denial_reason(send_with(Some("hunter3")).unwrap_err());
+    assert!(
+        reason.contains("secret doesn't match"),
+        "reason: {}",
+        reason
+    );
+    assert!(!dir.join("receive").join("file.txt").exists());
+
+    send_with(Some("hunter2")).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("file.txt")).unwrap(),
+        "hello"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
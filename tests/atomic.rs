@@ -0,0 +1,32 @@
+mod common;
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use common::{send, send_half_a_file, start_server, test_dir};
+
+#[test]
+fn failed_transfer_leaves_nothing_behind() {
+    let dir = test_dir("atomic");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |_| {});
+
+    send_half_a_file(&address, "half.bin");
+    // the server notices the connection is gone, then can accept the next one
+    let whole = dir.join("send").join("whole.bin");
+    fs::write(&whole, vec![7; 100_000]).unwrap();
+    send(&whole, &address).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let received: Vec<_> = fs::read_dir(&receive)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(received, vec!["whole.bin"]);
+    assert_eq!(
+        fs::read(receive.join("whole.bin")).unwrap(),
+        vec![7; 100_000]
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
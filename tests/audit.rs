@@ -0,0 +1,103 @@
+mod common;
+
+use std::fs;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use fshare::{format_timestamp, parse_timestamp, AuditLog, Outcome};
+
+use common::{send, send_half_a_file, start_server, test_dir};
+
+#[test]
+fn every_attempt_is_recorded() {
+    let dir = test_dir("audit");
+    let receive = dir.join("receive");
+    let log = dir.join("audit.jsonl");
+    let address = start_server(&receive, |server| {
+        server.audit_log(&log).unwrap();
+        server.max_file_size(1000);
+    });
+
+    let check = dir.join("send").join("check.txt");
+    fs::write(&check, "123456789").unwrap();
+    send(&check, &address).unwrap();
+    let big = dir.join("send").join("big.bin");
+    fs::write(&big, vec![0; 2000]).unwrap();
+    assert!(send(&big, &address).is_err());
+    send_half_a_file(&address, "half.bin");
+    thread::sleep(Duration::from_millis(200));
+
+    let records = AuditLog::read(&log).unwrap();
+    let outcomes: Vec<_> = records
+        .iter()
+        .map(|record| (record.file.as_str(), record.outcome))
+        .collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            ("check.txt", Outcome::Received),
+            ("big.bin", Outcome::Refused),
+            ("half.bin", Outcome::Failed),
+        ]
+    );
+
+    let received = &records[0];
+    assert_eq!(received.path, receive.join("check.txt"));
+    assert_eq!(received.size, 9);
+    // the standard CRC-32 check value
+    assert_eq!(received.checksum.as_deref(), Some("cbf43926"));
+    assert!(received.peer.starts_with("127.0.0.1:"));
+    assert!(received.error.is_none());
+
+    assert!(records[1]
+        .error
+        .as_ref()
+        .unwrap()
+        .contains("maximum file size"));
+    assert!(records[2].checksum.is_none());
+    assert!(records[2].error.is_some());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn timestamps_are_read_back_as_they_are_written() {
+    let time = UNIX_EPOCH + Duration::from_millis(1_613_295_000_123);
+    assert_eq!(format_timestamp(time), "2021-02-14T09:30:00.123Z");
+    assert_eq!(parse_timestamp("2021-02-14T09:30:00.123Z").unwrap(), time);
+    // or the start of one
+    let day = UNIX_EPOCH + Duration::from_secs(1_613_260_800);
+    assert_eq!(parse_timestamp("2021-02-14").unwrap(), day);
+    assert_eq!(
+        parse_timestamp("2021-02-14T09:30").unwrap(),
+        day + Duration::from_secs(9 * 3600 + 30 * 60)
+    );
+    assert_eq!(
+        parse_timestamp("2024-02-29").unwrap(),
+        UNIX_EPOCH + Duration::from_secs(1_709_164_800)
+    );
+
+    for invalid in [
+        "2021-2-14",
+        "21-02-14",
+        "2021-02-14T09",
+        "2021-02-14 09:30",
+        "2021-02-14T09:30+01:00",
+        "2021-02-30",
+        "2023-02-29",
+        "2021-02-14T24:00",
+        "yesterday",
+        "",
+        // characters wider than a byte, in each field
+        "2é21-02-14",
+        "2021-0é-14",
+        "2021-02-1é",
+        "2021-02-14T0é:30",
+        "2021-02-14T09:3é",
+        "2021-02-14T09:30:0é",
+        "2021-02-14T09:30:00.1é",
+        "2021-02-14T09:30:00.12é",
+        "2021-02-14T09:30:00.123é",
+    ] {
+        assert!(parse_timestamp(invalid).is_err(), "{:?}", invalid);
+    }
+}
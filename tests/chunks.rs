@@ -0,0 +1,68 @@
+mod common;
+
+use std::fs;
+
+use fshare::{AuditLog, Outcome};
+
+use common::{content, corrupting_proxy, eventually, send, start_server, test_dir};
+
+/// What a client sends before the content of a file called `name` without metadata: a FileTransferRequest and the
+/// file info
+fn before_content(name: &str) -> u64 {
+    1 + 2 + name.len() as u64 + 25
+}
+
+/// A chunk on the wire: its length and checksum, then its data
+const CHUNK: u64 = 8 + 64 * 1024;
+
+#[test]
+fn files_of_many_chunks_arrive_intact() {
+    let dir = test_dir("chunks");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |_| {});
+    // not a whole number of chunks, so the last is shorter than the rest
+    let data = content(1024 * 1024 + 1, 1);
+    let path = dir.join("send").join("large.bin");
+    fs::write(&path, &data).unwrap();
+
+    send(&path, &address).unwrap();
+    assert_eq!(fs::read(receive.join("large.bin")).unwrap(), data);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupted_chunks_are_caught_where_they_happen() {
+    let dir = test_dir("chunks-corrupted");
+    let receive = dir.join("receive");
+    let log = dir.join("audit.jsonl");
+    let address = start_server(&receive, |server| {
+        server.audit_log(&log).unwrap();
+    });
+    let path = dir.join("send").join("corrupt.bin");
+    fs::write(&path, content(1024 * 1024, 1)).unwrap();
+
+    // a byte of data in the fourth chunk, past its header
+    let position = before_content("corrupt.bin") + 3 * CHUNK + 8 + 1000;
+    let proxy = corrupting_proxy(address, position);
+    let error = send(&path, &proxy).unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("corrupt.bin"),
+        "{:#}",
+        error
+    );
+    assert!(!receive.join("corrupt.bin").exists());
+
+    // the server records the chunk the corruption was found in
+    assert!(eventually(|| AuditLog::read(&log)
+        .map(|records| !records.is_empty())
+        .unwrap_or(false)));
+    let records = AuditLog::read(&log).unwrap();
+    assert_eq!(records[0].outcome, Outcome::Failed);
+    let reason = records[0].error.as_ref().unwrap();
+    assert!(
+        reason.contains(&format!("in chunk at offset {}:", 3 * 64 * 1024)),
+        "{}",
+        reason
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use std::fs;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use fshare::{Client, Disconnected, Timeouts};
+
+use common::{start_server, test_dir};
+
+#[test]
+fn the_fluent_api_chains_from_a_file_to_a_stored_file() -> anyhow::Result<()> {
+    let dir = test_dir("client-fluent");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |_| {});
+    let first = dir.join("send").join("first.txt");
+    fs::write(&first, "first")?;
+    let second = dir.join("send").join("second.txt");
+    fs::write(&second, "second")?;
+
+    let client = Client::<Disconnected>::new()
+        .timeouts(Timeouts {
+            read: Duration::from_secs(2),
+            ..Timeouts::default()
+        })
+        .file(first.to_str().unwrap())?
+        .connect(&address)?
+        .negotiate()?
+        .send()?
+        .file(second.to_str().unwrap())?
+        .negotiate()?
+        .send()?
+        .goodbye();
+    assert!(client.error.is_none());
+    assert_eq!(fs::read_to_string(receive.join("first.txt"))?, "first");
+    assert_eq!(fs::read_to_string(receive.join("second.txt"))?, "second");
+
+    // and a transition that fails hands its error to `?`
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let unanswered = || -> anyhow::Result<()> {
+        Client::<Disconnected>::new()
+            .file(first.to_str().unwrap())?
+            .connect(format!("127.0.0.1:{}", port))?
+            .negotiate()?
+            .send()?
+            .goodbye();
+        Ok(())
+    };
+    let error = unanswered().unwrap_err();
+    assert!(
+        format!("{:#}", error).contains(&format!("127.0.0.1:{}", port)),
+        "{:#}",
+        error
+    );
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
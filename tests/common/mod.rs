@@ -0,0 +1,200 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fshare::{Client, Disconnected, ServerBuilder};
+
+/// A fresh directory for a test to work in, with `send` and `receive` directories inside
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fshare-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("send")).unwrap();
+    fs::create_dir_all(dir.join("receive")).unwrap();
+    dir
+}
+
+/// Start a server receiving into `directory` on a port chosen by the OS, returning its address
+/// `configure` can set any other options on the server before it is built
+pub fn start_server(directory: &Path, configure: impl FnOnce(&mut ServerBuilder)) -> String {
+    let mut server = ServerBuilder::new();
+    server.directory(directory).unwrap();
+    configure(&mut server);
+    let mut server = server.build().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || server.serve(listener));
+    address
+}
+
+/// Content that doesn't repeat, generated from `seed` so a test can make the same content again
+/// Delta blocks of it only match where the content really is the same
+pub fn content(length: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..length)
+        .map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+/// Wait up to 5 seconds for `done` to be true, for what happens on the server after the client has finished
+pub fn eventually(mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// Send a file using the fluent client API
+pub fn send(path: &Path, address: &str) -> anyhow::Result<()> {
+    Client::<Disconnected>::new()
+        .file(path.to_str().unwrap())?
+        .connect(address)?
+        .negotiate()?
+        .send()?
+        .goodbye();
+    Ok(())
+}
+
+/// Speak just enough of the protocol to start sending a file, then hang up part way through the content
+pub fn send_half_a_file(address: &str, name: &str) {
+    let mut connection = TcpStream::connect(address).unwrap();
+    let mut ack = [0; 1];
+    // FileTransferRequest
+    connection.write_all(&[30]).unwrap();
+    connection.read_exact(&mut ack).unwrap();
+    // FileInfo: a 1000 byte file without metadata
+    connection
+        .write_all(&(name.len() as u16).to_be_bytes())
+        .unwrap();
+    connection.write_all(name.as_bytes()).unwrap();
+    connection.write_all(&1000u64.to_be_bytes()).unwrap();
+    connection.write_all(&[0; 17]).unwrap();
+    connection.read_exact(&mut ack).unwrap();
+    // a single chunk of 10 bytes (with a bogus checksum, the server may give up early) and no end
+    connection.write_all(&10u32.to_be_bytes()).unwrap();
+    connection.write_all(&0u32.to_be_bytes()).unwrap();
+    connection.write_all(&[0; 10]).unwrap();
+}
+
+/// A proxy in front of a server, counting what passes through it
+pub struct Proxy {
+    pub address: String,
+    /// Bytes sent to the server
+    pub sent: Arc<AtomicU64>,
+    pub connections: Arc<AtomicU64>,
+}
+
+/// Forward connections to `server`, counting them and the bytes sent to it
+pub fn counting_proxy(server: String) -> Proxy {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy = Proxy {
+        address: listener.local_addr().unwrap().to_string(),
+        sent: Arc::new(AtomicU64::new(0)),
+        connections: Arc::new(AtomicU64::new(0)),
+    };
+    let (sent, connections) = (proxy.sent.clone(), proxy.connections.clone());
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let mut client = client.unwrap();
+            let mut upstream = TcpStream::connect(&server).unwrap();
+            connections.fetch_add(1, Ordering::SeqCst);
+            let (mut client_reader, mut upstream_writer) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            let sent = sent.clone();
+            thread::spawn(move || {
+                let copied = io::copy(&mut client_reader, &mut upstream_writer).unwrap_or(0);
+                sent.fetch_add(copied, Ordering::SeqCst);
+                let _ = upstream_writer.shutdown(Shutdown::Write);
+            });
+            thread::spawn(move || {
+                let _ = io::copy(&mut upstream, &mut client);
+                let _ = client.shutdown(Shutdown::Write);
+            });
+        }
+    });
+    proxy
+}
+
+/// Forward connections to `server`, flipping the bits of the byte at `position` of what each client sends
+pub fn corrupting_proxy(server: String, position: u64) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let mut client = client.unwrap();
+            let mut upstream = TcpStream::connect(&server).unwrap();
+            let (mut client_reader, mut upstream_writer) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || {
+                let mut buffer = vec![0; 64 * 1024];
+                let mut forwarded = 0;
+                loop {
+                    let read = match client_reader.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => read,
+                    };
+                    if (forwarded..forwarded + read as u64).contains(&position) {
+                        buffer[(position - forwarded) as usize] ^= 0xff;
+                    }
+                    forwarded += read as u64;
+                    if upstream_writer.write_all(&buffer[..read]).is_err() {
+                        break;
+                    }
+                }
+                let _ = upstream_writer.shutdown(Shutdown::Write);
+            });
+            thread::spawn(move || {
+                let _ = io::copy(&mut upstream, &mut client);
+                let _ = client.shutdown(Shutdown::Write);
+            });
+        }
+    });
+    address
+}
+
+/// Forward connections to `server`, except that the first is cut off once the client has sent `cut_after` bytes,
+/// returning the proxy's address and the number of connections made through it
+pub fn flaky_proxy(server: String, cut_after: u64) -> (String, Arc<AtomicU64>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicU64::new(0));
+    let counted = connections.clone();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let mut client = client.unwrap();
+            let mut upstream = TcpStream::connect(&server).unwrap();
+            let limit = if counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                cut_after
+            } else {
+                u64::MAX
+            };
+            let (mut client_reader, mut upstream_writer) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || {
+                let _ = io::copy(&mut (&mut client_reader).take(limit), &mut upstream_writer);
+                // cutting both directions off at once, as a failing network would
+                let _ = client_reader.shutdown(Shutdown::Both);
+                let _ = upstream_writer.shutdown(Shutdown::Both);
+            });
+            thread::spawn(move || {
+                let _ = io::copy(&mut upstream, &mut client);
+                let _ = client.shutdown(Shutdown::Write);
+            });
+        }
+    });
+    (address, connections)
+}
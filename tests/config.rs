@@ -0,0 +1,176 @@
+mod common;
+
+use std::fs::{self, File};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use fshare::{ClientConfig, Config, Reconnect};
+
+use common::{eventually, send, test_dir};
+
+const CONFIG: &str = r#"
+[server]
+directory = "/srv/fshare"
+max_file_size = "2G"
+min_free_space = 1048576
+allow = ["10.0.3.0/24"]
+
+[client]
+retries = 3
+limit = "1M"
+
+[remotes]
+nas = "192.168.1.20:8080"
+
+[remotes.buildbox]
+address = "10.0.3.17:8080"
+limit = "10M"
+reconnect = "before-sending"
+"#;
+
+fn load(name: &str, text: &str) -> anyhow::Result<Config> {
+    let dir = test_dir(name);
+    let path = dir.join("config.toml");
+    fs::write(&path, text).unwrap();
+    let config = Config::load(&path);
+    fs::remove_dir_all(dir).unwrap();
+    config
+}
+
+#[test]
+fn sizes_durations_and_networks_are_parsed() {
+    let config = load("config-parsed", CONFIG).unwrap();
+    assert_eq!(config.server.max_file_size, Some(2 << 30));
+    assert_eq!(config.server.min_free_space, Some(1 << 20));
+    assert_eq!(config.server.allow, vec!["10.0.3.0/24".parse().unwrap()]);
+    assert_eq!(config.server.address(), "0.0.0.0:8080");
+}
+
+#[test]
+fn remotes_override_client_defaults_and_flags_override_both() {
+    let config = load("config-remotes", CONFIG).unwrap();
+
+    let buildbox = config.client_for("buildbox");
+    assert_eq!(buildbox.address.as_deref(), Some("10.0.3.17:8080"));
+    assert_eq!(buildbox.limit, Some(10 << 20));
+    assert_eq!(buildbox.retries, Some(3));
+    assert_eq!(buildbox.reconnect, Some(Reconnect::BeforeSending));
+
+    let nas = config.client_for("nas");
+    assert_eq!(nas.address.as_deref(), Some("192.168.1.20:8080"));
+    assert_eq!(nas.limit, Some(1 << 20));
+
+    let elsewhere = config.client_for("10.0.3.99:8080");
+    assert_eq!(elsewhere.address.as_deref(), Some("10.0.3.99:8080"));
+    assert_eq!(elsewhere.limit, Some(1 << 20));
+
+    let flags = ClientConfig {
+        limit: Some(500),
+        retry_backoff: Some(Duration::from_millis(10)),
+        ..ClientConfig::default()
+    };
+    let settings = flags.or(buildbox);
+    assert_eq!(settings.limit, Some(500));
+    assert_eq!(settings.retries, Some(3));
+    assert_eq!(settings.retry_policy().retry.attempts, 4);
+    assert_eq!(
+        settings.retry_policy().retry.backoff,
+        Duration::from_millis(10)
+    );
+}
+
+#[test]
+fn mistakes_are_reported() {
+    let error = load("config-size", "[server]\nmax_file_size = \"lots\"\n").unwrap_err();
+    assert!(format!("{:#}", error).contains("Invalid size"));
+    let error = load("config-field", "[client]\nadress = \"10.0.3.17:8080\"\n").unwrap_err();
+    assert!(format!("{:#}", error).contains("unknown field `adress`"));
+}
+
+#[test]
+fn remotes_are_added_and_removed_without_losing_comments() {
+    let dir = test_dir("config-edit");
+    let path = dir.join("fshare").join("config.toml");
+
+    Config::add_remote(&path, "nas", "192.168.1.20:8080", None, None).unwrap();
+    assert_eq!(
+        Config::load(&path)
+            .unwrap()
+            .client_for("nas")
+            .address
+            .as_deref(),
+        Some("192.168.1.20:8080")
+    );
+
+    fs::write(&path, CONFIG.replace("[client]", "# keep me\n[client]")).unwrap();
+    Config::add_remote(&path, "buildbox", "10.0.3.18:8080", None, None).unwrap();
+    Config::add_remote(&path, "laptop", "10.0.3.40:8080", None, None).unwrap();
+    assert!(Config::remove_remote(&path, "nas").unwrap());
+    assert!(!Config::remove_remote(&path, "nas").unwrap());
+
+    let text = fs::read_to_string(&path).unwrap();
+    assert!(text.contains("# keep me\n[client]"));
+    let config = Config::load(&path).unwrap();
+    assert_eq!(
+        config.remotes.keys().collect::<Vec<_>>(),
+        vec!["buildbox", "laptop"]
+    );
+    let buildbox = config.client_for("buildbox");
+    assert_eq!(buildbox.address.as_deref(), Some("10.0.3.18:8080"));
+    assert_eq!(buildbox.limit, Some(10 << 20));
+
+    // a secret and certificate are added to a remote that was just an address, and kept when its address changes
+    let ca = Path::new("/etc/fshare/ca.pem");
+    Config::add_remote(&path, "laptop", "10.0.3.40:8080", Some("hunter2"), Some(ca)).unwrap();
+    Config::add_remote(&path, "laptop", "10.0.3.41:8080", None, None).unwrap();
+    let laptop = Config::load(&path).unwrap().client_for("laptop");
+    assert_eq!(laptop.address.as_deref(), Some("10.0.3.41:8080"));
+    assert_eq!(laptop.secret.as_deref(), Some("hunter2"));
+    assert_eq!(laptop.tls.as_deref(), Some(ca));
+
+    assert!(Config::add_remote(&path, "nowhere", "10.0.3.40", None, None).is_err());
+    assert!(Config::add_remote(&path, "no where", "10.0.3.40:8080", None, None).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn switches_on_the_command_line_win_over_the_config_file() {
+    let dir = test_dir("config-switch-off");
+    let receive = dir.join("receive");
+    let config = dir.join("config.toml");
+    fs::write(&config, "[server]\npreserve_metadata = true\n").unwrap();
+    // a port that was free a moment ago
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_fshare"))
+        .arg("--config")
+        .arg(&config)
+        .args(["server", "-a", &address, "--no-preserve-metadata"])
+        .arg(&receive)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let file = dir.join("send").join("old.txt");
+    fs::write(&file, "hello").unwrap();
+    let modified = SystemTime::now() - Duration::from_secs(60 * 60 * 24);
+    File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    // the server takes a moment to start listening
+    let sent = eventually(|| send(&file, &address).is_ok());
+    server.kill().unwrap();
+    server.wait().unwrap();
+    assert!(sent);
+    let received = fs::metadata(receive.join("old.txt")).unwrap();
+    assert_ne!(received.modified().unwrap(), modified);
+    fs::remove_dir_all(dir).unwrap();
+}
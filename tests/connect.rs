@@ -0,0 +1,52 @@
+mod common;
+
+use std::fs;
+use std::net::{TcpListener, ToSocketAddrs};
+
+use fshare::{Client, Disconnected};
+
+use common::{start_server, test_dir};
+
+#[test]
+fn hosts_are_resolved_by_name() {
+    let dir = test_dir("connect-localhost");
+    let address = start_server(&dir.join("receive"), |_| {});
+    let port = address.rsplit(':').next().unwrap();
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    // localhost may resolve to ::1 as well, where nobody is listening
+    Client::<Disconnected>::new()
+        .send(
+            format!("localhost:{}", port),
+            file.to_str().unwrap().to_string(),
+        )
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("file.txt")).unwrap(),
+        "hello"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn every_address_tried_is_named_when_none_answer() {
+    // a port nobody is listening on any more
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let host = format!("localhost:{}", port);
+
+    let error = Client::<Disconnected>::new()
+        .connect(&host)
+        .unwrap_err()
+        .error
+        .unwrap();
+    let message = format!("{:#}", error);
+    assert!(message.contains(&host), "{}", message);
+    for address in host.to_socket_addrs().unwrap() {
+        assert!(message.contains(&address.to_string()), "{}", message);
+    }
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+
+use fshare::{AuditLog, Client, Disconnected, Outcome};
+
+use common::{content, counting_proxy, eventually, start_server, test_dir};
+
+#[test]
+fn only_what_has_changed_is_sent() {
+    let dir = test_dir("delta");
+    let old = content(1024 * 1024, 1);
+    fs::write(dir.join("receive").join("disk.img"), &old).unwrap();
+    // content inserted at the start shifts everything after it, which should still be found
+    let mut new = content(100, 2);
+    new.extend_from_slice(&old[..500_000]);
+    new.extend_from_slice(b"a few changed bytes");
+    new.extend_from_slice(&old[500_019..1_000_000]);
+    new.extend_from_slice(&content(3000, 3));
+    let path = dir.join("send").join("disk.img");
+    fs::write(&path, &new).unwrap();
+    let server = start_server(&dir.join("receive"), |_| {});
+    let proxy = counting_proxy(server);
+
+    Client::<Disconnected>::new()
+        .delta(true)
+        .file(path.to_str().unwrap())
+        .unwrap()
+        .connect(&proxy.address)
+        .unwrap()
+        .negotiate()
+        .unwrap()
+        .send()
+        .unwrap()
+        .goodbye();
+    assert_eq!(fs::read(dir.join("receive").join("disk.img")).unwrap(), new);
+    let sent = proxy.sent.load(Ordering::SeqCst);
+    assert!(
+        sent < new.len() as u64 / 20,
+        "sent {} bytes of a {} byte file",
+        sent,
+        new.len()
+    );
+
+    // a file the server doesn't have yet is sent in full
+    let path = dir.join("send").join("new.img");
+    fs::write(&path, &new[..10_000]).unwrap();
+    Client::<Disconnected>::new()
+        .delta(true)
+        .send(proxy.address, path.to_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(
+        fs::read(dir.join("receive").join("new.img")).unwrap(),
+        &new[..10_000]
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn the_server_asks_for_all_of_a_file_it_could_not_rebuild() {
+    let dir = test_dir("delta-mismatch");
+    fs::write(dir.join("receive").join("notes.txt"), "old notes").unwrap();
+    let address = start_server(&dir.join("receive"), |_| {});
+
+    let mut connection = TcpStream::connect(address).unwrap();
+    let mut reply = [0; 1];
+    // DeltaTransferRequest
+    connection.write_all(&[33]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [200]);
+    // FileInfo: an empty file without metadata
+    connection.write_all(&9u16.to_be_bytes()).unwrap();
+    connection.write_all(b"notes.txt").unwrap();
+    connection.write_all(&0u64.to_be_bytes()).unwrap();
+    connection.write_all(&[0; 17]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [200]);
+    // signatures of the 9 byte file, which fits in a single block
+    let mut signatures = [0; 8 + 4 + 8];
+    connection.read_exact(&mut signatures).unwrap();
+    assert_eq!(signatures[..8], 9u64.to_be_bytes());
+
+    // the end of the delta, with the wrong checksum for an empty file
+    connection.write_all(&[0]).unwrap();
+    connection.write_all(&0xDEAD_BEEFu32.to_be_bytes()).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [43], "the server should refuse the delta");
+    let mut length = [0; 2];
+    connection.read_exact(&mut length).unwrap();
+    let mut reason = vec![0; u16::from_be_bytes(length) as usize];
+    connection.read_exact(&mut reason).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("notes.txt")).unwrap(),
+        "old notes"
+    );
+
+    // then accept the content in full: no chunks, and the checksum of no content
+    connection.write_all(&0u32.to_be_bytes()).unwrap();
+    connection.write_all(&0u32.to_be_bytes()).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [200]);
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("notes.txt")).unwrap(),
+        ""
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn copies_past_the_announced_size_are_refused_before_they_are_made() {
+    let dir = test_dir("delta-oversized-copy");
+    let log = dir.join("audit.jsonl");
+    fs::write(dir.join("receive").join("notes.txt"), "old notes").unwrap();
+    let address = start_server(&dir.join("receive"), |server| {
+        server.audit_log(&log).unwrap();
+    });
+
+    let mut connection = TcpStream::connect(address).unwrap();
+    let mut reply = [0; 1];
+    // DeltaTransferRequest
+    connection.write_all(&[33]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    // FileInfo: a 4 byte file without metadata
+    connection.write_all(&9u16.to_be_bytes()).unwrap();
+    connection.write_all(b"notes.txt").unwrap();
+    connection.write_all(&4u64.to_be_bytes()).unwrap();
+    connection.write_all(&[0; 17]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [200]);
+    let mut signatures = [0; 8 + 4 + 8];
+    connection.read_exact(&mut signatures).unwrap();
+
+    // copy the whole 9 byte base into the 4 byte file
+    connection.write_all(&[2]).unwrap();
+    connection.write_all(&0u64.to_be_bytes()).unwrap();
+    connection.write_all(&1u32.to_be_bytes()).unwrap();
+    assert_eq!(connection.read(&mut reply).unwrap_or(0), 0);
+
+    assert!(eventually(|| AuditLog::read(&log)
+        .map(|records| !records.is_empty())
+        .unwrap_or(false)));
+    let records = AuditLog::read(&log).unwrap();
+    assert_eq!(records[0].outcome, Outcome::Failed);
+    let reason = records[0].error.as_ref().unwrap();
+    assert!(
+        reason.contains("Asked to write 9 bytes at offset 0, past the announced size of 4 bytes"),
+        "{}",
+        reason
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("notes.txt")).unwrap(),
+        "old notes"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
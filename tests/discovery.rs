@@ -0,0 +1,49 @@
+mod common;
+
+use std::fs;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use fshare::{discover, find_peer};
+
+use common::{send, start_server, test_dir};
+
+#[test]
+fn servers_are_found_by_name_on_loopback() {
+    let dir = test_dir("discovery");
+    let port = UdpSocket::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = start_server(&dir.join("receive"), |server| {
+        server
+            .announce("buildbox")
+            .discovery_port(port)
+            .max_file_size(1 << 20);
+    });
+    let broadcast = ("127.255.255.255", port);
+
+    // the server starts answering once its thread is running, give it a few tries
+    let peers = (0..10)
+        .map(|_| discover(broadcast, Duration::from_millis(200)).unwrap())
+        .find(|peers| !peers.is_empty())
+        .expect("the server never answered");
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].name, "buildbox");
+    assert_eq!(peers[0].address.to_string(), address);
+    assert_eq!(peers[0].version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(peers[0].capabilities.max_file_size, Some(1 << 20));
+    assert!(!peers[0].capabilities.preserve_metadata);
+
+    assert!(find_peer("laptop", broadcast, Duration::from_millis(200)).is_err());
+    let peer = find_peer("buildbox", broadcast, Duration::from_secs(1)).unwrap();
+    let file = dir.join("send").join("hello.txt");
+    fs::write(&file, "hello").unwrap();
+    send(&file, &peer.address.to_string()).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("hello.txt")).unwrap(),
+        "hello"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::fs;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use fshare::{Client, Disconnected, Received, ServerConfig};
+
+use common::{content, eventually, send, send_half_a_file, start_server, test_dir};
+
+#[test]
+fn hooks_are_told_about_every_file_stored() {
+    let dir = test_dir("hooks");
+    let receive = dir.join("receive");
+    let received: Arc<Mutex<Vec<Received>>> = Arc::default();
+    let told = received.clone();
+    let address = start_server(&receive, move |server| {
+        server.on_receive(move |file| told.lock().unwrap().push(file.clone()));
+    });
+    let small = dir.join("send").join("small.txt");
+    fs::write(&small, "hello hooks").unwrap();
+    let large = dir.join("send").join("large.bin");
+    fs::write(&large, content(16 * 1024 * 1024, 1)).unwrap();
+
+    // one that never arrives
+    send_half_a_file(&address, "half.bin");
+    send(&small, &address).unwrap();
+    // in ranges, then as a delta of what the server now has
+    for (streams, delta) in [(4, false), (1, true)] {
+        Client::<Disconnected>::new()
+            .streams(streams)
+            .delta(delta)
+            .send(address.clone(), large.to_str().unwrap().to_string())
+            .unwrap();
+    }
+
+    // hooks are called once the client has been told the file arrived, so may still be running
+    assert!(eventually(|| received.lock().unwrap().len() >= 3));
+    let received = received.lock().unwrap();
+    let names: Vec<&str> = received
+        .iter()
+        .map(|file| file.info.name.as_str())
+        .collect();
+    assert_eq!(names, ["small.txt", "large.bin", "large.bin"]);
+    assert_eq!(received[0].info.size, 11);
+    assert_eq!(
+        received[0].path.as_ref().unwrap(),
+        &receive.join("small.txt")
+    );
+    assert!(received[0].peer.starts_with("127.0.0.1:"));
+    // the whole file and the one rebuilt from a delta are the same
+    assert_eq!(received[1].checksum, received[2].checksum);
+    assert_eq!(received[1].info.size, 16 * 1024 * 1024);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn commands_run_with_the_file_in_their_environment() {
+    let dir = test_dir("hooks-command");
+    let receive = dir.join("receive");
+    let output = dir.join("output.txt");
+    let config = ServerConfig {
+        directory: Some(receive.clone()),
+        on_receive: Some(format!(
+            r#"printf '%s %s %s %s' "$FSHARE_FILE" "$FSHARE_SIZE" "$FSHARE_CHECKSUM" "$FSHARE_PATH" > {}"#,
+            output.display()
+        )),
+        ..ServerConfig::default()
+    };
+    let mut server = config.builder().unwrap().build().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || server.serve(listener));
+
+    let path = dir.join("send").join("check.txt");
+    fs::write(&path, "123456789").unwrap();
+    send(&path, &address).unwrap();
+
+    // the command runs once the client has been told the file arrived, and may not have finished writing yet
+    let expected = format!(
+        "check.txt 9 cbf43926 {}",
+        receive.join("check.txt").display()
+    );
+    assert!(eventually(
+        || fs::read_to_string(&output).ok() == Some(expected.clone())
+    ));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn commands_run_one_at_a_time() {
+    let dir = test_dir("hooks-one-at-a-time");
+    let receive = dir.join("receive");
+    let output = dir.join("output.txt");
+    let config = ServerConfig {
+        directory: Some(receive.clone()),
+        on_receive: Some(format!(
+            r#"echo "start $FSHARE_FILE" >> {0}; sleep 0.05; echo "end $FSHARE_FILE" >> {0}"#,
+            output.display()
+        )),
+        ..ServerConfig::default()
+    };
+    let mut server = config.builder().unwrap().build().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || server.serve(listener));
+
+    let names: Vec<String> = (0..5).map(|i| format!("{}.txt", i)).collect();
+    for name in &names {
+        let path = dir.join("send").join(name);
+        fs::write(&path, name).unwrap();
+        send(&path, &address).unwrap();
+    }
+
+    // each finishes before the next starts, in the order the files were stored
+    let expected: String = names
+        .iter()
+        .map(|name| format!("start {0}\nend {0}\n", name))
+        .collect();
+    assert!(eventually(
+        || fs::read_to_string(&output).ok() == Some(expected.clone())
+    ));
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,217 @@
+mod common;
+
+use std::fs;
+use std::io;
+
+use fshare::{Client, Denied, Disconnected};
+
+use common::{eventually, send, start_server, test_dir};
+
+#[test]
+fn oversized_file_is_refused_before_any_data_is_written() {
+    let dir = test_dir("limits");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |server| {
+        server.max_file_size(1000);
+    });
+
+    let small = dir.join("send").join("small.bin");
+    fs::write(&small, vec![1; 1000]).unwrap();
+    send(&small, &address).unwrap();
+
+    let large = dir.join("send").join("large.bin");
+    fs::write(&large, vec![1; 1001]).unwrap();
+    let error = send(&large, &address).unwrap_err();
+    let denied = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Denied>())
+        .expect("the server should deny the request");
+    assert!(
+        denied.reason.contains("maximum file size"),
+        "unexpected reason: {}",
+        denied.reason
+    );
+
+    let received: Vec<_> = fs::read_dir(&receive)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(received, vec!["small.bin"]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_that_would_leave_too_little_free_space_are_refused() {
+    let dir = test_dir("limits-free-space");
+    let receive = dir.join("receive");
+    // more than any disk the tests run on has free
+    let address = start_server(&receive, |server| {
+        server.min_free_space(1 << 60);
+    });
+    let path = dir.join("send").join("file.bin");
+    fs::write(&path, vec![1; 10]).unwrap();
+
+    let error = send(&path, &address).unwrap_err();
+    let denied = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Denied>())
+        .expect("the server should deny the request");
+    assert!(
+        denied.reason.contains("must be kept free"),
+        "unexpected reason: {}",
+        denied.reason
+    );
+    assert!(!receive.join("file.bin").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sizes_near_the_largest_possible_are_refused() {
+    let dir = test_dir("limits-huge");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |server| {
+        server.max_directory_bytes(1000);
+    });
+    // so the announced size overflows when added to what is already there
+    fs::write(receive.join("stored.bin"), vec![1; 10]).unwrap();
+    let huge = || {
+        Client::<Disconnected>::new()
+            .connect(&address)
+            .unwrap()
+            .reader("huge.bin", io::empty(), u64::MAX - 5)
+            .negotiate()
+            .map_err(|client| client.error.unwrap())
+            .err()
+            .unwrap()
+    };
+
+    // twice, as the first mustn't have broken the server for the next
+    for _ in 0..2 {
+        let error = huge();
+        let denied = error.downcast_ref::<Denied>().unwrap();
+        assert!(
+            denied.reason.contains("allowed in the directory"),
+            "unexpected reason: {}",
+            denied.reason
+        );
+    }
+    let small = dir.join("send").join("small.bin");
+    fs::write(&small, vec![1; 10]).unwrap();
+    send(&small, &address).unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_arriving_at_once_share_the_directory_limit() {
+    let dir = test_dir("limits-concurrent");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |server| {
+        server.max_directory_bytes(1000);
+    });
+    let path = dir.join("send").join("content.bin");
+    fs::write(&path, vec![1; 600]).unwrap();
+    let accepted = |name: &str| {
+        Client::<Disconnected>::new()
+            .connect(&address)
+            .unwrap()
+            .file_named(path.to_str().unwrap(), name)
+            .unwrap()
+            .negotiate()
+            .map_err(|client| client.error.unwrap())
+    };
+
+    // each fits on its own, but not while the other is still arriving
+    let first = accepted("first.bin").unwrap();
+    let error = accepted("second.bin").err().unwrap();
+    let denied = error.downcast_ref::<Denied>().unwrap();
+    assert!(
+        denied.reason.contains("allowed in the directory"),
+        "unexpected reason: {}",
+        denied.reason
+    );
+
+    // a file that never arrives gives its space back
+    first.abort();
+    let mut second = None;
+    assert!(eventually(|| {
+        second = accepted("second.bin").ok();
+        second.is_some()
+    }));
+    // finish sending it, so the server isn't still clearing up its partial file as the directory is removed
+    let second = second
+        .unwrap()
+        .send()
+        .map_err(|client| client.error.unwrap())
+        .unwrap();
+    second.goodbye();
+    assert_eq!(fs::read(receive.join("second.bin")).unwrap(), vec![1; 600]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reservations_that_would_overflow_are_refused() {
+    let dir = test_dir("limits-reserve-huge");
+    let receive = dir.join("receive");
+    // without limits on the directory, every file is still set aside as it arrives
+    let address = start_server(&receive, |_| {});
+    let huge = || {
+        Client::<Disconnected>::new()
+            .connect(&address)
+            .unwrap()
+            .reader("huge.bin", io::empty(), u64::MAX - 5)
+            .negotiate()
+            .map_err(|client| client.error.unwrap())
+    };
+
+    let first = huge().unwrap();
+    let error = huge().err().unwrap();
+    let denied = error.downcast_ref::<Denied>().unwrap();
+    assert!(
+        denied.reason.contains("set aside"),
+        "unexpected reason: {}",
+        denied.reason
+    );
+
+    // the reservations are still usable by the files after
+    first.abort();
+    let small = dir.join("send").join("small.bin");
+    fs::write(&small, vec![1; 10]).unwrap();
+    send(&small, &address).unwrap();
+    // wait for the server to clear up the partial file of the one that never arrived
+    assert!(eventually(|| fs::read_dir(&receive).unwrap().count() == 1));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn names_kept_for_partial_files_are_refused() {
+    let dir = test_dir("limits-partial-names");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |server| {
+        server.max_directory_bytes(1000);
+    });
+    fs::write(receive.join("full.bin"), vec![1; 1000]).unwrap();
+    let path = dir.join("send").join("content.bin");
+    fs::write(&path, vec![1; 10]).unwrap();
+
+    // the directory is full, and files still being received don't count towards it, so these mustn't pass for one
+    for name in &["foo.fshare-partial", "dir.fshare-partial/foo"] {
+        let error = Client::<Disconnected>::new()
+            .connect(&address)
+            .unwrap()
+            .file_named(path.to_str().unwrap(), *name)
+            .unwrap()
+            .negotiate()
+            .map_err(|client| client.error.unwrap())
+            .err()
+            .unwrap();
+        let denied = error.downcast_ref::<Denied>().unwrap();
+        assert!(
+            denied.reason.contains(".fshare-partial"),
+            "unexpected reason: {}",
+            denied.reason
+        );
+    }
+    assert!(!receive.join("foo.fshare-partial").exists());
+    assert!(!receive.join("dir.fshare-partial").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
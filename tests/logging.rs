@@ -0,0 +1,99 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use common::{start_server, test_dir};
+
+/// Send `file` to `address` with the fshare binary, returning the lines it logged
+fn logged(dir: &Path, flags: &[&str], address: &str, file: &Path) -> Vec<String> {
+    // an empty config file, so whatever the user running the tests has configured doesn't get in the way
+    let config = dir.join("config.toml");
+    fs::write(&config, "").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_fshare"))
+        .args(flags)
+        .arg("--config")
+        .arg(&config)
+        .args(["client", "-a", address])
+        .arg(file)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn records_are_logged_as_text_at_the_level_asked_for() {
+    let dir = test_dir("logging-text");
+    let address = start_server(&dir.join("receive"), |_| {});
+    let file = dir.join("send").join("my file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    let lines = logged(&dir, &[], &address, &file);
+    let acknowledged = lines
+        .iter()
+        .find(|line| line.contains("server acknowledged receipt of the file"))
+        .unwrap();
+    // the time, the level padded to line up, the message, then each key and value, quoted if it has spaces
+    let (time, rest) = acknowledged.split_once(' ').unwrap();
+    assert!(time.ends_with('Z'), "{}", acknowledged);
+    assert!(
+        rest.starts_with("INFO  server acknowledged receipt of the file "),
+        "{}",
+        acknowledged
+    );
+    assert!(
+        rest.ends_with(&format!(" server={} file=\"my file.txt\" bytes=5", address)),
+        "{}",
+        acknowledged
+    );
+    assert!(
+        !lines.iter().any(|line| line.contains(" DEBUG ")),
+        "{:#?}",
+        lines
+    );
+
+    // -v adds debug records, -q leaves out everything below warnings
+    let lines = logged(&dir, &["-v"], &address, &file);
+    assert!(
+        lines.iter().any(|line| line.contains(" DEBUG ")),
+        "{:#?}",
+        lines
+    );
+    let lines = logged(&dir, &["-q"], &address, &file);
+    assert!(lines.is_empty(), "{:#?}", lines);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn records_are_logged_as_json_lines() {
+    let dir = test_dir("logging-json");
+    let address = start_server(&dir.join("receive"), |_| {});
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    let lines = logged(&dir, &["--log-json"], &address, &file);
+    let records: Vec<serde_json::Value> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let acknowledged = records
+        .iter()
+        .find(|record| record["message"] == "server acknowledged receipt of the file")
+        .unwrap();
+    assert_eq!(acknowledged["level"], "INFO");
+    assert!(acknowledged["target"]
+        .as_str()
+        .unwrap()
+        .starts_with("fshare"));
+    assert_eq!(acknowledged["server"], address.as_str());
+    assert_eq!(acknowledged["file"], "file.txt");
+    // numbers stay numbers
+    assert_eq!(acknowledged["bytes"], 5);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,53 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use common::{send, start_server, test_dir};
+
+fn write_script(path: &Path) -> SystemTime {
+    let mut script = File::create(path).unwrap();
+    script.write_all(b"#!/bin/sh\necho hello\n").unwrap();
+    script
+        .set_permissions(fs::Permissions::from_mode(0o755))
+        .unwrap();
+    let modified = SystemTime::now() - Duration::from_secs(60 * 60 * 24);
+    script.set_modified(modified).unwrap();
+    modified
+}
+
+#[test]
+fn executable_script_arrives_executable() {
+    let dir = test_dir("executable");
+    let script = dir.join("send").join("hello.sh");
+    let modified = write_script(&script);
+
+    let address = start_server(&dir.join("receive"), |server| {
+        server.preserve_metadata(true);
+    });
+    send(&script, &address).unwrap();
+
+    let received = fs::metadata(dir.join("receive").join("hello.sh")).unwrap();
+    assert_eq!(received.permissions().mode() & 0o777, 0o755);
+    assert_eq!(received.modified().unwrap(), modified);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn metadata_is_only_applied_when_asked() {
+    let dir = test_dir("no-metadata");
+    let script = dir.join("send").join("hello.sh");
+    let modified = write_script(&script);
+
+    let address = start_server(&dir.join("receive"), |_| {});
+    send(&script, &address).unwrap();
+
+    let received = fs::metadata(dir.join("receive").join("hello.sh")).unwrap();
+    assert_ne!(received.modified().unwrap(), modified);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use std::fs::{self, File};
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use fshare::{Outbox, Retry};
+
+use common::{send, start_server, test_dir};
+
+#[test]
+fn each_file_is_sent_once_and_failures_are_retried() {
+    let dir = test_dir("outbox");
+    let outbox = dir.join("send");
+    let address = start_server(&dir.join("receive"), |_| {});
+    fs::write(outbox.join("a.txt"), "a").unwrap();
+    fs::write(outbox.join("b.txt"), "b").unwrap();
+
+    let retry_at_once = Retry {
+        attempts: u32::MAX,
+        backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+    let mut watched = Outbox::open(&outbox)
+        .unwrap()
+        .settle(Duration::ZERO)
+        .retry(retry_at_once);
+    assert_eq!(watched.poll(|path| send(path, &address)).unwrap(), 2);
+    assert_eq!(watched.poll(|path| send(path, &address)).unwrap(), 0);
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("b.txt")).unwrap(),
+        "b"
+    );
+
+    fs::write(outbox.join("c.txt"), "c").unwrap();
+    let failing = |_: &_| Err(anyhow!("the server is down"));
+    assert_eq!(watched.poll(failing).unwrap(), 0);
+    let mut tried = Vec::new();
+    let count = watched
+        .poll(|path| {
+            tried.push(path.file_name().unwrap().to_owned());
+            send(path, &address)
+        })
+        .unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(tried, vec!["c.txt"]);
+
+    // what was sent is remembered after a restart, but a changed file is sent again
+    drop(watched);
+    let mut watched = Outbox::open(&outbox).unwrap().settle(Duration::ZERO);
+    assert_eq!(watched.poll(|path| send(path, &address)).unwrap(), 0);
+    fs::write(outbox.join("a.txt"), "changed").unwrap();
+    File::options()
+        .write(true)
+        .open(outbox.join("a.txt"))
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(watched.poll(|path| send(path, &address)).unwrap(), 1);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_still_being_written_wait() {
+    let dir = test_dir("outbox-settle");
+    let outbox = dir.join("send");
+    fs::write(outbox.join("growing.log"), "still writing").unwrap();
+
+    let mut watched = Outbox::open(&outbox)
+        .unwrap()
+        .settle(Duration::from_secs(60 * 60));
+    let count = watched
+        .poll(|path| panic!("{:?} was sent before it settled", path))
+        .unwrap();
+    assert_eq!(count, 0);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn a_half_written_record_is_repaired() {
+    let dir = test_dir("outbox-torn");
+    let outbox = dir.join("send");
+    let address = start_server(&dir.join("receive"), |_| {});
+    fs::write(outbox.join("a.txt"), "a").unwrap();
+    fs::write(outbox.join("b.txt"), "b").unwrap();
+    let mut watched = Outbox::open(&outbox).unwrap().settle(Duration::ZERO);
+    assert_eq!(watched.poll(|path| send(path, &address)).unwrap(), 2);
+    drop(watched);
+
+    // as if we crashed part way through recording that c.txt was sent
+    fs::write(outbox.join("c.txt"), "c").unwrap();
+    let record = outbox.join(".fshare-sent");
+    let mut text = fs::read_to_string(&record).unwrap();
+    text.push_str("{\"file\":\"c.t");
+    fs::write(&record, text).unwrap();
+
+    let mut watched = Outbox::open(&outbox).unwrap().settle(Duration::ZERO);
+    let mut tried = Vec::new();
+    let count = watched
+        .poll(|path| {
+            tried.push(path.file_name().unwrap().to_owned());
+            send(path, &address)
+        })
+        .unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(tried, vec!["c.txt"]);
+
+    // and what is recorded after the repair is read back
+    drop(watched);
+    let mut watched = Outbox::open(&outbox).unwrap().settle(Duration::ZERO);
+    assert_eq!(watched.poll(|path| send(path, &address)).unwrap(), 0);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fshare::{AuditLog, Client, Denied, Disconnected, Manifest};
+
+use common::{content, counting_proxy, eventually, send, start_server, test_dir};
+
+#[test]
+fn large_files_are_sent_in_ranges_over_several_connections() {
+    let dir = test_dir("parallel");
+    let audit_log = dir.join("audit.jsonl");
+    let server = start_server(&dir.join("receive"), |server| {
+        server.audit_log(&audit_log).unwrap();
+    });
+    let proxy = counting_proxy(server);
+    let data = content(5 * 1024 * 1024 + 123, 1);
+    let path = dir.join("send").join("disk.img");
+    fs::write(&path, &data).unwrap();
+
+    Client::<Disconnected>::new()
+        .streams(4)
+        .send(proxy.address.clone(), path.to_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(
+        fs::read(dir.join("receive").join("disk.img")).unwrap(),
+        data
+    );
+    // the first connection negotiates, and each of the four ranges gets another
+    assert_eq!(proxy.connections.load(Ordering::SeqCst), 5);
+
+    // the checksum the server put together from the ranges is the checksum of the whole file
+    let records = AuditLog::read(&audit_log).unwrap();
+    let manifest = Manifest::of_directory(&dir.join("receive")).unwrap();
+    assert_eq!(
+        records[0].checksum,
+        Some(format!("{:08x}", manifest.files["disk.img"].checksum))
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Ask the server to receive a file in ranges, returning the connection it was negotiated on and the transfer id
+fn start_transfer(address: &str, size: u64) -> (TcpStream, [u8; 8]) {
+    let mut connection = TcpStream::connect(address).unwrap();
+    let mut reply = [0; 1];
+    // ParallelTransferRequest
+    connection.write_all(&[34]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    // FileInfo without metadata
+    connection.write_all(&8u16.to_be_bytes()).unwrap();
+    connection.write_all(b"disk.img").unwrap();
+    connection.write_all(&size.to_be_bytes()).unwrap();
+    connection.write_all(&[0; 17]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [200]);
+    let mut id = [0; 8];
+    connection.read_exact(&mut id).unwrap();
+    (connection, id)
+}
+
+/// Ask to send a range of a file, returning the connection and the server's answer
+fn request_range(address: &str, id: [u8; 8], offset: u64, length: u64) -> (TcpStream, u8) {
+    let mut connection = TcpStream::connect(address).unwrap();
+    let mut reply = [0; 1];
+    // RangeTransferRequest
+    connection.write_all(&[35]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    connection.write_all(&id).unwrap();
+    connection.write_all(&offset.to_be_bytes()).unwrap();
+    connection.write_all(&length.to_be_bytes()).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    (connection, reply[0])
+}
+
+#[test]
+fn ranges_must_belong_to_a_transfer_and_not_overlap() {
+    let dir = test_dir("parallel-ranges");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |_| {});
+    let (control, id) = start_transfer(&address, 2 * 1024 * 1024);
+
+    let (_first, reply) = request_range(&address, id, 0, 1024 * 1024);
+    assert_eq!(reply, 200);
+    let (_, reply) = request_range(&address, id, 512 * 1024, 1024 * 1024);
+    assert_eq!(reply, 43, "a range overlapping another should be refused");
+    let (_, reply) = request_range(&address, id, 1024 * 1024, 2 * 1024 * 1024);
+    assert_eq!(
+        reply, 43,
+        "a range past the end of the file should be refused"
+    );
+    let unknown = (u64::from_be_bytes(id) + 1).to_be_bytes();
+    let (_, reply) = request_range(&address, unknown, 0, 1024);
+    assert_eq!(reply, 43, "a range of another transfer should be refused");
+
+    // giving up on the transfer leaves nothing behind
+    drop(control);
+    for _ in 0..50 {
+        if fs::read_dir(&receive).unwrap().next().is_none() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(fs::read_dir(&receive).unwrap().count(), 0);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn partial_files_are_sparse_without_limits_on_the_disk() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = test_dir("parallel-sparse");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |_| {});
+    // 4 TiB, more than the disk has free, but within what most filesystems allow in one file
+    let size = 4 << 40;
+    let (control, _) = start_transfer(&address, size);
+
+    let partial = fs::read_dir(&receive).unwrap().next().unwrap().unwrap();
+    let metadata = partial.metadata().unwrap();
+    assert_eq!(metadata.len(), size);
+    assert!(metadata.blocks() * 512 < size);
+    drop(control);
+    assert!(eventually(|| fs::read_dir(&receive).unwrap().count() == 0));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn connections_over_the_limit_are_refused() {
+    let dir = test_dir("parallel-max-connections");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.max_connections(1);
+    });
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    let connected = Client::<Disconnected>::new().connect(&address).unwrap();
+    let error = send(&file, &address).unwrap_err();
+    let reason = &error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Denied>())
+        .unwrap()
+        .reason;
+    assert!(reason.contains("most connections"), "reason: {}", reason);
+
+    // the connection is given up once the server has said Goodbye
+    connected.goodbye();
+    assert!(eventually(|| send(&file, &address).is_ok()));
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("file.txt")).unwrap(),
+        "hello"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn refusing_idle_connections_does_not_hold_up_the_next() {
+    let dir = test_dir("parallel-refusals");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.max_connections(1);
+    });
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    let connected = Client::<Disconnected>::new().connect(&address).unwrap();
+    // connections over the limit that never send anything or hang up, each waited on for a while as it is refused
+    let idle: Vec<_> = (0..3)
+        .map(|_| TcpStream::connect(&address).unwrap())
+        .collect();
+    let start = Instant::now();
+    send(&file, &address).unwrap_err();
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "refused after {:?}",
+        start.elapsed()
+    );
+    drop(idle);
+    connected.goodbye();
+    fs::remove_dir_all(dir).unwrap();
+}
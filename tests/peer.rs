@@ -0,0 +1,113 @@
+mod common;
+
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use fshare::{Client, Config, Disconnected, PeerCommand, PeerShell, ServerBuilder};
+
+use common::{content, eventually, start_server, test_dir};
+
+fn send(file: &str, to: Option<&str>) -> PeerCommand {
+    PeerCommand::Send {
+        file: file.to_string(),
+        to: to.map(str::to_string),
+    }
+}
+
+#[test]
+fn commands_are_read_from_a_line() {
+    let cases = [
+        ("", PeerCommand::Nothing),
+        ("   \n", PeerCommand::Nothing),
+        ("# send nothing.txt", PeerCommand::Nothing),
+        ("send notes.txt\n", send("notes.txt", None)),
+        ("  send   notes.txt   nas  ", send("notes.txt", Some("nas"))),
+        (
+            "send notes.txt 10.0.3.17:8080",
+            send("notes.txt", Some("10.0.3.17:8080")),
+        ),
+        ("peers", PeerCommand::Peers),
+        ("help", PeerCommand::Help),
+        ("quit", PeerCommand::Quit),
+        ("exit\n", PeerCommand::Quit),
+    ];
+    for (line, command) in cases {
+        assert_eq!(line.parse::<PeerCommand>().unwrap(), command, "{:?}", line);
+    }
+}
+
+#[test]
+fn quotes_keep_words_with_spaces_together() {
+    assert_eq!(
+        r#"send "my notes.txt" "the nas""#.parse::<PeerCommand>().unwrap(),
+        send("my notes.txt", Some("the nas"))
+    );
+    // even an empty one
+    assert_eq!(r#"send """#.parse::<PeerCommand>().unwrap(), send("", None));
+}
+
+#[test]
+fn unknown_commands_are_refused() {
+    for line in ["sned notes.txt", "send", "send a b c", "peers now", "Help"] {
+        let error = line.parse::<PeerCommand>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Unknown command `{}`, try `help`", line)
+        );
+    }
+}
+
+#[test]
+fn files_are_sent_while_another_is_arriving() {
+    let dir = test_dir("peer");
+    let elsewhere = dir.join("elsewhere");
+    fs::create_dir_all(&elsewhere).unwrap();
+    let other = start_server(&elsewhere, |_| {});
+    let mut server = ServerBuilder::new();
+    server.directory(dir.join("receive")).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (commands, mut typed) = io::pipe().unwrap();
+    let peer = thread::spawn(move || {
+        PeerShell::new(Config::default()).run(
+            server.build().unwrap(),
+            listener,
+            BufReader::new(commands),
+            io::sink(),
+        )
+    });
+
+    // a file starts arriving at the peer
+    let arriving = content(64 * 1024, 1);
+    let arriving_client = Client::<Disconnected>::new()
+        .connect(&address)
+        .unwrap()
+        .bytes("arriving.bin", arriving.clone())
+        .negotiate()
+        .map_err(|client| client.error.unwrap())
+        .unwrap();
+
+    // and the peer sends one before it has finished arriving
+    let sent = dir.join("send").join("sent.bin");
+    let sending = content(64 * 1024, 2);
+    fs::write(&sent, &sending).unwrap();
+    writeln!(typed, "send {} {}", sent.display(), other).unwrap();
+    let copy = elsewhere.join("sent.bin");
+    assert!(eventually(|| fs::read(&copy).ok() == Some(sending.clone())));
+
+    arriving_client
+        .send()
+        .map_err(|client| client.error.unwrap())
+        .unwrap()
+        .goodbye();
+    assert_eq!(
+        fs::read(dir.join("receive").join("arriving.bin")).unwrap(),
+        arriving
+    );
+
+    writeln!(typed, "quit").unwrap();
+    peer.join().unwrap().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+}
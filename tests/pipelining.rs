@@ -0,0 +1,87 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use common::{start_server, test_dir};
+
+/// The CRC-32 of `CONTENT`
+const CHECKSUM: u32 = 0xCBF4_3926;
+const CONTENT: &[u8] = b"123456789";
+
+/// FileInfo for `CONTENT` without metadata
+fn file_info(message: &mut Vec<u8>, name: &str) {
+    message.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    message.extend_from_slice(name.as_bytes());
+    message.extend_from_slice(&(CONTENT.len() as u64).to_be_bytes());
+    message.extend_from_slice(&[0; 17]);
+}
+
+/// `CONTENT` in a single chunk, followed by the end of the content
+fn chunks(message: &mut Vec<u8>) {
+    message.extend_from_slice(&(CONTENT.len() as u32).to_be_bytes());
+    message.extend_from_slice(&CHECKSUM.to_be_bytes());
+    message.extend_from_slice(CONTENT);
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&CHECKSUM.to_be_bytes());
+}
+
+#[test]
+fn messages_sent_straight_after_the_content_are_not_lost() {
+    let dir = test_dir("pipelining");
+    let address = start_server(&dir.join("receive"), |_| {});
+
+    // two files and a Goodbye, all without waiting for a reply
+    let mut pipelined = Vec::new();
+    for name in ["first.txt", "second.txt"] {
+        pipelined.push(30);
+        file_info(&mut pipelined, name);
+        chunks(&mut pipelined);
+    }
+    pipelined.push(255);
+    let mut connection = TcpStream::connect(address).unwrap();
+    connection.write_all(&pipelined).unwrap();
+
+    let mut replies = [0; 7];
+    connection.read_exact(&mut replies).unwrap();
+    assert_eq!(replies, [200, 200, 200, 200, 200, 200, 255]);
+    for name in ["first.txt", "second.txt"] {
+        assert_eq!(fs::read(dir.join("receive").join(name)).unwrap(), CONTENT);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn messages_sent_straight_after_a_delta_are_not_lost() {
+    let dir = test_dir("pipelining-delta");
+    fs::write(dir.join("receive").join("notes.txt"), "old notes").unwrap();
+    let address = start_server(&dir.join("receive"), |_| {});
+
+    // a delta of nothing but literal content, then Goodbye, without waiting for the server's signatures
+    let mut pipelined = vec![33];
+    file_info(&mut pipelined, "notes.txt");
+    pipelined.push(1);
+    pipelined.extend_from_slice(&(CONTENT.len() as u32).to_be_bytes());
+    pipelined.extend_from_slice(&CHECKSUM.to_be_bytes());
+    pipelined.extend_from_slice(CONTENT);
+    pipelined.push(0);
+    pipelined.extend_from_slice(&CHECKSUM.to_be_bytes());
+    pipelined.push(255);
+    let mut connection = TcpStream::connect(address).unwrap();
+    connection.write_all(&pipelined).unwrap();
+
+    let mut replies = [0; 2];
+    connection.read_exact(&mut replies).unwrap();
+    assert_eq!(replies, [200, 200]);
+    // signatures of the 9 byte file, which fits in a single block
+    let mut signatures = [0; 8 + 4 + 8];
+    connection.read_exact(&mut signatures).unwrap();
+    connection.read_exact(&mut replies).unwrap();
+    assert_eq!(replies, [200, 255]);
+    assert_eq!(
+        fs::read(dir.join("receive").join("notes.txt")).unwrap(),
+        CONTENT
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
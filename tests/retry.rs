@@ -0,0 +1,84 @@
+mod common;
+
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use fshare::{Client, Denied, Disconnected, Reconnect, Retry, RetryPolicy};
+
+use common::{content, counting_proxy, flaky_proxy, start_server, test_dir};
+
+fn policy(reconnect: Reconnect) -> RetryPolicy {
+    RetryPolicy {
+        retry: Retry {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+        },
+        reconnect,
+    }
+}
+
+#[test]
+fn dropped_connections_are_retried() {
+    let dir = test_dir("retry-dropped");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |_| {});
+    let data = content(1024 * 1024, 1);
+    let path = dir.join("send").join("file.bin");
+    fs::write(&path, &data).unwrap();
+
+    // before anything is sent, and part way through the content
+    for (cut_after, reconnect) in [(0, Reconnect::BeforeSending), (100_000, Reconnect::Always)] {
+        let (proxy, connections) = flaky_proxy(address.clone(), cut_after);
+        Client::<Disconnected>::new()
+            .file(path.to_str().unwrap())
+            .unwrap()
+            .send_with_retry(&proxy, policy(reconnect))
+            .unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        assert_eq!(fs::read(receive.join("file.bin")).unwrap(), data);
+        fs::remove_file(receive.join("file.bin")).unwrap();
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_that_were_partly_sent_are_only_retried_when_allowed() {
+    let dir = test_dir("retry-partly-sent");
+    let address = start_server(&dir.join("receive"), |_| {});
+    let (proxy, connections) = flaky_proxy(address, 100_000);
+    let path = dir.join("send").join("file.bin");
+    fs::write(&path, content(1024 * 1024, 2)).unwrap();
+
+    let failed = Client::<Disconnected>::new()
+        .file(path.to_str().unwrap())
+        .unwrap()
+        .send_with_retry(&proxy, policy(Reconnect::BeforeSending))
+        .unwrap_err();
+    assert!(failed.error.is_some());
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    assert!(!dir.join("receive").join("file.bin").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn denied_files_are_not_retried() {
+    let dir = test_dir("retry-denied");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.max_file_size(10);
+    });
+    let proxy = counting_proxy(address);
+    let path = dir.join("send").join("large.bin");
+    fs::write(&path, vec![1; 100]).unwrap();
+
+    let failed = Client::<Disconnected>::new()
+        .file(path.to_str().unwrap())
+        .unwrap()
+        .send_with_retry(&proxy.address, policy(Reconnect::Always))
+        .unwrap_err();
+    let error = failed.error.unwrap();
+    assert!(error.is::<Denied>(), "{:#}", error);
+    assert_eq!(proxy.connections.load(Ordering::SeqCst), 1);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,188 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use fshare::{Client, Denied, Disconnected, FileInfo, Incoming, ServerBuilder, Sink};
+
+use common::{content, send_half_a_file, start_server, test_dir};
+
+/// Keeps every file it receives in memory, by name
+#[derive(Debug, Default, Clone)]
+struct Memory {
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+#[derive(Debug)]
+struct Arriving {
+    name: String,
+    content: Vec<u8>,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl Sink for Memory {
+    fn create(&self, info: &FileInfo) -> anyhow::Result<Box<dyn Incoming>> {
+        Ok(Box::new(Arriving {
+            name: info.name.clone(),
+            content: Vec::new(),
+            files: self.files.clone(),
+        }))
+    }
+}
+
+impl Write for Arriving {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.content.write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Incoming for Arriving {
+    fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        self.files.lock().unwrap().insert(self.name, self.content);
+        Ok(())
+    }
+}
+
+/// Start a server that only keeps files in `sink`, returning its address
+fn start_memory_server(sink: Memory, configure: impl FnOnce(&mut ServerBuilder)) -> String {
+    let mut server = ServerBuilder::new();
+    server.sink(sink);
+    configure(&mut server);
+    let mut server = server.build().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || server.serve(listener));
+    address
+}
+
+#[test]
+fn bytes_and_readers_arrive_in_a_sink_of_our_own() {
+    let sink = Memory::default();
+    let address = start_memory_server(sink.clone(), |_| {});
+    let bytes = content(3 * 64 * 1024 + 17, 1);
+    let read = content(2 * 64 * 1024 + 5, 2);
+
+    Client::<Disconnected>::new()
+        .connect(&address)
+        .unwrap()
+        .bytes("bytes.bin", bytes.clone())
+        .negotiate()
+        .unwrap()
+        .send()
+        .unwrap()
+        .reader(
+            "nested/read.bin",
+            io::Cursor::new(read.clone()),
+            read.len() as u64,
+        )
+        .negotiate()
+        .unwrap()
+        .send()
+        .unwrap()
+        .goodbye();
+
+    let files = sink.files.lock().unwrap();
+    assert_eq!(files["bytes.bin"], bytes);
+    assert_eq!(files["nested/read.bin"], read);
+}
+
+#[test]
+fn files_that_fail_part_way_are_never_committed() {
+    let sink = Memory::default();
+    let address = start_memory_server(sink.clone(), |_| {});
+    send_half_a_file(&address, "half.bin");
+    thread::sleep(Duration::from_millis(200));
+    assert!(sink.files.lock().unwrap().is_empty());
+}
+
+#[test]
+fn a_sink_without_files_refuses_ranges_and_deltas_fall_back_to_whole_files() {
+    let sink = Memory::default();
+    let address = start_memory_server(sink.clone(), |_| {});
+    let data = content(16 * 1024 * 1024, 3);
+
+    let client = Client::<Disconnected>::new()
+        .streams(4)
+        .connect(&address)
+        .unwrap()
+        .bytes("large.bin", data.clone());
+    let error = client.negotiate().unwrap_err().error.unwrap();
+    let denied = error.downcast_ref::<Denied>().unwrap();
+    assert!(denied.reason.contains("ranges"), "{}", denied.reason);
+
+    // the sink has no copies to build on, so the file is sent in full
+    Client::<Disconnected>::new()
+        .delta(true)
+        .connect(&address)
+        .unwrap()
+        .bytes("large.bin", data.clone())
+        .negotiate()
+        .unwrap()
+        .send()
+        .unwrap()
+        .goodbye();
+    assert_eq!(sink.files.lock().unwrap()["large.bin"], data);
+}
+
+#[test]
+fn a_server_without_a_directory_has_no_manifest() {
+    let address = start_memory_server(Memory::default(), |_| {});
+    let mut client = Client::<Disconnected>::new().connect(&address).unwrap();
+    let error = client.manifest().unwrap_err();
+    assert!(error.downcast_ref::<Denied>().is_some(), "{:#}", error);
+    client.goodbye();
+}
+
+#[test]
+fn bytes_and_readers_are_stored_in_the_directory_by_default() {
+    let dir = test_dir("sink-directory");
+    let receive = dir.join("receive");
+    let address = start_server(&receive, |_| {});
+    let bytes = content(16 * 1024 * 1024, 4);
+    let read = content(16 * 1024 * 1024, 5);
+    fs::write(receive.join("read.bin"), content(1024, 6)).unwrap();
+
+    // bytes can be sent in ranges, but a reader is always sent whole, even when a delta or ranges are asked for
+    Client::<Disconnected>::new()
+        .streams(4)
+        .delta(false)
+        .bytes("bytes.bin", bytes.clone())
+        .send_with_retry(&address, Default::default())
+        .unwrap();
+    Client::<Disconnected>::new()
+        .streams(4)
+        .delta(true)
+        .reader("read.bin", io::Cursor::new(read.clone()), read.len() as u64)
+        .send_with_retry(&address, Default::default())
+        .unwrap();
+
+    assert_eq!(fs::read(receive.join("bytes.bin")).unwrap(), bytes);
+    assert_eq!(fs::read(receive.join("read.bin")).unwrap(), read);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn readers_that_end_early_are_refused() {
+    let dir = test_dir("sink-short-reader");
+    let address = start_server(&dir.join("receive"), |_| {});
+
+    let result = Client::<Disconnected>::new()
+        .connect(&address)
+        .unwrap()
+        .reader("short.bin", io::Cursor::new(vec![1; 100]), 1000)
+        .negotiate()
+        .unwrap()
+        .send();
+    assert!(result.is_err());
+    assert!(!dir.join("receive").join("short.bin").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use fshare::{Client, Denied, Disconnected, SyncSummary, Timeouts};
+
+use common::{content, start_server, test_dir};
+
+/// Large enough that reading it to checksum it takes longer than the short timeouts below
+const SLOW_TO_CHECKSUM: usize = 64 * 1024 * 1024;
+
+#[test]
+fn only_new_and_changed_files_are_sent() {
+    let dir = test_dir("sync");
+    let local = dir.join("send");
+    let remote = dir.join("receive");
+    fs::create_dir_all(local.join("sub")).unwrap();
+    fs::write(local.join("same.txt"), "same").unwrap();
+    fs::write(local.join("sub").join("new.txt"), "new").unwrap();
+    fs::write(local.join(".hidden"), "secret").unwrap();
+    fs::write(remote.join("same.txt"), "same").unwrap();
+    fs::create_dir_all(remote.join("gone")).unwrap();
+    fs::write(remote.join("gone").join("old.txt"), "old").unwrap();
+    let address = start_server(&remote, |server| {
+        server.allow_delete(true);
+    });
+
+    let summary = Client::<Disconnected>::new()
+        .sync(&local, &address, false)
+        .unwrap();
+    assert_eq!(
+        summary,
+        SyncSummary {
+            sent: vec!["sub/new.txt".to_string()],
+            unchanged: 1,
+            ..SyncSummary::default()
+        }
+    );
+    assert_eq!(
+        fs::read_to_string(remote.join("sub").join("new.txt")).unwrap(),
+        "new"
+    );
+    assert!(!remote.join(".hidden").exists());
+    assert!(remote.join("gone").join("old.txt").exists());
+
+    // the same size but different content is still a change
+    fs::write(local.join("sub").join("new.txt"), "NEW").unwrap();
+    let summary = Client::<Disconnected>::new()
+        .sync(&local, &address, true)
+        .unwrap();
+    assert_eq!(
+        summary,
+        SyncSummary {
+            sent: vec!["sub/new.txt".to_string()],
+            unchanged: 1,
+            deleted: vec!["gone/old.txt".to_string()],
+            ..SyncSummary::default()
+        }
+    );
+    assert_eq!(
+        fs::read_to_string(remote.join("sub").join("new.txt")).unwrap(),
+        "NEW"
+    );
+    assert!(!remote.join("gone").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn servers_decide_what_may_be_stored_and_deleted() {
+    let dir = test_dir("sync-refused");
+    let local = dir.join("send");
+    let remote = dir.join("receive");
+    fs::write(local.join("small.txt"), "small").unwrap();
+    fs::write(local.join("large.txt"), "far too large").unwrap();
+    fs::write(remote.join("kept.txt"), "kept").unwrap();
+    let address = start_server(&remote, |server| {
+        server.max_file_size(10);
+    });
+
+    let summary = Client::<Disconnected>::new()
+        .sync(&local, &address, false)
+        .unwrap();
+    assert_eq!(summary.sent, vec!["small.txt"]);
+    assert_eq!(summary.refused, vec!["large.txt"]);
+
+    let error = Client::<Disconnected>::new()
+        .sync(&local, &address, true)
+        .unwrap_err();
+    assert!(error.is::<Denied>(), "{:#}", error);
+    assert!(remote.join("kept.txt").exists());
+
+    // names from a client can't reach outside the server's directory
+    let error = Client::<Disconnected>::new()
+        .connect(address.as_str())
+        .unwrap()
+        .file_named(local.join("small.txt").to_str().unwrap(), "../escaped.txt")
+        .unwrap()
+        .negotiate()
+        .unwrap_err();
+    assert!(error.error.as_ref().unwrap().is::<Denied>());
+    assert!(!dir.join("escaped.txt").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn many_small_files_are_sent_over_one_connection() {
+    let dir = test_dir("sync-many");
+    let local = dir.join("send");
+    let remote = dir.join("receive");
+    for i in 0..1000 {
+        fs::write(local.join(format!("{:04}.txt", i)), i.to_string()).unwrap();
+    }
+    let address = start_server(&remote, |_| {});
+
+    let summary = Client::<Disconnected>::new()
+        .sync(&local, &address, false)
+        .unwrap();
+    assert_eq!(summary.sent.len(), 1000);
+    assert_eq!(fs::read_to_string(remote.join("0999.txt")).unwrap(), "999");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn large_files_take_as_long_as_they_need_to_checksum() {
+    let dir = test_dir("sync-slow-checksum");
+    let local = dir.join("send");
+    let remote = dir.join("receive");
+    // the same on both ends, so both read all of it to find that out, before sending what is new
+    let large = content(SLOW_TO_CHECKSUM, 1);
+    fs::write(local.join("large.bin"), &large).unwrap();
+    fs::write(remote.join("large.bin"), &large).unwrap();
+    fs::write(local.join("new.txt"), "new").unwrap();
+    let timeouts = Timeouts {
+        read: Duration::from_millis(300),
+        idle: Duration::from_millis(300),
+        ..Timeouts::default()
+    };
+    let address = start_server(&remote, |server| {
+        server.timeouts(timeouts);
+    });
+
+    let start = Instant::now();
+    let summary = Client::<Disconnected>::new()
+        .timeouts(timeouts)
+        .sync(&local, &address, false)
+        .unwrap();
+    assert!(start.elapsed() > timeouts.read, "{:?}", start.elapsed());
+    assert_eq!(summary.sent, vec!["new.txt"]);
+    assert_eq!(summary.unchanged, 1);
+    fs::remove_dir_all(dir).unwrap();
+}
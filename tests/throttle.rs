@@ -0,0 +1,77 @@
+mod common;
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use fshare::{Client, Disconnected};
+
+use common::{send, start_server, test_dir};
+
+const SIZE: usize = 256 * 1024;
+const RATE: u64 = 512 * 1024;
+
+/// At RATE, SIZE takes half a second, less the tenth of a second burst the limiter allows up front
+fn assert_throttled(elapsed: Duration) {
+    assert!(
+        elapsed >= Duration::from_millis(350),
+        "transfer was too fast: {:?}",
+        elapsed
+    );
+    assert!(
+        elapsed < Duration::from_secs(3),
+        "transfer was too slow: {:?}",
+        elapsed
+    );
+}
+
+#[test]
+fn client_limit() {
+    let dir = test_dir("throttle-client");
+    let address = start_server(&dir.join("receive"), |_| {});
+    let file = dir.join("send").join("file.bin");
+    fs::write(&file, vec![0; SIZE]).unwrap();
+
+    let start = Instant::now();
+    Client::<Disconnected>::new()
+        .limit(RATE)
+        .file(file.to_str().unwrap())
+        .unwrap()
+        .connect(&address)
+        .unwrap()
+        .negotiate()
+        .unwrap()
+        .send()
+        .unwrap();
+    assert_throttled(start.elapsed());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn server_limit() {
+    let dir = test_dir("throttle-server");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.rate_limit(RATE);
+    });
+    let file = dir.join("send").join("file.bin");
+    fs::write(&file, vec![0; SIZE]).unwrap();
+
+    let start = Instant::now();
+    send(&file, &address).unwrap();
+    assert_throttled(start.elapsed());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn server_connection_limit() {
+    let dir = test_dir("throttle-connection");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.connection_rate_limit(RATE);
+    });
+    let file = dir.join("send").join("file.bin");
+    fs::write(&file, vec![0; SIZE]).unwrap();
+
+    let start = Instant::now();
+    send(&file, &address).unwrap();
+    assert_throttled(start.elapsed());
+    fs::remove_dir_all(dir).unwrap();
+}
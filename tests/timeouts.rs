@@ -0,0 +1,125 @@
+mod common;
+
+use std::fs;
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use fshare::{parse_duration, Client, Disconnected, Retry, Timeouts};
+
+use common::{start_server, test_dir};
+
+/// How much longer than its timeout anything may take to give up, on a busy machine
+const SLACK: Duration = Duration::from_secs(1);
+
+fn short_timeouts() -> Timeouts {
+    Timeouts {
+        connect: Duration::from_millis(300),
+        read: Duration::from_millis(300),
+        write: Duration::from_millis(300),
+        idle: Duration::from_millis(300),
+        goodbye: Retry {
+            attempts: 3,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(50),
+        },
+    }
+}
+
+/// Check `elapsed` is at least `timeout` but not much more
+fn assert_gave_up_after(elapsed: Duration, timeout: Duration) {
+    assert!(
+        elapsed >= timeout && elapsed < timeout + SLACK,
+        "gave up after {:?}, expected {:?}",
+        elapsed,
+        timeout
+    );
+}
+
+#[test]
+fn a_server_that_never_answers_is_given_up_on() {
+    // the OS accepts connections for the listener, but nobody ever reads or writes
+    let dir = test_dir("timeouts-never-answers");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let timeouts = short_timeouts();
+    let file = dir.join("send").join("file.txt");
+    fs::write(&file, "hello").unwrap();
+
+    let start = Instant::now();
+    let result = Client::<Disconnected>::new()
+        .timeouts(timeouts)
+        .send(address.clone(), file.to_str().unwrap().to_string());
+    assert_gave_up_after(start.elapsed(), timeouts.read);
+    assert!(result.is_err());
+
+    // each attempt to say Goodbye waits for a reply, with a pause before each retry
+    let connected = Client::<Disconnected>::new()
+        .timeouts(timeouts)
+        .connect(address)
+        .unwrap();
+    let start = Instant::now();
+    let disconnected = connected.goodbye();
+    assert_gave_up_after(
+        start.elapsed(),
+        timeouts.read * 3 + timeouts.goodbye.delay(1) + timeouts.goodbye.delay(2),
+    );
+    assert!(disconnected.error.is_some());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn a_server_that_never_accepts_is_given_up_on() {
+    use std::os::unix::io::AsRawFd;
+
+    // with no room for connections waiting to be accepted, the OS ignores any more until one is
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // SAFETY: listen only changes the backlog of a socket we own, which is already listening
+    assert_eq!(unsafe { libc::listen(listener.as_raw_fd(), 0) }, 0);
+    let address = listener.local_addr().unwrap().to_string();
+    let _waiting = TcpStream::connect(&address).unwrap();
+    let timeouts = short_timeouts();
+
+    let start = Instant::now();
+    let result = Client::<Disconnected>::new()
+        .timeouts(timeouts)
+        .connect(address);
+    assert_gave_up_after(start.elapsed(), timeouts.connect);
+    assert!(result.is_err());
+}
+
+#[test]
+fn clients_that_say_nothing_are_disconnected() {
+    let dir = test_dir("timeouts-idle");
+    let timeouts = short_timeouts();
+    let address = start_server(&dir.join("receive"), |server| {
+        server.timeouts(timeouts);
+    });
+
+    let mut connection = TcpStream::connect(&address).unwrap();
+    connection
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let start = Instant::now();
+    // the server hangs up without a word
+    let read = connection.read(&mut [0; 1]).unwrap_or(0);
+    assert_eq!(read, 0);
+    assert_gave_up_after(start.elapsed(), timeouts.idle);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn durations_are_parsed_with_their_units() {
+    assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(parse_duration("5s").unwrap(), Duration::from_secs(5));
+    assert_eq!(parse_duration("7").unwrap(), Duration::from_secs(7));
+    assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+    assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+    assert!(parse_duration("0").is_err());
+    assert!(parse_duration("5d").is_err());
+
+    let huge = format!("{}h", u64::MAX / 60);
+    let error = parse_duration(&huge).unwrap_err();
+    assert!(error.to_string().contains("too large"), "{}", error);
+}
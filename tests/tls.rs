@@ -0,0 +1,122 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use fshare::{Client, ClientTls, Denied, Disconnected, ServerTls};
+
+use common::{content, start_server, test_dir};
+
+/// A certificate for `localhost` and `127.0.0.1`, and the authority that signed it
+fn certificates() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("data")
+        .join("tls")
+}
+
+fn server_tls() -> ServerTls {
+    let certificates = certificates();
+    ServerTls::load(
+        certificates.join("server.pem"),
+        certificates.join("server.key"),
+    )
+    .unwrap()
+}
+
+fn client_tls() -> ClientTls {
+    ClientTls::trust(certificates().join("ca.pem")).unwrap()
+}
+
+#[test]
+fn files_are_sent_over_tls() {
+    let dir = test_dir("tls");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.tls(server_tls());
+    });
+    let path = dir.join("send").join("file.txt");
+    fs::write(&path, "hello").unwrap();
+
+    Client::<Disconnected>::new()
+        .tls(client_tls())
+        .send(address, path.to_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("receive").join("file.txt")).unwrap(),
+        "hello"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn servers_not_vouched_for_are_not_sent_to() {
+    let dir = test_dir("tls-untrusted");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.tls(server_tls());
+    });
+    let path = dir.join("send").join("file.txt");
+    fs::write(&path, "hello").unwrap();
+
+    // the server's own certificate, rather than the authority that signed it
+    let error = Client::<Disconnected>::new()
+        .tls(ClientTls::trust(certificates().join("server.pem")).unwrap())
+        .send(address, path.to_str().unwrap().to_string())
+        .unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("TLS handshake"),
+        "error: {:#}",
+        error
+    );
+    assert!(!dir.join("receive").join("file.txt").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn ranges_are_sent_over_tls_with_the_secret() {
+    let dir = test_dir("tls-parallel");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.tls(server_tls()).secret("hunter2");
+    });
+    let data = content(5 * 1024 * 1024 + 123, 1);
+    let path = dir.join("send").join("disk.img");
+    fs::write(&path, &data).unwrap();
+
+    Client::<Disconnected>::new()
+        .tls(client_tls())
+        .secret("hunter2")
+        .streams(4)
+        .send(address, path.to_str().unwrap().to_string())
+        .unwrap();
+    assert_eq!(
+        fs::read(dir.join("receive").join("disk.img")).unwrap(),
+        data
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn a_wrong_secret_is_refused_over_tls() {
+    let dir = test_dir("tls-secret");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.tls(server_tls()).secret("hunter2");
+    });
+    let path = dir.join("send").join("file.txt");
+    fs::write(&path, "hello").unwrap();
+
+    let error = Client::<Disconnected>::new()
+        .tls(client_tls())
+        .secret("hunter3")
+        .send(address, path.to_str().unwrap().to_string())
+        .unwrap_err();
+    let denied = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Denied>())
+        .expect("the server should deny the request");
+    assert!(
+        denied.reason.contains("secret"),
+        "reason: {}",
+        denied.reason
+    );
+    assert!(!dir.join("receive").join("file.txt").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use fshare::{Client, Disconnected};
+
+use common::{content, start_server, test_dir};
+
+#[test]
+fn files_arrive_intact_with_and_without_zero_copy() {
+    let dir = test_dir("zero-copy");
+    // several chunks, the last of them short
+    let data = content(5 * 64 * 1024 + 123, 1);
+    let path = dir.join("send").join("data.bin");
+    fs::write(&path, &data).unwrap();
+
+    for (sender, receiver) in [(true, true), (true, false), (false, true), (false, false)] {
+        let receive = dir.join(format!("receive-{}-{}", sender, receiver));
+        fs::create_dir_all(&receive).unwrap();
+        let address = start_server(&receive, |server| {
+            server.zero_copy(receiver);
+        });
+        Client::<Disconnected>::new()
+            .zero_copy(sender)
+            .send(address, path.to_str().unwrap().to_string())
+            .unwrap();
+        assert_eq!(
+            fs::read(receive.join("data.bin")).unwrap(),
+            data,
+            "sending with zero copy {}, receiving with zero copy {}",
+            sender,
+            receiver
+        );
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupt_chunks_are_caught_when_spliced_into_the_file() {
+    let dir = test_dir("zero-copy-corrupt");
+    let address = start_server(&dir.join("receive"), |server| {
+        server.zero_copy(true);
+    });
+
+    let mut connection = TcpStream::connect(address).unwrap();
+    let mut reply = [0; 1];
+    // FileTransferRequest
+    connection.write_all(&[30]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    // FileInfo: a 1000 byte file without metadata
+    connection.write_all(&8u16.to_be_bytes()).unwrap();
+    connection.write_all(b"data.bin").unwrap();
+    connection.write_all(&1000u64.to_be_bytes()).unwrap();
+    connection.write_all(&[0; 17]).unwrap();
+    connection.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [200]);
+    // all of the content in one chunk, with the wrong checksum
+    connection.write_all(&1000u32.to_be_bytes()).unwrap();
+    connection.write_all(&0xDEAD_BEEFu32.to_be_bytes()).unwrap();
+    connection.write_all(&content(1000, 2)).unwrap();
+
+    // the server gives up on the file and hangs up
+    let mut rest = Vec::new();
+    let _ = connection.read_to_end(&mut rest);
+    assert!(
+        !rest.contains(&200),
+        "the server should not accept the file"
+    );
+    assert_eq!(fs::read_dir(dir.join("receive")).unwrap().count(), 0);
+    fs::remove_dir_all(dir).unwrap();
+}